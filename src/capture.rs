@@ -0,0 +1,243 @@
+// Copyright 2017 Thorben Kroeger.
+// Dual-licensed MIT and Apache 2.0 (see LICENSE files for details).
+
+// Capture times of photos and videos.
+//
+// Cameras record the wall-clock time at the place of capture (EXIF
+// `DateTimeOriginal`), newer ones together with its offset from UTC
+// (`OffsetTimeOriginal`). Videos often only record an instant in UTC.
+// To sort both into the right day, capture times are kept as local time
+// plus offset, and a default zone fills in the offset where it is unknown.
+
+use std::cmp::Ordering;
+use std::fmt;
+use std::str::FromStr;
+
+use chrono::offset::TimeZone;
+use chrono::{DateTime, FixedOffset, Local, NaiveDate, NaiveDateTime, NaiveTime, UTC};
+
+use errors::*;
+
+/// The zone assumed for capture times which do not record their offset.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum DefaultZone {
+    // the zone of this computer (including daylight saving time)
+    #[default]
+    Local,
+    Fixed(FixedOffset),
+}
+
+impl FromStr for DefaultZone {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<DefaultZone> {
+        match s {
+            "local" => Ok(DefaultZone::Local),
+            _ => parse_offset(s).map(DefaultZone::Fixed).ok_or_else(|| {
+                format!("invalid time zone '{}' (expected `local`, `UTC` or an offset like `+02:00`)", s)
+                    .into()
+            }),
+        }
+    }
+}
+
+impl DefaultZone {
+    fn offset_at_local(&self, local: &NaiveDateTime) -> FixedOffset {
+        match *self {
+            DefaultZone::Local => {
+                // a local time skipped by a switch to daylight saving time
+                // is taken to be UTC, to pick one of the nearby offsets
+                Local.offset_from_local_datetime(local)
+                    .earliest()
+                    .unwrap_or_else(|| Local.offset_from_utc_datetime(local))
+            }
+            DefaultZone::Fixed(offset) => offset,
+        }
+    }
+
+    fn offset_at_utc(&self, utc: &NaiveDateTime) -> FixedOffset {
+        match *self {
+            DefaultZone::Local => Local.offset_from_utc_datetime(utc),
+            DefaultZone::Fixed(offset) => offset,
+        }
+    }
+}
+
+/// Parses UTC offsets as used by EXIF (`+02:00`) and ISO 8601
+/// (`+0200`, `+02`, `Z`).
+pub fn parse_offset(s: &str) -> Option<FixedOffset> {
+    let s = s.trim();
+    if s == "Z" || s.eq_ignore_ascii_case("utc") {
+        return Some(FixedOffset::east(0));
+    }
+    let sign = match s.chars().next()? {
+        '+' => 1,
+        '-' => -1,
+        _ => return None,
+    };
+    let digits = s[1..].replace(':', "");
+    if !(digits.len() == 2 || digits.len() == 4) || !digits.chars().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    let hours: i32 = digits[..2].parse().ok()?;
+    let minutes: i32 = if digits.len() == 4 { digits[2..].parse().ok()? } else { 0 };
+    if hours > 23 || minutes > 59 {
+        return None;
+    }
+    FixedOffset::east_opt(sign * (hours * 3600 + minutes * 60))
+}
+
+/// Parses ISO 8601 dates as used by XMP and QuickTime, e.g.
+/// `2017-05-12T18:30:00+02:00`, `2017-05-12T18:30` or `2017-05-12`.
+/// Fractions of seconds are ignored.
+pub fn parse_iso_datetime(s: &str) -> Option<CaptureTime> {
+    let s = s.trim();
+    let date = NaiveDate::parse_from_str(s.get(..10)?, "%Y-%m-%d").ok()?;
+    let rest = &s[10..];
+    if rest.is_empty() {
+        return Some(CaptureTime::new(date.and_hms(0, 0, 0), None));
+    }
+    if !rest.starts_with('T') && !rest.starts_with(' ') {
+        return None;
+    }
+    let rest = &rest[1..];
+    let end = rest.find(|c: char| !(c.is_ascii_digit() || c == ':' || c == '.'))
+        .unwrap_or(rest.len());
+    let (time, offset) = rest.split_at(end);
+    let time = time.split('.').next()?;
+    let time = NaiveTime::parse_from_str(time, "%H:%M:%S")
+        .or_else(|_| NaiveTime::parse_from_str(time, "%H:%M"))
+        .ok()?;
+    let offset = if offset.is_empty() { None } else { Some(parse_offset(offset)?) };
+    Some(CaptureTime::new(date.and_time(time), offset))
+}
+
+fn format_offset(offset: &FixedOffset) -> String {
+    let secs = offset.local_minus_utc();
+    let sign = if secs < 0 { '-' } else { '+' };
+    let secs = secs.abs();
+    format!("{}{:02}:{:02}", sign, secs / 3600, secs / 60 % 60)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CaptureTime {
+    // wall-clock time at the place of capture
+    pub local: NaiveDateTime,
+    // offset of `local` from UTC, if known
+    pub offset: Option<FixedOffset>,
+}
+
+impl CaptureTime {
+    pub fn new(local: NaiveDateTime, offset: Option<FixedOffset>) -> CaptureTime {
+        CaptureTime { local, offset }
+    }
+
+    /// An instant in UTC (e.g. from a video), converted to the local time of `zone`.
+    pub fn from_utc(utc: &DateTime<UTC>, zone: DefaultZone) -> CaptureTime {
+        let offset = zone.offset_at_utc(&utc.naive_utc());
+        CaptureTime {
+            local: utc.with_timezone(&offset).naive_local(),
+            offset: Some(offset),
+        }
+    }
+
+    /// Assumes `zone` if the offset is unknown.
+    pub fn with_default_zone(self, zone: DefaultZone) -> CaptureTime {
+        CaptureTime {
+            local: self.local,
+            offset: Some(self.offset.unwrap_or_else(|| zone.offset_at_local(&self.local))),
+        }
+    }
+
+    /// The calendar date at the place of capture.
+    pub fn date(&self) -> NaiveDate {
+        self.local.date()
+    }
+
+    pub fn to_utc(&self) -> Option<DateTime<UTC>> {
+        self.offset.map(|o| UTC.from_utc_datetime(&(self.local - o)))
+    }
+}
+
+impl Ord for CaptureTime {
+    // by instant where both offsets are known, else by local time
+    fn cmp(&self, other: &CaptureTime) -> Ordering {
+        match (self.to_utc(), other.to_utc()) {
+            (Some(a), Some(b)) => a.cmp(&b).then(self.local.cmp(&other.local)),
+            _ => self.local.cmp(&other.local),
+        }
+    }
+}
+
+impl PartialOrd for CaptureTime {
+    fn partial_cmp(&self, other: &CaptureTime) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl fmt::Display for CaptureTime {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.local.format("%Y-%m-%d %H:%M:%S"))?;
+        if let Some(ref offset) = self.offset {
+            write!(f, "{}", format_offset(offset))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+fn naive(y: i32, m: u32, d: u32, h: u32, min: u32) -> NaiveDateTime {
+    NaiveDate::from_ymd(y, m, d).and_hms(h, min, 0)
+}
+
+#[test]
+fn test_parse_offset() {
+    let a: Vec<_> = ["+02:00", "+0200", "+02", "-05:30", "Z", "UTC", "2:00", "+2:00", "+24:00"]
+        .iter()
+        .map(|s| parse_offset(s).map(|o| o.local_minus_utc()))
+        .collect();
+    let e = vec![Some(7200), Some(7200), Some(7200), Some(-19800), Some(0), Some(0), None, None, None];
+    assert_eq!(a, e);
+
+    assert_eq!("+01:00".parse::<DefaultZone>().unwrap(), DefaultZone::Fixed(FixedOffset::east(3600)));
+    assert_eq!("local".parse::<DefaultZone>().unwrap(), DefaultZone::Local);
+    assert!("Europe/Berlin".parse::<DefaultZone>().is_err());
+}
+
+#[test]
+fn test_parse_iso_datetime() {
+    let a: Vec<_> = ["2017-05-12T18:30:00+02:00", "2017-05-12T18:30:00.123Z", "2017-05-12T18:30",
+                     "2017-05-12 18:30:00", "2017-05-12", "2017-05-12T18:30:00+2", "2017:05:12", "2017-05", "2017"]
+        .iter()
+        .map(|s| parse_iso_datetime(s).map(|t| t.to_string()))
+        .collect();
+    let e = vec![Some("2017-05-12 18:30:00+02:00"), Some("2017-05-12 18:30:00+00:00"),
+                 Some("2017-05-12 18:30:00"), Some("2017-05-12 18:30:00"),
+                 Some("2017-05-12 00:00:00"), None, None, None, None];
+    assert_eq!(a, e.into_iter().map(|s| s.map(String::from)).collect::<Vec<_>>());
+}
+
+#[test]
+fn test_capture_time() {
+    let cest = DefaultZone::Fixed(FixedOffset::east(7200));
+
+    // a video recorded shortly after midnight local time belongs to the next day
+    let utc = UTC.ymd(2017, 5, 12).and_hms(22, 30, 0);
+    let video = CaptureTime::from_utc(&utc, cest);
+    assert_eq!(video.local, naive(2017, 5, 13, 0, 30));
+    assert_eq!(video.date(), NaiveDate::from_ymd(2017, 5, 13));
+    assert_eq!(video.to_utc(), Some(utc));
+    assert_eq!(video.to_string(), "2017-05-13 00:30:00+02:00");
+
+    // a recorded offset takes precedence over the default zone
+    let photo = CaptureTime::new(naive(2017, 5, 12, 23, 45), Some(FixedOffset::east(0)));
+    assert_eq!(photo.with_default_zone(cest), photo);
+    assert!(photo > video);
+
+    let photo = CaptureTime::new(naive(2017, 5, 12, 23, 45), None);
+    assert_eq!(photo.to_string(), "2017-05-12 23:45:00");
+    assert_eq!(photo.to_utc(), None);
+    let photo = photo.with_default_zone(cest);
+    assert_eq!(photo.to_utc(), Some(UTC.ymd(2017, 5, 12).and_hms(21, 45, 0)));
+    assert!(photo < video);
+}
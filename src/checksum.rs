@@ -0,0 +1,112 @@
+// Copyright 2017 Thorben Kroeger.
+// Dual-licensed MIT and Apache 2.0 (see LICENSE files for details).
+
+use std::fmt;
+use std::fs;
+use std::io::Read;
+use std::path::Path;
+use std::str::FromStr;
+
+use blake3;
+use hex;
+use sha2::{Digest, Sha256};
+
+#[cfg(test)]
+use fileops::make_test_dir;
+
+use errors::*;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all="snake_case")]
+pub enum HashAlgorithm {
+    Blake3,
+    Sha256,
+}
+
+impl FromStr for HashAlgorithm {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<HashAlgorithm> {
+        match s {
+            "blake3" => Ok(HashAlgorithm::Blake3),
+            "sha256" => Ok(HashAlgorithm::Sha256),
+            _ => bail!("unknown hash algorithm '{}' (expected blake3 or sha256)", s),
+        }
+    }
+}
+
+impl fmt::Display for HashAlgorithm {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            HashAlgorithm::Blake3 => write!(f, "blake3"),
+            HashAlgorithm::Sha256 => write!(f, "sha256"),
+        }
+    }
+}
+
+/// Hash of a file's content, as recorded in the journal.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Checksum {
+    pub algorithm: HashAlgorithm,
+    // lowercase hex
+    pub digest: String,
+}
+
+impl fmt::Display for Checksum {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.algorithm, self.digest)
+    }
+}
+
+fn read_chunks<F>(file: &Path, mut f: F) -> Result<()>
+where
+    F: FnMut(&[u8]),
+{
+    let mut file = fs::File::open(file)?;
+    let mut buf = vec![0u8; 64 * 1024];
+    loop {
+        let n = file.read(&mut buf)?;
+        if n == 0 {
+            return Ok(());
+        }
+        f(&buf[..n]);
+    }
+}
+
+pub fn hash_file(file: &Path, algorithm: HashAlgorithm) -> Result<Checksum> {
+    let digest = match algorithm {
+        HashAlgorithm::Blake3 => {
+            let mut hasher = blake3::Hasher::new();
+            read_chunks(file, |chunk| { hasher.update(chunk); })?;
+            hasher.finalize().to_hex().to_string()
+        }
+        HashAlgorithm::Sha256 => {
+            let mut hasher = Sha256::new();
+            read_chunks(file, |chunk| hasher.input(chunk))?;
+            hex::encode(hasher.result())
+        }
+    };
+    Ok(Checksum { algorithm, digest })
+}
+
+/// Returns whether `file` still has the content described by `checksum`.
+pub fn verify_file(file: &Path, checksum: &Checksum) -> Result<bool> {
+    Ok(hash_file(file, checksum.algorithm)? == *checksum)
+}
+
+#[test]
+fn test_hash_file() {
+    let dir = make_test_dir("hash-file");
+    let file = dir.join("a.txt");
+    fs::write(&file, b"abc").unwrap();
+
+    let a = hash_file(&file, HashAlgorithm::Sha256).unwrap();
+    assert_eq!(a.digest, "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad");
+    let a = hash_file(&file, HashAlgorithm::Blake3).unwrap();
+    assert_eq!(a.digest, "6437b3ac38465133ffb63b75273a8db548c558465d79db03fd359c6cd5bd9d85");
+    assert_eq!(a.to_string(), format!("blake3:{}", a.digest));
+
+    assert!(verify_file(&file, &a).unwrap());
+    fs::write(&file, b"abd").unwrap();
+    assert!(!verify_file(&file, &a).unwrap());
+}
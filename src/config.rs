@@ -0,0 +1,173 @@
+// Copyright 2017 Thorben Kroeger.
+// Dual-licensed MIT and Apache 2.0 (see LICENSE files for details).
+
+// Optional configuration file (TOML), e.g.
+//
+//   [naming]
+//   folder = "{year}/{date} {title}"
+//   file = "{seq:4}_{date}"
+//
+//   [time]
+//   default_zone = "+02:00"
+//   date_sources = ["datetime_original", "filename", "mtime"]
+//
+//   [places]
+//   gazetteer = "/usr/share/geonames/cities1000.txt"
+//   max_distance = 30
+//
+//   [pairing]
+//   strategies = ["stem"]
+//
+//   [file_types]
+//   iiq = { kind = "raw" }
+//
+// Command line arguments take precedence over the configuration file.
+
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use toml;
+
+use datesource::DEFAULT_DATE_SOURCES;
+use errors::*;
+use filetypes::FileTypeConfig;
+use geocode::DEFAULT_MAX_DISTANCE_KM;
+use pairing::DEFAULT_PAIRING;
+use template::{DEFAULT_FILE_TEMPLATE, DEFAULT_FOLDER_TEMPLATE};
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub naming: NamingConfig,
+    pub time: TimeConfig,
+    pub places: PlacesConfig,
+    pub pairing: PairingConfig,
+    // additional file types by extension, see `FileTypes`
+    pub file_types: HashMap<String, FileTypeConfig>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct NamingConfig {
+    pub folder: String,
+    pub file: String,
+}
+
+impl Default for NamingConfig {
+    fn default() -> NamingConfig {
+        NamingConfig {
+            folder: DEFAULT_FOLDER_TEMPLATE.into(),
+            file: DEFAULT_FILE_TEMPLATE.into(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TimeConfig {
+    // zone for capture times without offset: `local` or e.g. `+02:00`
+    pub default_zone: String,
+    // where to take capture times from, in order (see `DateSource`)
+    pub date_sources: Vec<String>,
+}
+
+impl Default for TimeConfig {
+    fn default() -> TimeConfig {
+        TimeConfig {
+            default_zone: "local".into(),
+            date_sources: DEFAULT_DATE_SOURCES.iter().map(|s| s.to_string()).collect(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PlacesConfig {
+    // GeoNames dump for `{country}`, `{region}` and `{city}`, and for
+    // location keywords
+    pub gazetteer: Option<PathBuf>,
+    // how far away (in km) the nearest city may be
+    pub max_distance: f64,
+}
+
+impl Default for PlacesConfig {
+    fn default() -> PlacesConfig {
+        PlacesConfig {
+            gazetteer: None,
+            max_distance: DEFAULT_MAX_DISTANCE_KM,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PairingConfig {
+    // how to pair files shot together without `DerivedFrom`, in order
+    // (see `PairingStrategy`)
+    pub strategies: Vec<String>,
+}
+
+impl Default for PairingConfig {
+    fn default() -> PairingConfig {
+        PairingConfig {
+            strategies: DEFAULT_PAIRING.iter().map(|s| s.to_string()).collect(),
+        }
+    }
+}
+
+// `$XDG_CONFIG_HOME/imgor/config.toml`, falling back to `~/.config`
+fn default_config_path() -> Option<PathBuf> {
+    let base = env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|h| PathBuf::from(h).join(".config")))?;
+    Some(base.join("imgor").join("config.toml"))
+}
+
+pub fn parse_config(s: &str) -> Result<Config> {
+    Ok(toml::from_str(s)?)
+}
+
+/// Reads the configuration from `path`, or from the default location
+/// if `path` is `None`. A missing default configuration is not an error.
+pub fn load_config(path: Option<&Path>) -> Result<Config> {
+    let path = match path {
+        Some(p) => p.to_path_buf(),
+        None => match default_config_path() {
+            Some(ref p) if p.is_file() => p.clone(),
+            _ => return Ok(Config::default()),
+        },
+    };
+    let s = fs::read_to_string(&path)?;
+    parse_config(&s).chain_err(|| format!("invalid configuration file '{}'", path.display()))
+}
+
+#[test]
+fn test_parse_config() {
+    let c = parse_config("[naming]\nfolder = \"{year}/{date}\"\n").unwrap();
+    assert_eq!(c.naming.folder, "{year}/{date}");
+    assert_eq!(c.naming.file, DEFAULT_FILE_TEMPLATE);
+
+    assert_eq!(c.time.default_zone, "local");
+
+    let c = parse_config("[time]\ndefault_zone = \"+02:00\"\n").unwrap();
+    assert_eq!(c.naming.folder, DEFAULT_FOLDER_TEMPLATE);
+    assert_eq!(c.time.default_zone, "+02:00");
+    assert_eq!(c.time.date_sources.len(), DEFAULT_DATE_SOURCES.len());
+
+    assert_eq!(c.places.gazetteer, None);
+    assert_eq!(c.pairing.strategies, vec!["stem", "capture_time"]);
+
+    let c = parse_config("[places]\ngazetteer = \"/geonames/cities1000.txt\"\nmax_distance = 30\n").unwrap();
+    assert_eq!(c.places.gazetteer, Some(PathBuf::from("/geonames/cities1000.txt")));
+    assert_eq!(c.places.max_distance, 30.0);
+
+    let c = parse_config("[file_types]\niiq = { kind = \"raw\" }\nwebm = { kind = \"video\", metadata = \"none\" }\n")
+        .unwrap();
+    assert_eq!(c.file_types["iiq"].kind, "raw");
+    assert_eq!(c.file_types["webm"].metadata.as_deref(), Some("none"));
+    assert!(parse_config("[file_types]\niiq = { knd = \"raw\" }\n").is_err());
+
+    assert!(parse_config("[naming]\nfoldr = \"{date}\"\n").is_err());
+}
@@ -0,0 +1,178 @@
+// Copyright 2017 Thorben Kroeger.
+// Dual-licensed MIT and Apache 2.0 (see LICENSE files for details).
+
+// The editing history darktable keeps in its XMP sidecars, e.g.
+//
+//   darktable:history_end="10"
+//   <darktable:history><rdf:Seq>
+//     <rdf:li darktable:operation="exposure" darktable:enabled="1"
+//             darktable:modversion="5" darktable:params="0000..."/>
+//
+// Only the first `history_end` items are in effect; the ones after
+// were undone in darktable (but may be redone).
+
+use std::path::Path;
+
+use errors::*;
+
+static XMP_DARKTABLE_HISTORY: &str = "Xmp.darktable.history";
+static XMP_DARKTABLE_HISTORY_END: &str = "Xmp.darktable.history_end";
+static XMP_DARKTABLE_AUTO_PRESETS_APPLIED: &str = "Xmp.darktable.auto_presets_applied";
+
+// operations darktable applies to every raw file by itself
+static AUTO_APPLIED_OPERATIONS: &[&str] = &[
+    "rawprepare", "demosaic", "highlights", "colorin", "colorout", "gamma", "dither",
+    "flip", "sharpen", "basecurve",
+];
+
+/// A step in the editing history: a module turned on or off, or its
+/// parameters changed.
+#[derive(Debug, Clone, PartialEq)]
+pub struct HistoryItem {
+    // e.g. `exposure`, `clipping` (crop), `temperature` (white balance)
+    pub operation: String,
+    pub enabled: bool,
+    pub modversion: u32,
+    // hex encoded, or `gz` + base64 of the compressed parameters
+    pub params: String,
+    // name and number of a module instance, if there are several
+    pub multi_name: String,
+    pub multi_priority: u32,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct DarktableHistory {
+    pub items: Vec<HistoryItem>,
+    // number of items in effect
+    pub end: usize,
+    pub auto_presets_applied: bool,
+    // number of drawn masks
+    pub masks: usize,
+}
+
+impl DarktableHistory {
+    /// The items in effect, oldest first.
+    pub fn active(&self) -> &[HistoryItem] {
+        &self.items[..self.end.min(self.items.len())]
+    }
+
+    /// The enabled module instances in effect, each with its latest item.
+    pub fn operations(&self) -> Vec<&HistoryItem> {
+        let mut latest: Vec<&HistoryItem> = vec![];
+        for item in self.active() {
+            let same = |i: &&HistoryItem| {
+                i.operation == item.operation && i.multi_priority == item.multi_priority
+            };
+            match latest.iter().position(same) {
+                Some(pos) => latest[pos] = item,
+                None => latest.push(item),
+            }
+        }
+        latest.retain(|i| i.enabled);
+        latest
+    }
+
+    /// Whether `operation` (e.g. `clipping`) is in effect.
+    pub fn applies(&self, operation: &str) -> bool {
+        self.operations().iter().any(|i| i.operation == operation)
+    }
+
+    /// Whether any module besides the ones darktable applies by itself is in effect.
+    pub fn is_edited(&self) -> bool {
+        self.operations().iter().any(|i| !AUTO_APPLIED_OPERATIONS.contains(&i.operation.as_str()))
+    }
+}
+
+/// Reads the history of the sidecar `path` from its XMP tags, looked up by
+/// `tag`. `None` if the file has no darktable history.
+pub fn parse_history<F>(path: &Path, tag: F, masks: usize) -> Result<Option<DarktableHistory>>
+where
+    F: Fn(&str) -> Option<String>,
+{
+    let invalid = |tag: &str, value: &str| -> Error {
+        ErrorKind::InvalidTag(path.to_path_buf(), tag.to_string(), value.to_string()).into()
+    };
+    let number = |name: &str| -> Result<Option<u32>> {
+        match tag(name) {
+            Some(value) => value.trim().parse().map(Some).map_err(|_| invalid(name, &value)),
+            None => Ok(None),
+        }
+    };
+
+    let mut items = vec![];
+    loop {
+        let field = |name: &str| format!("{}[{}]/darktable:{}", XMP_DARKTABLE_HISTORY, items.len() + 1, name);
+        let operation = match tag(&field("operation")) {
+            Some(operation) => operation,
+            None => break,
+        };
+        items.push(HistoryItem {
+            operation,
+            enabled: number(&field("enabled"))? == Some(1),
+            modversion: number(&field("modversion"))?.unwrap_or(0),
+            params: tag(&field("params")).unwrap_or_default(),
+            multi_name: tag(&field("multi_name")).unwrap_or_default(),
+            multi_priority: number(&field("multi_priority"))?.unwrap_or(0),
+        });
+    }
+
+    let end = number(XMP_DARKTABLE_HISTORY_END)?;
+    if items.is_empty() && end.is_none() {
+        return Ok(None);
+    }
+    Ok(Some(DarktableHistory {
+        // without `history_end` (older versions of darktable), all items are in effect
+        end: end.map_or(items.len(), |e| e as usize),
+        items,
+        auto_presets_applied: number(XMP_DARKTABLE_AUTO_PRESETS_APPLIED)? == Some(1),
+        masks,
+    }))
+}
+
+#[cfg(test)]
+fn test_history(tags: &[(&str, &str)]) -> Result<Option<DarktableHistory>> {
+    use std::collections::HashMap;
+    let tags: HashMap<_, _> = tags.iter().cloned().collect();
+    parse_history(Path::new("/a/1.cr2.xmp"), |t| tags.get(t).map(|v| v.to_string()), 0)
+}
+
+#[test]
+fn test_parse_history() {
+    let h = test_history(&[
+        ("Xmp.darktable.history_end", "3"),
+        ("Xmp.darktable.auto_presets_applied", "1"),
+        ("Xmp.darktable.history[1]/darktable:operation", "sharpen"),
+        ("Xmp.darktable.history[1]/darktable:enabled", "1"),
+        ("Xmp.darktable.history[1]/darktable:modversion", "1"),
+        ("Xmp.darktable.history[1]/darktable:params", "000000400000003f0000003f"),
+        ("Xmp.darktable.history[2]/darktable:operation", "exposure"),
+        ("Xmp.darktable.history[2]/darktable:enabled", "1"),
+        ("Xmp.darktable.history[3]/darktable:operation", "sharpen"),
+        ("Xmp.darktable.history[3]/darktable:enabled", "0"),
+        // undone
+        ("Xmp.darktable.history[4]/darktable:operation", "clipping"),
+        ("Xmp.darktable.history[4]/darktable:enabled", "1"),
+    ]).unwrap().unwrap();
+
+    assert_eq!(h.items.len(), 4);
+    assert_eq!(h.items[0].modversion, 1);
+    assert_eq!(h.items[0].params, "000000400000003f0000003f");
+    assert!(h.auto_presets_applied);
+    assert_eq!(h.active().len(), 3);
+    let ops: Vec<_> = h.operations().iter().map(|i| i.operation.as_str()).collect();
+    assert_eq!(ops, vec!["exposure"]);
+    assert!(h.applies("exposure"));
+    assert!(!h.applies("sharpen"));
+    assert!(!h.applies("clipping"));
+    assert!(h.is_edited());
+
+    let h = test_history(&[
+        ("Xmp.darktable.history[1]/darktable:operation", "flip"),
+        ("Xmp.darktable.history[1]/darktable:enabled", "1"),
+    ]).unwrap().unwrap();
+    assert_eq!(h.end, 1);
+    assert!(!h.is_edited());
+
+    assert_eq!(test_history(&[]).unwrap(), None);
+    assert!(test_history(&[("Xmp.darktable.history_end", "x")]).is_err());
+}
@@ -0,0 +1,243 @@
+// Copyright 2017 Thorben Kroeger.
+// Dual-licensed MIT and Apache 2.0 (see LICENSE files for details).
+
+// Where the capture time of a file comes from.
+//
+// Not every file has an EXIF `DateTimeOriginal`: scans, screenshots and
+// images received via messengers usually do not. For these, the capture
+// time is taken from the first of a list of sources which yields one.
+
+use std::convert::TryFrom;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+use std::str::FromStr;
+use std::time::UNIX_EPOCH;
+
+use chrono::offset::TimeZone;
+use chrono::{NaiveDate, UTC};
+use regex::Regex;
+
+use capture::{CaptureTime, DefaultZone};
+use errors::*;
+use filetypes::{FileTypes, MetadataBackend};
+use metadata::Metadata;
+use quicktime;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum DateSource {
+    // EXIF `DateTimeOriginal`, or the recording date of a video
+    DateTimeOriginal,
+    // EXIF `DateTimeDigitized` (`CreateDate`)
+    CreateDate,
+    // XMP `photoshop:DateCreated` or `xmp:CreateDate`
+    Xmp,
+    // e.g. `IMG_20170512_123456.jpg`
+    FileName,
+    // e.g. `2017-05-12 Wedding/scan001.tif`
+    FolderName,
+    // modification time of the file
+    ModificationTime,
+}
+
+pub static DEFAULT_DATE_SOURCES: &[DateSource] = &[
+    DateSource::DateTimeOriginal,
+    DateSource::CreateDate,
+    DateSource::Xmp,
+    DateSource::FileName,
+    DateSource::FolderName,
+    DateSource::ModificationTime,
+];
+
+static NAMES: &[(DateSource, &str)] = &[
+    (DateSource::DateTimeOriginal, "datetime_original"),
+    (DateSource::CreateDate, "create_date"),
+    (DateSource::Xmp, "xmp"),
+    (DateSource::FileName, "filename"),
+    (DateSource::FolderName, "folder"),
+    (DateSource::ModificationTime, "mtime"),
+];
+
+impl FromStr for DateSource {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<DateSource> {
+        match NAMES.iter().find(|&&(_, name)| name == s) {
+            Some(&(source, _)) => Ok(source),
+            None => {
+                let names: Vec<_> = NAMES.iter().map(|&(_, name)| name).collect();
+                bail!("unknown date source '{}' (expected one of {})", s, names.join(", "))
+            }
+        }
+    }
+}
+
+impl fmt::Display for DateSource {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = NAMES.iter().find(|&&(source, _)| source == *self).unwrap().1;
+        write!(f, "{}", name)
+    }
+}
+
+/// Parses a comma separated list of date sources, e.g. `datetime_original,filename`.
+pub fn parse_date_sources(s: &str) -> Result<Vec<DateSource>> {
+    s.split(',').map(|name| name.trim().parse()).collect()
+}
+
+lazy_static! {
+    // date and time, most specific first
+    static ref DATETIME_PATTERNS: Vec<Regex> = vec![
+        // `WhatsApp Image 2017-05-12 at 12.34.56`, `Screenshot_2017-05-12-12-34-56`
+        Regex::new(r"(?:^|\D)((?:19|20)\d\d)[-_.](\d\d)[-_.](\d\d)(?:[ _T-]|\sat\s)(\d\d)[-_.:](\d\d)[-_.:](\d\d)").unwrap(),
+        // `IMG_20170512_123456`, `PXL_20170512_123456789`, `20170512T123456`
+        Regex::new(r"(?:^|\D)((?:19|20)\d\d)(\d\d)(\d\d)[_T-]?(\d\d)(\d\d)(\d\d)").unwrap(),
+    ];
+    // `IMG-20170512-WA0001`, `2017-05-12 Wedding`
+    static ref DATE_PATTERN: Regex =
+        Regex::new(r"(?:^|\D)((?:19|20)\d\d)-?(\d\d)-?(\d\d)(?:\D|$)").unwrap();
+}
+
+/// Finds a date (and time, if present) in a file or folder name.
+pub fn parse_name_date(name: &str) -> Option<CaptureTime> {
+    let number = |c: &::regex::Captures, i: usize| c[i].parse::<u32>().unwrap();
+    for pattern in DATETIME_PATTERNS.iter() {
+        for c in pattern.captures_iter(name) {
+            let datetime = NaiveDate::from_ymd_opt(number(&c, 1) as i32, number(&c, 2), number(&c, 3))
+                .and_then(|d| d.and_hms_opt(number(&c, 4), number(&c, 5), number(&c, 6)));
+            if let Some(datetime) = datetime {
+                return Some(CaptureTime::new(datetime, None));
+            }
+        }
+    }
+    DATE_PATTERN.captures_iter(name)
+        .filter_map(|c| NaiveDate::from_ymd_opt(number(&c, 1) as i32, number(&c, 2), number(&c, 3)))
+        .map(|d| CaptureTime::new(d.and_hms(0, 0, 0), None))
+        .next()
+}
+
+fn modification_time(path: &Path, zone: DefaultZone) -> Option<CaptureTime> {
+    let modified = fs::metadata(path).ok()?.modified().ok()?;
+    let seconds = modified.duration_since(UNIX_EPOCH).ok()?.as_secs();
+    // out of chrono's range for bogus file times
+    let modified = UTC.timestamp_opt(i64::try_from(seconds).ok()?, 0).single()?;
+    Some(CaptureTime::from_utc(&modified, zone))
+}
+
+/// Returns the capture time of a photo or video from the first of `sources`
+/// which yields one, together with that source. Embedded dates are read with
+/// the metadata backend of the file's type in `types`. Capture times which do
+/// not record their offset from UTC are taken to be in `zone`. Fails on
+/// malformed dates in the metadata, rather than silently falling back to the
+/// next source.
+pub fn extract_capture_time(path: &Path, types: &FileTypes, sources: &[DateSource], zone: DefaultZone)
+    -> Result<Option<(CaptureTime, DateSource)>>
+{
+    let backend = types.backend(path).unwrap_or(MetadataBackend::Exiv2);
+    // read once, and only if needed
+    let mut meta: Option<Option<Metadata>> = None;
+
+    for &source in sources {
+        let embedded = match source {
+            DateSource::DateTimeOriginal | DateSource::CreateDate | DateSource::Xmp => {
+                backend == MetadataBackend::Exiv2
+            }
+            _ => false,
+        };
+        if embedded && meta.is_none() {
+            meta = Some(Metadata::new(path).ok());
+        }
+        let m = meta.as_ref().and_then(|m| m.as_ref());
+
+        let time = match source {
+            DateSource::DateTimeOriginal if backend == MetadataBackend::QuickTime => {
+                // a video without (readable) recording date is treated like a photo without EXIF
+                quicktime::creation_date(path, zone).ok().and_then(|t| t)
+            }
+            DateSource::DateTimeOriginal => m.map_or(Ok(None), |m| m.datetime_original())?,
+            DateSource::CreateDate => m.map_or(Ok(None), |m| m.create_date())?,
+            DateSource::Xmp => m.map_or(Ok(None), |m| m.xmp_create_date())?,
+            DateSource::FileName => {
+                path.file_name().and_then(|n| n.to_str()).and_then(parse_name_date)
+            }
+            DateSource::FolderName => {
+                // the nearest folder with a date in its name
+                path.ancestors().skip(1)
+                    .filter_map(|d| d.file_name().and_then(|n| n.to_str()))
+                    .filter_map(parse_name_date)
+                    .next()
+            }
+            DateSource::ModificationTime => modification_time(path, zone),
+        };
+        if let Some(time) = time {
+            return Ok(Some((time.with_default_zone(zone), source)));
+        }
+    }
+    Ok(None)
+}
+
+#[test]
+fn test_date_sources() {
+    use self::DateSource::*;
+    assert_eq!(parse_date_sources("filename, mtime").unwrap(), vec![FileName, ModificationTime]);
+    assert!(parse_date_sources("filename,exif").is_err());
+    for source in DEFAULT_DATE_SOURCES {
+        assert_eq!(source.to_string().parse::<DateSource>().unwrap(), *source);
+    }
+}
+
+#[test]
+fn test_parse_name_date() {
+    let a: Vec<_> = [
+        "IMG_20170512_123456.jpg",
+        "PXL_20170512_123456789.jpg",
+        "VID_20170512_123456.mp4",
+        "WhatsApp Image 2017-05-12 at 12.34.56.jpeg",
+        "Screenshot_2017-05-12-12-34-56.png",
+        "IMG-20170512-WA0001.jpg",
+        "2017-05-12 Wedding",
+        "scan_19991231.tif",
+        // invalid dates and too many digits
+        "IMG_20171312_123456.jpg",
+        "DSC_0001.JPG",
+        "123201705120.jpg",
+    ].iter().map(|n| parse_name_date(n).map(|t| t.to_string())).collect();
+    let e = vec![
+        Some("2017-05-12 12:34:56"),
+        Some("2017-05-12 12:34:56"),
+        Some("2017-05-12 12:34:56"),
+        Some("2017-05-12 12:34:56"),
+        Some("2017-05-12 12:34:56"),
+        Some("2017-05-12 00:00:00"),
+        Some("2017-05-12 00:00:00"),
+        Some("1999-12-31 00:00:00"),
+        None,
+        None,
+        None,
+    ];
+    assert_eq!(a, e.into_iter().map(|s| s.map(String::from)).collect::<Vec<_>>());
+}
+
+#[test]
+fn test_extract_capture_time() {
+    use chrono::FixedOffset;
+    use fileops::make_test_dir;
+
+    let dir = make_test_dir("date-sources").join("2017-05-12 Wedding");
+    fs::create_dir(&dir).unwrap();
+    let file = dir.join("IMG_20170513_100000.png");
+    fs::write(&file, b"not an image").unwrap();
+    let utc = DefaultZone::Fixed(FixedOffset::east(0));
+    let types = FileTypes::default();
+
+    let (time, source) = extract_capture_time(&file, &types, DEFAULT_DATE_SOURCES, utc).unwrap().unwrap();
+    assert_eq!((time.to_string().as_str(), source), ("2017-05-13 10:00:00+00:00", DateSource::FileName));
+
+    let sources = [DateSource::Xmp, DateSource::FolderName];
+    let (time, source) = extract_capture_time(&file, &types, &sources, utc).unwrap().unwrap();
+    assert_eq!((time.to_string().as_str(), source), ("2017-05-12 00:00:00+00:00", DateSource::FolderName));
+
+    let (_, source) = extract_capture_time(&file, &types, &[DateSource::ModificationTime], utc).unwrap().unwrap();
+    assert_eq!(source, DateSource::ModificationTime);
+
+    assert_eq!(extract_capture_time(&file, &types, &[DateSource::DateTimeOriginal], utc).unwrap(), None);
+}
@@ -5,6 +5,9 @@ error_chain!{
     foreign_links {
         Io(::std::io::Error);
         Rexiv2(::rexiv2::Rexiv2Error);
+        Json(::serde_json::Error);
+        Pattern(::glob::PatternError);
+        Toml(::toml::de::Error);
     }
 
     errors {
@@ -12,5 +15,49 @@ error_chain!{
             description("path is not valid utf-8")
             display("path '{}' is not valid utf-8", path.display())
         }
+        DestinationExists(path: ::std::path::PathBuf) {
+            description("destination already exists")
+            display("destination '{}' already exists", path.display())
+        }
+        FileMissing(path: ::std::path::PathBuf) {
+            description("file does not exist")
+            display("file '{}' does not exist", path.display())
+        }
+        ReferenceToOtherFolder(file: ::std::path::PathBuf, referenced: ::std::path::PathBuf) {
+            description("reference to a file in another folder")
+            display("'{}' cannot refer to '{}', which is not in its folder", file.display(), referenced.display())
+        }
+        InvalidTemplate(template: String, reason: String) {
+            description("invalid naming template")
+            display("invalid naming template '{}': {}", template, reason)
+        }
+        PlanConflicts(count: usize) {
+            description("plan has conflicts")
+            display("plan has {} conflict(s)", count)
+        }
+        UnsupportedPlanVersion(version: u32) {
+            description("unsupported plan version")
+            display("unsupported plan version {}", version)
+        }
+        InvalidTag(path: ::std::path::PathBuf, tag: String, value: String) {
+            description("invalid metadata value")
+            display("file '{}': invalid {} value '{}'", path.display(), tag, value)
+        }
+        UnknownColorLabel(path: ::std::path::PathBuf, label: String) {
+            description("unknown darktable color label")
+            display("file '{}': unknown darktable color label '{}'", path.display(), label)
+        }
+        InvalidDateTime(value: String) {
+            description("invalid date/time")
+            display("invalid date/time '{}'", value)
+        }
+        VerificationFailed(src: ::std::path::PathBuf, dest: ::std::path::PathBuf) {
+            description("copy differs from its source")
+            display("copy '{}' differs from its source '{}'", dest.display(), src.display())
+        }
+        NoSidecar(path: ::std::path::PathBuf) {
+            description("photo has no XMP sidecar")
+            display("'{}' has no XMP sidecar to write to", path.display())
+        }
     }
 }
\ No newline at end of file
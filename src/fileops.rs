@@ -0,0 +1,173 @@
+// Copyright 2017 Thorben Kroeger.
+// Dual-licensed MIT and Apache 2.0 (see LICENSE files for details).
+
+use std::fs;
+use std::io::{self, Read};
+use std::path::Path;
+
+#[cfg(test)]
+use std::env;
+#[cfg(test)]
+use std::path::PathBuf;
+
+use checksum::{Checksum, HashAlgorithm, hash_file};
+use errors::*;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TransferMode {
+    Copy,
+    Move,
+}
+
+#[derive(Debug)]
+pub enum MoveOutcome {
+    // source and destination are on the same filesystem
+    Renamed,
+    // copied across devices, verified and the source removed
+    Copied,
+    // copied across devices and verified, but the source could not be removed
+    SourceNotRemoved(io::Error),
+}
+
+fn files_equal(a: &Path, b: &Path) -> io::Result<bool> {
+    if fs::metadata(a)?.len() != fs::metadata(b)?.len() {
+        return Ok(false);
+    }
+
+    let mut fa = fs::File::open(a)?;
+    let mut fb = fs::File::open(b)?;
+    let mut buf_a = vec![0u8; 64 * 1024];
+    let mut buf_b = vec![0u8; 64 * 1024];
+    loop {
+        let n = fa.read(&mut buf_a)?;
+        if n == 0 {
+            return Ok(true);
+        }
+        fb.read_exact(&mut buf_b[..n])?;
+        if buf_a[..n] != buf_b[..n] {
+            return Ok(false);
+        }
+    }
+}
+
+// Copies `src` to `dest`, syncs `dest` to disk and checks that both have the
+// same content, by hashing them with `algorithm` or else comparing them byte
+// by byte. A copy which turns out to differ is removed again.
+fn copy_verified(src: &Path, dest: &Path, algorithm: Option<HashAlgorithm>)
+    -> Result<Option<Checksum>>
+{
+    let src_checksum = match algorithm {
+        Some(algorithm) => Some(hash_file(src, algorithm)?),
+        None => None,
+    };
+    fs::copy(src, dest)?;
+    fs::File::open(dest)?.sync_all()?;
+
+    let equal = match (algorithm, &src_checksum) {
+        (Some(algorithm), Some(checksum)) => hash_file(dest, algorithm)? == *checksum,
+        _ => files_equal(src, dest)?,
+    };
+    if !equal {
+        let _ = fs::remove_file(dest);
+        bail!(ErrorKind::VerificationFailed(src.to_path_buf(), dest.to_path_buf()));
+    }
+    Ok(src_checksum)
+}
+
+/// Copies `src` to `dest` and verifies the copy (see `move_file`).
+/// Returns the checksum of the content if an `algorithm` is given.
+pub fn copy_file(src: &Path, dest: &Path, algorithm: Option<HashAlgorithm>)
+    -> Result<Option<Checksum>>
+{
+    if dest.exists() {
+        bail!(ErrorKind::DestinationExists(dest.to_path_buf()));
+    }
+    copy_verified(src, dest, algorithm)
+}
+
+/// Moves `src` to `dest`. Uses a plain rename where possible, and falls back
+/// to copy, verify and delete if `src` and `dest` live on different devices.
+/// If an `algorithm` is given, copies are verified by hashing source and
+/// destination, and the checksum of the content is returned.
+pub fn move_file(src: &Path, dest: &Path, algorithm: Option<HashAlgorithm>)
+    -> Result<(MoveOutcome, Option<Checksum>)>
+{
+    if dest.exists() {
+        bail!(ErrorKind::DestinationExists(dest.to_path_buf()));
+    }
+
+    match fs::rename(src, dest) {
+        Ok(()) => {
+            let checksum = match algorithm {
+                Some(algorithm) => Some(hash_file(dest, algorithm)?),
+                None => None,
+            };
+            Ok((MoveOutcome::Renamed, checksum))
+        }
+        // source and destination are on different filesystems (`EXDEV`,
+        // `ERROR_NOT_SAME_DEVICE`)
+        Err(ref e) if e.kind() == io::ErrorKind::CrossesDevices => {
+            let checksum = copy_verified(src, dest, algorithm)?;
+            match fs::remove_file(src) {
+                Ok(()) => Ok((MoveOutcome::Copied, checksum)),
+                Err(e) => Ok((MoveOutcome::SourceNotRemoved(e), checksum)),
+            }
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+#[cfg(test)]
+pub fn make_test_dir(name: &str) -> PathBuf {
+    let dir = env::temp_dir().join(format!("imgor-test-{}", name));
+    if dir.exists() {
+        fs::remove_dir_all(&dir).unwrap();
+    }
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+#[test]
+fn test_move_file() {
+    let dir = make_test_dir("move-file");
+    let src = dir.join("a.cr2");
+    let dest = dir.join("b.cr2");
+    fs::write(&src, b"raw data").unwrap();
+
+    match move_file(&src, &dest, None).unwrap() {
+        (MoveOutcome::Renamed, None) => {}
+        outcome => panic!("unexpected outcome {:?}", outcome),
+    }
+    assert!(!src.exists());
+    assert_eq!(fs::read(&dest).unwrap(), b"raw data");
+}
+
+#[test]
+fn test_move_file_refuses_to_overwrite() {
+    let dir = make_test_dir("move-file-overwrite");
+    let src = dir.join("a.cr2");
+    let dest = dir.join("b.cr2");
+    fs::write(&src, b"new").unwrap();
+    fs::write(&dest, b"old").unwrap();
+
+    assert!(move_file(&src, &dest, None).is_err());
+    assert!(copy_file(&src, &dest, None).is_err());
+    assert_eq!(fs::read(&src).unwrap(), b"new");
+    assert_eq!(fs::read(&dest).unwrap(), b"old");
+}
+
+#[test]
+fn test_copy_verified() {
+    let dir = make_test_dir("copy-verified");
+    let src = dir.join("a.jpg");
+    let dest = dir.join("b.jpg");
+    fs::write(&src, b"jpeg data").unwrap();
+
+    assert_eq!(copy_verified(&src, &dest, None).unwrap(), None);
+    assert!(src.exists());
+    assert!(files_equal(&src, &dest).unwrap());
+
+    let dest = dir.join("c.jpg");
+    let checksum = copy_verified(&src, &dest, Some(HashAlgorithm::Sha256)).unwrap();
+    assert_eq!(checksum, Some(hash_file(&dest, HashAlgorithm::Sha256).unwrap()));
+}
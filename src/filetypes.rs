@@ -0,0 +1,192 @@
+// Copyright 2017 Thorben Kroeger.
+// Dual-licensed MIT and Apache 2.0 (see LICENSE files for details).
+
+// The types of files imgor handles, by extension: what kind of file it is,
+// and how its metadata is read. Files of other types are left alone.
+//
+// More types can be added (or built-in ones changed) in the configuration:
+//
+//   [file_types]
+//   iiq = { kind = "raw" }
+//   webm = { kind = "video", metadata = "none" }
+
+use std::collections::HashMap;
+use std::path::Path;
+use std::str::FromStr;
+
+use errors::*;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileKind {
+    // straight from the camera's sensor, e.g. `cr2`, `nef`
+    Raw,
+    // rendered images, e.g. `jpg` (from the camera or developed from a raw)
+    Developed,
+    // metadata only, e.g. darktable's `xmp` or RawTherapee's `pp3`
+    Sidecar,
+    Video,
+    // e.g. voice memos recorded along with photos
+    Audio,
+}
+
+impl FromStr for FileKind {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<FileKind> {
+        match s {
+            "raw" => Ok(FileKind::Raw),
+            "developed" => Ok(FileKind::Developed),
+            "sidecar" => Ok(FileKind::Sidecar),
+            "video" => Ok(FileKind::Video),
+            "audio" => Ok(FileKind::Audio),
+            _ => bail!("invalid file kind '{}' (expected raw, developed, sidecar, video or audio)", s),
+        }
+    }
+}
+
+/// How the metadata of a file is read (and written).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MetadataBackend {
+    // EXIF, IPTC and XMP through exiv2, which can also write them
+    Exiv2,
+    // recording date from the `moov` atom of QuickTime/MP4 containers (read only)
+    QuickTime,
+    // dated by file name, folder name or modification time only
+    None,
+}
+
+impl FromStr for MetadataBackend {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<MetadataBackend> {
+        match s {
+            "exiv2" => Ok(MetadataBackend::Exiv2),
+            "quicktime" => Ok(MetadataBackend::QuickTime),
+            "none" => Ok(MetadataBackend::None),
+            _ => bail!("invalid metadata backend '{}' (expected exiv2, quicktime or none)", s),
+        }
+    }
+}
+
+impl FileKind {
+    // the backend of types configured without one
+    fn default_backend(&self) -> MetadataBackend {
+        match *self {
+            FileKind::Raw | FileKind::Developed | FileKind::Sidecar => MetadataBackend::Exiv2,
+            FileKind::Video | FileKind::Audio => MetadataBackend::None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FileType {
+    pub kind: FileKind,
+    pub backend: MetadataBackend,
+}
+
+static BUILTIN_TYPES: &[(&[&str], FileKind, MetadataBackend)] = &[
+    (&["3fr", "arw", "cr2", "cr3", "crw", "dng", "erf", "iiq", "kdc", "mef", "mos", "mrw", "nef",
+       "nrw", "orf", "pef", "raf", "rw2", "rwl", "sr2", "srf", "srw", "x3f"],
+     FileKind::Raw, MetadataBackend::Exiv2),
+    (&["jpg", "jpeg", "png", "tif", "tiff", "heic", "heif", "webp"],
+     FileKind::Developed, MetadataBackend::Exiv2),
+    (&["xmp"], FileKind::Sidecar, MetadataBackend::Exiv2),
+    // RawTherapee, DxO, Capture One (see `sidecars`)
+    (&["pp3", "dop", "cos"], FileKind::Sidecar, MetadataBackend::None),
+    (&["mov", "mp4", "m4v", "3gp"], FileKind::Video, MetadataBackend::QuickTime),
+    (&["avi", "mts", "m2ts", "mkv"], FileKind::Video, MetadataBackend::None),
+    (&["m4a"], FileKind::Audio, MetadataBackend::QuickTime),
+    (&["wav", "mp3"], FileKind::Audio, MetadataBackend::None),
+];
+
+/// A file type as given in the configuration file.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FileTypeConfig {
+    pub kind: String,
+    pub metadata: Option<String>,
+}
+
+/// The known file types, by lowercase extension.
+#[derive(Debug, Clone)]
+pub struct FileTypes {
+    types: HashMap<String, FileType>,
+}
+
+impl Default for FileTypes {
+    fn default() -> FileTypes {
+        let mut types = HashMap::new();
+        for &(extensions, kind, backend) in BUILTIN_TYPES {
+            for e in extensions {
+                types.insert(e.to_string(), FileType { kind, backend });
+            }
+        }
+        FileTypes { types }
+    }
+}
+
+impl FileTypes {
+    /// The built-in types, extended (or changed) by `config`.
+    pub fn new(config: &HashMap<String, FileTypeConfig>) -> Result<FileTypes> {
+        let mut types = FileTypes::default();
+        for (extension, c) in config {
+            let kind: FileKind = c.kind.parse()?;
+            let backend = match c.metadata {
+                Some(ref b) => b.parse()?,
+                None => kind.default_backend(),
+            };
+            let extension = extension.trim_start_matches('.').to_lowercase();
+            types.types.insert(extension, FileType { kind, backend });
+        }
+        Ok(types)
+    }
+
+    /// The type of `path`, by its (last) extension; `None` for unknown types.
+    pub fn get(&self, path: &Path) -> Option<FileType> {
+        // a non utf-8 extension is no known extension either
+        let extension = path.extension()?.to_string_lossy().to_lowercase();
+        self.types.get(&extension).cloned()
+    }
+
+    pub fn is_known(&self, path: &Path) -> bool {
+        self.get(path).is_some()
+    }
+
+    pub fn backend(&self, path: &Path) -> Option<MetadataBackend> {
+        self.get(path).map(|t| t.backend)
+    }
+}
+
+#[test]
+fn test_builtin_types() {
+    let types = FileTypes::default();
+    let kinds: Vec<_> = ["a.CR2", "a.nef", "a.ARW", "a.dng", "a.heic", "a.cr2.xmp", "a.MOV", "a.mts", "a.wav"]
+        .iter()
+        .map(|f| types.get(Path::new(f)).map(|t| t.kind))
+        .collect();
+    assert_eq!(kinds, vec![Some(FileKind::Raw), Some(FileKind::Raw), Some(FileKind::Raw), Some(FileKind::Raw),
+                           Some(FileKind::Developed), Some(FileKind::Sidecar), Some(FileKind::Video),
+                           Some(FileKind::Video), Some(FileKind::Audio)]);
+    assert_eq!(types.backend(Path::new("a.mp4")), Some(MetadataBackend::QuickTime));
+    assert_eq!(types.backend(Path::new("a.mts")), Some(MetadataBackend::None));
+    assert!(!types.is_known(Path::new("a.txt")));
+    assert!(!types.is_known(Path::new("nef")));
+}
+
+#[test]
+fn test_configured_types() {
+    let config = |kind: &str, metadata: Option<&str>| FileTypeConfig {
+        kind: kind.into(),
+        metadata: metadata.map(String::from),
+    };
+    let mut c = HashMap::new();
+    c.insert("WEBM".to_string(), config("video", None));
+    c.insert(".mp4".to_string(), config("video", Some("none")));
+    let types = FileTypes::new(&c).unwrap();
+    assert_eq!(types.get(Path::new("a.webm")), Some(FileType { kind: FileKind::Video, backend: MetadataBackend::None }));
+    assert_eq!(types.backend(Path::new("a.mp4")), Some(MetadataBackend::None));
+    assert!(types.is_known(Path::new("a.nef")));
+
+    c.insert("x".to_string(), config("movie", None));
+    assert!(FileTypes::new(&c).is_err());
+}
@@ -0,0 +1,249 @@
+// Copyright 2017 Thorben Kroeger.
+// Dual-licensed MIT and Apache 2.0 (see LICENSE files for details).
+
+// Offline reverse geocoding: the place a photo was taken at is the nearest
+// city of a GeoNames gazetteer (e.g. `cities1000.txt` from
+// https://download.geonames.org/export/dump/), one tab separated line per
+// city:
+//
+//   geonameid, name, asciiname, alternatenames, latitude, longitude,
+//   feature class, feature code, country code, cc2, admin1 code, ...
+//
+// If `countryInfo.txt` and `admin1CodesASCII.txt` from the same dump are
+// next to the gazetteer, country and region names are taken from them,
+// otherwise their codes are used (e.g. `DE` and `16`).
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+use errors::*;
+use gps::Coordinates;
+
+static COUNTRY_INFO: &str = "countryInfo.txt";
+static ADMIN1_CODES: &str = "admin1CodesASCII.txt";
+
+pub const DEFAULT_MAX_DISTANCE_KM: f64 = 50.0;
+
+const EARTH_RADIUS_KM: f64 = 6371.0;
+const KM_PER_DEGREE: f64 = EARTH_RADIUS_KM * std::f64::consts::PI / 180.0;
+
+/// Where a photo was taken.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Place {
+    pub country: String,
+    // state, province etc.; empty if unknown
+    pub region: String,
+    pub city: String,
+}
+
+impl Place {
+    /// The hierarchical keyword for the place, e.g.
+    /// `places|Germany|Bavaria|Munich`.
+    pub fn keyword(&self) -> String {
+        let mut parts = vec!["places"];
+        for part in &[&self.country, &self.region, &self.city] {
+            // e.g. the city state Berlin is also a region
+            if !part.is_empty() && parts.last() != Some(&part.as_str()) {
+                parts.push(part);
+            }
+        }
+        parts.join("|")
+    }
+}
+
+#[derive(Debug, Clone)]
+struct City {
+    name: String,
+    latitude: f64,
+    longitude: f64,
+    country: String,
+    admin1: String,
+}
+
+// great-circle distance in km
+fn distance(lat1: f64, lon1: f64, lat2: f64, lon2: f64) -> f64 {
+    let (lat1, lat2) = (lat1.to_radians(), lat2.to_radians());
+    let dlat = lat2 - lat1;
+    let dlon = (lon2 - lon1).to_radians();
+    let a = (dlat / 2.0).sin().powi(2) + lat1.cos() * lat2.cos() * (dlon / 2.0).sin().powi(2);
+    2.0 * EARTH_RADIUS_KM * a.sqrt().min(1.0).asin()
+}
+
+// cells of 1° by 1°
+type Cell = (i32, i32);
+
+fn cell_of(latitude: f64, longitude: f64) -> Cell {
+    (latitude.floor() as i32, longitude.floor() as i32)
+}
+
+fn wrap_longitude(cell: i32) -> i32 {
+    (cell + 180).rem_euclid(360) - 180
+}
+
+/// The cities of a gazetteer, indexed by position.
+#[derive(Debug, Default)]
+pub struct Gazetteer {
+    cities: Vec<City>,
+    cells: HashMap<Cell, Vec<usize>>,
+    // `DE` => `Germany`
+    countries: HashMap<String, String>,
+    // `DE.16` => `Berlin`
+    regions: HashMap<String, String>,
+}
+
+// `code<TAB>name<TAB>...` lines, skipping comments
+fn parse_names(s: &str) -> HashMap<String, String> {
+    s.lines()
+        .filter(|l| !l.starts_with('#'))
+        .filter_map(|l| {
+            let mut fields = l.split('\t');
+            match (fields.next(), fields.next()) {
+                (Some(code), Some(name)) if !name.is_empty() => Some((code.to_string(), name.to_string())),
+                _ => None,
+            }
+        })
+        .collect()
+}
+
+// `ISO, ISO3, ISO-Numeric, fips, Country, ...`
+fn parse_country_info(s: &str) -> HashMap<String, String> {
+    s.lines()
+        .filter(|l| !l.starts_with('#'))
+        .filter_map(|l| {
+            let fields: Vec<_> = l.split('\t').collect();
+            match fields.get(4) {
+                Some(name) if !name.is_empty() => Some((fields[0].to_string(), name.to_string())),
+                _ => None,
+            }
+        })
+        .collect()
+}
+
+impl Gazetteer {
+    /// Parses the lines of a gazetteer.
+    pub fn parse(cities: &str) -> Result<Gazetteer> {
+        let mut gazetteer = Gazetteer::default();
+        for (i, line) in cities.lines().enumerate() {
+            if line.trim().is_empty() {
+                continue;
+            }
+            let fields: Vec<_> = line.split('\t').collect();
+            let coordinate = |i: usize| fields.get(i).and_then(|f| f.parse::<f64>().ok());
+            let city = match (fields.get(1), coordinate(4), coordinate(5), fields.get(8), fields.get(10)) {
+                (Some(name), Some(latitude), Some(longitude), Some(country), Some(admin1)) => City {
+                    name: name.to_string(),
+                    latitude,
+                    longitude,
+                    country: country.to_string(),
+                    admin1: admin1.to_string(),
+                },
+                _ => bail!("invalid gazetteer entry in line {}", i + 1),
+            };
+            gazetteer.cells.entry(cell_of(city.latitude, city.longitude))
+                .or_default()
+                .push(gazetteer.cities.len());
+            gazetteer.cities.push(city);
+        }
+        Ok(gazetteer)
+    }
+
+    /// Reads the gazetteer `path`, and the country and region names next
+    /// to it, if there are any.
+    pub fn read(path: &Path) -> Result<Gazetteer> {
+        let read = || -> Result<Gazetteer> {
+            let mut gazetteer = Gazetteer::parse(&fs::read_to_string(path)?)?;
+            let dir = path.parent().unwrap_or_else(|| Path::new(""));
+            if let Ok(s) = fs::read_to_string(dir.join(COUNTRY_INFO)) {
+                gazetteer.countries = parse_country_info(&s);
+            }
+            if let Ok(s) = fs::read_to_string(dir.join(ADMIN1_CODES)) {
+                gazetteer.regions = parse_names(&s);
+            }
+            Ok(gazetteer)
+        };
+        read().chain_err(|| format!("cannot read gazetteer '{}'", path.display()))
+    }
+
+    pub fn len(&self) -> usize {
+        self.cities.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.cities.is_empty()
+    }
+
+    fn place(&self, city: &City) -> Place {
+        let region_code = format!("{}.{}", city.country, city.admin1);
+        Place {
+            country: self.countries.get(&city.country).unwrap_or(&city.country).clone(),
+            region: self.regions.get(&region_code).unwrap_or(&city.admin1).clone(),
+            city: city.name.clone(),
+        }
+    }
+
+    /// The place of the city nearest to `position`, if there is one at
+    /// most `max_distance` km away.
+    pub fn lookup(&self, position: &Coordinates, max_distance: f64) -> Option<Place> {
+        let (lat, lon) = (position.latitude, position.longitude);
+        let (cell_lat, cell_lon) = cell_of(lat, lon);
+        // the cells within `max_distance`; they get narrower towards the poles
+        let lat_cells = (max_distance / KM_PER_DEGREE).ceil() as i32;
+        let cos = (lat.abs() + lat_cells as f64).min(90.0).to_radians().cos();
+        let lon_cells = (max_distance / (KM_PER_DEGREE * cos)).ceil().min(180.0) as i32;
+        let lon_range = if lon_cells >= 180 { -180..180 } else { -lon_cells..lon_cells + 1 };
+
+        let mut nearest: Option<(f64, &City)> = None;
+        for dlat in -lat_cells..lat_cells + 1 {
+            for dlon in lon_range.clone() {
+                let cell = (cell_lat + dlat, wrap_longitude(cell_lon + dlon));
+                for &i in self.cells.get(&cell).into_iter().flatten() {
+                    let city = &self.cities[i];
+                    let d = distance(lat, lon, city.latitude, city.longitude);
+                    if d <= max_distance && nearest.is_none_or(|(n, _)| d < n) {
+                        nearest = Some((d, city));
+                    }
+                }
+            }
+        }
+        nearest.map(|(_, city)| self.place(city))
+    }
+}
+
+#[cfg(test)]
+fn position(latitude: f64, longitude: f64) -> Coordinates {
+    Coordinates { latitude, longitude, altitude: None }
+}
+
+#[test]
+fn test_lookup() {
+    let cities = "\
+2950159\tBerlin\tBerlin\tBerlino\t52.52437\t13.41053\tP\tPPLC\tDE\t\t16\t00\t11000\t11000000\t3426354\t74\t43\tEurope/Berlin\t2019-09-05
+2867714\tMunich\tMunich\t\t48.13743\t11.57549\tP\tPPLA\tDE\t\t02\t091\t09162\t09162000\t1260391\t\t524\tEurope/Berlin\t2021-07-22
+2925533\tFrankfurt am Main\tFrankfurt am Main\t\t50.11552\t8.68417\tP\tPPLA2\tDE\t\t05\t064\t06412\t06412000\t650000\t\t100\tEurope/Berlin\t2019-09-05
+2193733\tAuckland\tAuckland\t\t-36.84853\t174.76349\tP\tPPLA\tNZ\t\tE7\t\t\t\t417910\t\t26\tPacific/Auckland\t2021-06-13
+4031574\tApia\tApia\t\t-13.83333\t-171.76666\tP\tPPLC\tWS\t\t11\t\t\t\t40407\t\t2\tPacific/Apia\t2012-01-17
+";
+    let mut g = Gazetteer::parse(cities).unwrap();
+    assert_eq!(g.len(), 5);
+
+    let munich = g.lookup(&position(48.2, 11.6), DEFAULT_MAX_DISTANCE_KM).unwrap();
+    assert_eq!(munich, Place { country: "DE".into(), region: "02".into(), city: "Munich".into() });
+    // Potsdam is closer to Berlin than to any other city
+    assert_eq!(g.lookup(&position(52.39, 13.06), DEFAULT_MAX_DISTANCE_KM).unwrap().city, "Berlin");
+    assert_eq!(g.lookup(&position(52.39, 13.06), 10.0), None);
+    // across the antimeridian, and into the neighboring cells
+    assert_eq!(g.lookup(&position(-14.0, 179.9), 1000.0).unwrap().city, "Apia");
+    assert_eq!(g.lookup(&position(-37.1, 175.0), DEFAULT_MAX_DISTANCE_KM).unwrap().city, "Auckland");
+    assert_eq!(g.lookup(&position(0.0, 0.0), DEFAULT_MAX_DISTANCE_KM), None);
+
+    g.countries = parse_country_info("#ISO\tISO3\tISO-Numeric\tfips\tCountry\n\
+                                      DE\tDEU\t276\tGM\tGermany\tBerlin\n");
+    g.regions = parse_names("DE.16\tBerlin\tBerlin\t2950157\nDE.02\tBavaria\tBavaria\t2951839\n");
+    let berlin = g.lookup(&position(52.5, 13.4), DEFAULT_MAX_DISTANCE_KM).unwrap();
+    assert_eq!(berlin.keyword(), "places|Germany|Berlin");
+    let munich = g.lookup(&position(48.2, 11.6), DEFAULT_MAX_DISTANCE_KM).unwrap();
+    assert_eq!(munich.keyword(), "places|Germany|Bavaria|Munich");
+
+    assert!(Gazetteer::parse("1\tBerlin\tBerlin\t\tx\t13.4\n").is_err());
+}
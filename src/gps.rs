@@ -0,0 +1,304 @@
+// Copyright 2017 Thorben Kroeger.
+// Dual-licensed MIT and Apache 2.0 (see LICENSE files for details).
+
+// Locations of photos, and geotagging with tracks recorded by a GPS
+// logger (GPX files).
+//
+// Coordinates are written to the XMP sidecars in the format of the EXIF
+// schema for XMP, e.g. `exif:GPSLatitude="52,31.123456N"`.
+
+use std::fs;
+use std::path::Path;
+
+use chrono::{DateTime, Duration, UTC};
+use regex::Regex;
+
+use capture::parse_iso_datetime;
+use errors::*;
+use metadata::{Metadata, TagEdit, TagValue};
+use photo::Photo;
+
+pub static XMP_EXIF_GPSLATITUDE: &str = "Xmp.exif.GPSLatitude";
+pub static XMP_EXIF_GPSLONGITUDE: &str = "Xmp.exif.GPSLongitude";
+pub static XMP_EXIF_GPSALTITUDE: &str = "Xmp.exif.GPSAltitude";
+pub static XMP_EXIF_GPSALTITUDEREF: &str = "Xmp.exif.GPSAltitudeRef";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Coordinates {
+    // degrees, north and east are positive
+    pub latitude: f64,
+    pub longitude: f64,
+    // meters above sea level
+    pub altitude: Option<f64>,
+}
+
+/// Parses a coordinate as written in XMP: `DDD,MM,SSk` or `DDD,MM.mmk`,
+/// where `k` is one of `NSEW`.
+pub fn parse_xmp_coordinate(s: &str) -> Option<f64> {
+    let s = s.trim();
+    let direction = s.chars().last()?;
+    let sign = match direction.to_ascii_uppercase() {
+        'N' | 'E' => 1.0,
+        'S' | 'W' => -1.0,
+        _ => return None,
+    };
+    let parts = s[..s.len() - 1].split(',')
+        .map(|p| p.trim().parse::<f64>().ok())
+        .collect::<Option<Vec<_>>>()?;
+    let degrees = match parts[..] {
+        [d, m] => d + m / 60.0,
+        [d, m, s] => d + m / 60.0 + s / 3600.0,
+        _ => return None,
+    };
+    Some(sign * degrees)
+}
+
+/// Formats a coordinate for XMP, e.g. `52,31.123456N`.
+pub fn format_xmp_coordinate(value: f64, positive: char, negative: char) -> String {
+    let direction = if value < 0.0 { negative } else { positive };
+    let value = value.abs();
+    let degrees = value.trunc();
+    format!("{},{:.6}{}", degrees, (value - degrees) * 60.0, direction)
+}
+
+// EXIF altitudes are rationals, e.g. `3450/100`
+fn parse_rational(s: &str) -> Option<f64> {
+    let mut parts = s.trim().splitn(2, '/');
+    let n: f64 = parts.next()?.parse().ok()?;
+    match parts.next() {
+        Some(d) => d.parse::<f64>().ok().filter(|&d| d != 0.0).map(|d| n / d),
+        None => Some(n),
+    }
+}
+
+/// Reads the coordinates from the XMP tags of a sidecar, looked up by `tag`.
+pub fn parse_xmp_coordinates<F>(path: &Path, tag: F) -> Result<Option<Coordinates>>
+where
+    F: Fn(&str) -> Option<String>,
+{
+    let coordinate = |name: &str| -> Result<Option<f64>> {
+        match tag(name) {
+            Some(value) => parse_xmp_coordinate(&value).map(Some).ok_or_else(|| {
+                ErrorKind::InvalidTag(path.to_path_buf(), name.to_string(), value).into()
+            }),
+            None => Ok(None),
+        }
+    };
+    let (latitude, longitude) = match (coordinate(XMP_EXIF_GPSLATITUDE)?, coordinate(XMP_EXIF_GPSLONGITUDE)?) {
+        (Some(latitude), Some(longitude)) => (latitude, longitude),
+        _ => return Ok(None),
+    };
+    let below_sea_level = tag(XMP_EXIF_GPSALTITUDEREF).is_some_and(|r| r.trim() == "1");
+    let altitude = tag(XMP_EXIF_GPSALTITUDE)
+        .and_then(|a| parse_rational(&a))
+        .map(|a| if below_sea_level { -a } else { a });
+    Ok(Some(Coordinates { latitude, longitude, altitude }))
+}
+
+fn xmp_values(c: &Coordinates) -> Vec<(&'static str, Option<String>)> {
+    vec![
+        (XMP_EXIF_GPSLATITUDE, Some(format_xmp_coordinate(c.latitude, 'N', 'S'))),
+        (XMP_EXIF_GPSLONGITUDE, Some(format_xmp_coordinate(c.longitude, 'E', 'W'))),
+        (XMP_EXIF_GPSALTITUDE, c.altitude.map(|a| format!("{}/100", (a.abs() * 100.0).round()))),
+        (XMP_EXIF_GPSALTITUDEREF, c.altitude.map(|a| if a < 0.0 { "1" } else { "0" }.to_string())),
+    ]
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TrackPoint {
+    pub time: DateTime<UTC>,
+    pub position: Coordinates,
+}
+
+/// The points of one or more GPS tracks, by time.
+#[derive(Debug, Clone, Default)]
+pub struct Track {
+    points: Vec<TrackPoint>,
+}
+
+lazy_static! {
+    static ref TRKPT: Regex =
+        Regex::new(r"(?s)<trkpt\b([^>]*?)(?:/>|>(.*?)</trkpt>)").unwrap();
+    static ref ATTRIBUTE: Regex =
+        Regex::new(r#"\b(lat|lon)\s*=\s*["']([^"']*)["']"#).unwrap();
+    static ref ELE: Regex = Regex::new(r"<ele>\s*([^<]*?)\s*</ele>").unwrap();
+    static ref TIME: Regex = Regex::new(r"<time>\s*([^<]*?)\s*</time>").unwrap();
+}
+
+/// Parses the track points of a GPX file. Points without time are skipped.
+pub fn parse_gpx(gpx: &str) -> Result<Vec<TrackPoint>> {
+    let mut points = vec![];
+    for trkpt in TRKPT.captures_iter(gpx) {
+        let (mut lat, mut lon) = (None, None);
+        for a in ATTRIBUTE.captures_iter(&trkpt[1]) {
+            let value = a[2].trim().parse::<f64>()
+                .map_err(|_| format!("invalid track point coordinate '{}'", &a[2]))?;
+            match &a[1] {
+                "lat" => lat = Some(value),
+                _ => lon = Some(value),
+            }
+        }
+        let (latitude, longitude) = match (lat, lon) {
+            (Some(lat), Some(lon)) => (lat, lon),
+            _ => bail!("track point without coordinates: {}", &trkpt[0]),
+        };
+        let content = trkpt.get(2).map_or("", |c| c.as_str());
+        let time = match TIME.captures(content) {
+            Some(t) => parse_iso_datetime(&t[1])
+                .and_then(|t| t.to_utc())
+                .ok_or_else(|| format!("invalid track point time '{}'", &t[1]))?,
+            None => continue,
+        };
+        let altitude = ELE.captures(content).and_then(|e| e[1].parse().ok());
+        points.push(TrackPoint { time, position: Coordinates { latitude, longitude, altitude } });
+    }
+    Ok(points)
+}
+
+impl Track {
+    pub fn new(mut points: Vec<TrackPoint>) -> Track {
+        points.sort_by_key(|p| p.time);
+        Track { points }
+    }
+
+    /// Reads and merges the tracks of `files`.
+    pub fn read(files: &[&Path]) -> Result<Track> {
+        let mut points = vec![];
+        for file in files {
+            let gpx = fs::read_to_string(file)?;
+            points.extend(parse_gpx(&gpx).chain_err(|| format!("cannot read GPX file '{}'", file.display()))?);
+        }
+        Ok(Track::new(points))
+    }
+
+    pub fn len(&self) -> usize {
+        self.points.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.points.is_empty()
+    }
+
+    /// The position at `time`: interpolated between the surrounding points
+    /// if they are at most `max_gap` apart, else the nearest point if it is
+    /// at most `max_gap` away.
+    pub fn locate(&self, time: DateTime<UTC>, max_gap: Duration) -> Option<Coordinates> {
+        let i = self.points.partition_point(|p| p.time < time);
+        let next = if i < self.points.len() { Some(i) } else { None };
+        let prev = match next {
+            Some(i) if self.points[i].time == time => return Some(self.points[i].position),
+            Some(0) => None,
+            Some(i) => Some(i - 1),
+            None => self.points.len().checked_sub(1),
+        };
+        let (prev, next) = (prev.map(|i| &self.points[i]), next.map(|i| &self.points[i]));
+
+        if let (Some(a), Some(b)) = (prev, next) {
+            if b.time.signed_duration_since(a.time) <= max_gap {
+                let span = b.time.signed_duration_since(a.time).num_milliseconds() as f64;
+                let f = time.signed_duration_since(a.time).num_milliseconds() as f64 / span;
+                let lerp = |x: f64, y: f64| x + (y - x) * f;
+                return Some(Coordinates {
+                    latitude: lerp(a.position.latitude, b.position.latitude),
+                    longitude: lerp(a.position.longitude, b.position.longitude),
+                    altitude: match (a.position.altitude, b.position.altitude) {
+                        (Some(x), Some(y)) => Some(lerp(x, y)),
+                        _ => None,
+                    },
+                });
+            }
+        }
+        let distance = |p: &TrackPoint| (p.time.signed_duration_since(time)).num_milliseconds().abs();
+        prev.into_iter().chain(next)
+            .filter(|p| distance(p) <= max_gap.num_milliseconds())
+            .min_by_key(|p| distance(p))
+            .map(|p| p.position)
+    }
+}
+
+/// The position of `photo`, from its sidecars or else from the photo itself.
+pub fn photo_position(photo: &Photo) -> Result<Option<Coordinates>> {
+    for file in photo.sidecars() {
+        if let Some(position) = Metadata::new(file)?.gps()? {
+            return Ok(Some(position));
+        }
+    }
+    // e.g. videos, which exiv2 cannot read
+    match Metadata::new(&photo.source) {
+        Ok(meta) => meta.gps(),
+        Err(_) => Ok(None),
+    }
+}
+
+/// The edits which write `position` to all sidecars of `photo`. Sidecars
+/// which already have coordinates are left alone, unless `overwrite`.
+pub fn plan_geotag_edits(photo: &Photo, position: &Coordinates, overwrite: bool) -> Result<Vec<TagEdit>> {
+    let mut edits = vec![];
+    for file in photo.editable_files()? {
+        let meta = Metadata::new(&file)?;
+        if !overwrite && meta.gps()?.is_some() {
+            continue;
+        }
+        for (tag, new) in xmp_values(position) {
+            let old = meta.tag_string(tag);
+            if old != new {
+                edits.push(TagEdit {
+                    file: file.clone(),
+                    tag: tag.to_string(),
+                    old: old.map(TagValue::Text),
+                    new: new.map(TagValue::Text),
+                });
+            }
+        }
+    }
+    Ok(edits)
+}
+
+#[test]
+fn test_xmp_coordinates() {
+    use std::collections::HashMap;
+
+    assert_eq!(parse_xmp_coordinate("52,30.6N"), Some(52.51));
+    assert_eq!(parse_xmp_coordinate("13,24,36W"), Some(-13.41));
+    assert_eq!(parse_xmp_coordinate("52.51"), None);
+    assert_eq!(format_xmp_coordinate(-13.41, 'E', 'W'), "13,24.600000W");
+
+    let c = Coordinates { latitude: 52.51, longitude: -13.41, altitude: Some(-3.5) };
+    let tags: HashMap<_, _> = xmp_values(&c).into_iter().filter_map(|(t, v)| v.map(|v| (t, v))).collect();
+    let a = parse_xmp_coordinates(Path::new("a.xmp"), |t| tags.get(t).cloned()).unwrap().unwrap();
+    assert!((a.latitude - c.latitude).abs() < 1e-9 && (a.longitude - c.longitude).abs() < 1e-9);
+    assert_eq!(a.altitude, Some(-3.5));
+    assert!(parse_xmp_coordinates(Path::new("a.xmp"), |_| Some("x".into())).is_err());
+}
+
+#[test]
+fn test_track() {
+    use chrono::TimeZone;
+
+    let gpx = r#"<?xml version="1.0"?>
+        <gpx version="1.1"><trk><trkseg>
+          <trkpt lat="52.0" lon="13.0"><ele>30</ele><time>2017-05-12T10:00:00Z</time></trkpt>
+          <trkpt lon='13.1' lat='52.1'><ele>40</ele><time>2017-05-12T10:01:00Z</time></trkpt>
+          <trkpt lat="52.2" lon="13.2"/>
+          <trkpt lat="53.0" lon="14.0">
+            <time>2017-05-12T12:30:00+02:00</time>
+          </trkpt>
+        </trkseg></trk></gpx>"#;
+    let track = Track::new(parse_gpx(gpx).unwrap());
+    assert_eq!(track.len(), 3);
+    assert_eq!(track.points[2].position.latitude, 53.0);
+
+    let at = |h, m, s| UTC.ymd(2017, 5, 12).and_hms(h, m, s);
+    let gap = Duration::minutes(5);
+    let c = track.locate(at(10, 0, 30), gap).unwrap();
+    assert!((c.latitude - 52.05).abs() < 1e-9 && (c.longitude - 13.05).abs() < 1e-9);
+    assert_eq!(c.altitude, Some(35.0));
+    assert_eq!(track.locate(at(10, 1, 0), gap).unwrap().latitude, 52.1);
+    // the points around 10:15 are too far apart, the nearest one too far away
+    assert_eq!(track.locate(at(10, 15, 0), gap), None);
+    assert_eq!(track.locate(at(10, 5, 0), gap).unwrap().latitude, 52.1);
+    assert_eq!(track.locate(at(10, 27, 0), gap).unwrap().latitude, 53.0);
+    assert_eq!(track.locate(at(9, 56, 0), gap).unwrap().latitude, 52.0);
+    assert_eq!(track.locate(at(9, 54, 0), gap), None);
+    assert_eq!(track.locate(at(10, 0, 0), Duration::zero()).unwrap().latitude, 52.0);
+}
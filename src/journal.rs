@@ -0,0 +1,440 @@
+// Copyright 2017 Thorben Kroeger.
+// Dual-licensed MIT and Apache 2.0 (see LICENSE files for details).
+
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+#[cfg(test)]
+use checksum::{HashAlgorithm, hash_file};
+#[cfg(test)]
+use fileops::make_test_dir;
+#[cfg(test)]
+use std::time::{Duration, UNIX_EPOCH};
+
+use serde_json;
+
+use checksum::{Checksum, verify_file};
+use errors::*;
+use fileops::{move_file, MoveOutcome};
+use metadata::{clear_derivedfrom, read_derivedfrom, write_derivedfrom, write_tags, TagValue};
+use sidecars::replace_reference;
+
+/// Size and modification time of a file, which tell changes to it when
+/// no checksum was recorded.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FileStamp {
+    pub size: u64,
+    pub modified: SystemTime,
+}
+
+impl FileStamp {
+    pub fn of(path: &Path) -> Result<FileStamp> {
+        let meta = fs::metadata(path)?;
+        Ok(FileStamp { size: meta.len(), modified: meta.modified()? })
+    }
+}
+
+// A single, successfully executed operation.
+// Entries carry everything needed to revert them. `stamp` is the state of
+// the written file afterwards, so that a copy which was changed since is
+// not removed by `undo`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum JournalEntry {
+    CreateDirectory { dir: PathBuf },
+    // `checksum` is the hash of the transferred content, if requested
+    Copy {
+        src: PathBuf,
+        dest: PathBuf,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        checksum: Option<Checksum>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        stamp: Option<FileStamp>,
+    },
+    Move {
+        src: PathBuf,
+        dest: PathBuf,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        checksum: Option<Checksum>,
+    },
+    // `old` is the `DerivedFrom` value before the adjustment (if any);
+    // `checksum` is the hash of the file afterwards, if requested
+    AdjustRef {
+        file: PathBuf,
+        old: Option<String>,
+        new: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        checksum: Option<Checksum>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        stamp: Option<FileStamp>,
+    },
+    // the file name `old` was replaced by `new` in a sidecar of another raw developer
+    ReplaceRef {
+        file: PathBuf,
+        old: String,
+        new: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        checksum: Option<Checksum>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        stamp: Option<FileStamp>,
+    },
+    // a metadata tag was set (or, for `None`, removed)
+    SetTag { file: PathBuf, tag: String, old: Option<TagValue>, new: Option<TagValue> },
+}
+
+// Append-only journal, one JSON object per line.
+// Every entry is synced to disk before the next operation is executed,
+// so a run which is aborted halfway can still be undone.
+pub struct Journal {
+    path: PathBuf,
+    file: fs::File,
+}
+
+impl Journal {
+    pub fn create(path: &Path) -> Result<Journal> {
+        let file = fs::OpenOptions::new().write(true).create_new(true).open(path)?;
+        Ok(Journal {
+            path: path.to_path_buf(),
+            file,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn record(&mut self, entry: &JournalEntry) -> Result<()> {
+        let line = serde_json::to_string(entry)?;
+        writeln!(self.file, "{}", line)?;
+        self.file.sync_data()?;
+        Ok(())
+    }
+}
+
+pub fn read_journal(path: &Path) -> Result<Vec<JournalEntry>> {
+    let reader = BufReader::new(fs::File::open(path)?);
+    let mut entries = vec![];
+    for line in reader.lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        entries.push(serde_json::from_str(&line)?);
+    }
+    Ok(entries)
+}
+
+// Entries which are reverted already (e.g. by an undo which was aborted
+// halfway) are skipped, so that an undo can simply be run again.
+fn undo_entry(entry: &JournalEntry) -> Result<Option<(PathBuf, io::Error)>> {
+    match *entry {
+        JournalEntry::CreateDirectory { ref dir } => {
+            // a directory which is not empty (anymore) is left, e.g. with
+            // a copy which was changed
+            if dir.exists() && fs::read_dir(dir)?.next().is_none() {
+                fs::remove_dir(dir)?;
+            }
+        }
+        JournalEntry::Copy { ref dest, .. } => {
+            if dest.exists() {
+                fs::remove_file(dest)?;
+            }
+        }
+        JournalEntry::Move { ref src, ref dest, ref checksum } => {
+            if dest.exists() || !src.exists() {
+                let algorithm = checksum.as_ref().map(|c| c.algorithm);
+                if let (MoveOutcome::SourceNotRemoved(e), _) = move_file(dest, src, algorithm)? {
+                    return Ok(Some((dest.clone(), e)));
+                }
+            }
+        }
+        // a missing file was moved back or removed already
+        JournalEntry::AdjustRef { ref file, ref old, .. } => {
+            if file.exists() && read_derivedfrom(file)? != *old {
+                match *old {
+                    Some(ref old) => write_derivedfrom(file, old)?,
+                    None => clear_derivedfrom(file)?,
+                }
+            }
+        }
+        JournalEntry::ReplaceRef { ref file, ref old, ref new, .. } => {
+            // without references to `new`, there is nothing to do
+            if file.exists() {
+                replace_reference(file, new, old)?;
+            }
+        }
+        JournalEntry::SetTag { ref file, ref tag, ref old, .. } => {
+            write_tags(file, &[(tag, old.as_ref())])?;
+        }
+    }
+    Ok(None)
+}
+
+// the file written by `entry`, and its checksum and stamp afterwards
+fn recorded_state(entry: &JournalEntry) -> Option<(&PathBuf, Option<&Checksum>, Option<&FileStamp>)> {
+    match *entry {
+        JournalEntry::Copy { ref dest, ref checksum, ref stamp, .. } => Some((dest, checksum.as_ref(), stamp.as_ref())),
+        JournalEntry::Move { ref dest, ref checksum, .. } => Some((dest, checksum.as_ref(), None)),
+        JournalEntry::AdjustRef { ref file, ref checksum, ref stamp, .. } |
+        JournalEntry::ReplaceRef { ref file, ref checksum, ref stamp, .. } => {
+            Some((file, checksum.as_ref(), stamp.as_ref()))
+        }
+        _ => None,
+    }
+}
+
+// whether `file` is in the recorded state; without a record, it is
+// assumed to be
+fn is_unchanged(file: &Path, checksum: Option<&Checksum>, stamp: Option<&FileStamp>) -> Result<bool> {
+    match (checksum, stamp) {
+        (Some(checksum), _) => verify_file(file, checksum),
+        (None, Some(stamp)) => Ok(FileStamp::of(file)? == *stamp),
+        (None, None) => Ok(true),
+    }
+}
+
+/// Reverts `entries` in reverse order. Returns the files which were moved
+/// back by copying, but could not be removed, and the copies which were
+/// changed since the run, which are not removed.
+pub fn undo(entries: &[JournalEntry]) -> Result<Vec<(PathBuf, io::Error)>> {
+    // the state of the copies after the run; their references are not
+    // reverted, as they are removed anyway
+    let mut copies = HashMap::<&Path, (Option<&Checksum>, Option<&FileStamp>)>::new();
+    for entry in entries {
+        if let JournalEntry::Copy { ref dest, .. } = *entry {
+            copies.insert(dest, (None, None));
+        }
+        if let Some((file, checksum, stamp)) = recorded_state(entry) {
+            if let Some(state) = copies.get_mut(file.as_path()) {
+                *state = (checksum, stamp);
+            }
+        }
+    }
+
+    let mut not_removed = vec![];
+    for entry in entries.iter().rev() {
+        match *entry {
+            JournalEntry::Copy { ref dest, .. } if dest.exists() => {
+                let (checksum, stamp) = copies[dest.as_path()];
+                if !is_unchanged(dest, checksum, stamp)? {
+                    let e = io::Error::other("changed since it was copied");
+                    not_removed.push((dest.clone(), e));
+                    continue;
+                }
+            }
+            JournalEntry::AdjustRef { ref file, .. } |
+            JournalEntry::ReplaceRef { ref file, .. } if copies.contains_key(file.as_path()) => continue,
+            _ => {}
+        }
+        if let Some(e) = undo_entry(entry)? {
+            not_removed.push(e);
+        }
+    }
+    Ok(not_removed)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Verification {
+    Intact,
+    Modified,
+    Missing,
+}
+
+// the files whose checksum was recorded in `entries`, each with the last
+// one recorded for it, i.e. its content after the run
+fn last_checksums(entries: &[JournalEntry]) -> Vec<(&PathBuf, &Checksum)> {
+    let mut checksums: Vec<(&PathBuf, &Checksum)> = vec![];
+    for entry in entries {
+        let (file, checksum) = match recorded_state(entry) {
+            Some((file, Some(checksum), _)) => (file, checksum),
+            _ => continue,
+        };
+        match checksums.iter_mut().find(|&&mut (f, _)| f == file) {
+            Some(last) => last.1 = checksum,
+            None => checksums.push((file, checksum)),
+        }
+    }
+    checksums
+}
+
+/// Checks the transferred files recorded in `entries` against the last
+/// checksum recorded for them (references in sidecars and derived files
+/// are adjusted after the transfer). Entries without a checksum are skipped.
+pub fn verify(entries: &[JournalEntry]) -> Result<Vec<(PathBuf, Verification)>> {
+    let mut result = vec![];
+    for (dest, checksum) in last_checksums(entries) {
+        let verification = if !dest.exists() {
+            Verification::Missing
+        } else if verify_file(dest, checksum)? {
+            Verification::Intact
+        } else {
+            Verification::Modified
+        };
+        result.push((dest.clone(), verification));
+    }
+    Ok(result)
+}
+
+#[test]
+fn test_journal_roundtrip() {
+    let dir = make_test_dir("journal-roundtrip");
+    let path = dir.join("journal.jsonl");
+    let entries = vec![
+        JournalEntry::CreateDirectory { dir: PathBuf::from("/out/2017-05-12") },
+        JournalEntry::Move {
+            src: PathBuf::from("/in/IMG_1.CR2"),
+            dest: PathBuf::from("/out/2017-05-12/0000_2017-05-12.cr2"),
+            checksum: Some(Checksum {
+                algorithm: HashAlgorithm::Blake3,
+                digest: "6437b3ac38465133ffb63b75273a8db548c558465d79db03fd359c6cd5bd9d85".into(),
+            }),
+        },
+        JournalEntry::Copy {
+            src: PathBuf::from("/in/IMG_1.JPG"),
+            dest: PathBuf::from("/out/2017-05-12/0000_2017-05-12.jpg"),
+            checksum: None,
+            stamp: Some(FileStamp { size: 1234, modified: UNIX_EPOCH + Duration::new(1494613800, 5) }),
+        },
+        JournalEntry::AdjustRef {
+            file: PathBuf::from("/out/2017-05-12/0000_2017-05-12.cr2.xmp"),
+            old: Some("IMG_1.CR2".into()),
+            new: "0000_2017-05-12.cr2".into(),
+            checksum: None,
+            stamp: None,
+        },
+        JournalEntry::ReplaceRef {
+            file: PathBuf::from("/out/2017-05-12/0000_2017-05-12.cr2.pp3"),
+            old: "IMG_1.CR2".into(),
+            new: "0000_2017-05-12.cr2".into(),
+            checksum: Some(Checksum { algorithm: HashAlgorithm::Sha256, digest: "00ff".into() }),
+            stamp: None,
+        },
+        JournalEntry::SetTag {
+            file: PathBuf::from("/out/2017-05-12/0000_2017-05-12.cr2"),
+            tag: "Exif.Photo.DateTimeOriginal".into(),
+            old: Some(TagValue::Text("2017:05:12 20:30:00".into())),
+            new: Some(TagValue::Text("2017:05:12 18:30:00".into())),
+        },
+    ];
+
+    let mut journal = Journal::create(&path).unwrap();
+    for entry in &entries {
+        journal.record(entry).unwrap();
+    }
+
+    assert_eq!(read_journal(&path).unwrap(), entries);
+}
+
+#[test]
+fn test_undo() {
+    let dir = make_test_dir("journal-undo");
+    let out = dir.join("out");
+    fs::create_dir(&out).unwrap();
+    fs::write(dir.join("a.cr2"), b"a").unwrap();
+    fs::write(dir.join("b.jpg"), b"b").unwrap();
+    fs::rename(dir.join("a.cr2"), out.join("0000.cr2")).unwrap();
+    fs::copy(dir.join("b.jpg"), out.join("0000.jpg")).unwrap();
+
+    let entries = vec![
+        JournalEntry::CreateDirectory { dir: out.clone() },
+        JournalEntry::Move { src: dir.join("a.cr2"), dest: out.join("0000.cr2"), checksum: None },
+        JournalEntry::Copy { src: dir.join("b.jpg"), dest: out.join("0000.jpg"), checksum: None, stamp: None },
+    ];
+    let not_removed = undo(&entries).unwrap();
+
+    assert!(not_removed.is_empty());
+    assert!(!out.exists());
+    assert_eq!(fs::read(dir.join("a.cr2")).unwrap(), b"a");
+    assert_eq!(fs::read(dir.join("b.jpg")).unwrap(), b"b");
+
+    // again, e.g. after an undo which was aborted halfway
+    assert!(undo(&entries).unwrap().is_empty());
+    fs::create_dir(&out).unwrap();
+    fs::rename(dir.join("a.cr2"), out.join("0000.cr2")).unwrap();
+    assert!(undo(&entries).unwrap().is_empty());
+    assert!(!out.exists());
+    assert_eq!(fs::read(dir.join("a.cr2")).unwrap(), b"a");
+}
+
+#[test]
+fn test_verify() {
+    let dir = make_test_dir("journal-verify");
+    for name in &["a.cr2", "b.cr2", "c.cr2", "a.cr2.pp3"] {
+        fs::write(dir.join(name), b"raw data").unwrap();
+    }
+    let checksum = hash_file(&dir.join("a.cr2"), HashAlgorithm::Blake3).unwrap();
+    fs::write(dir.join("a.cr2.pp3"), b"File=a.cr2").unwrap();
+    let adjusted = hash_file(&dir.join("a.cr2.pp3"), HashAlgorithm::Blake3).unwrap();
+    let entries = vec![
+        JournalEntry::Copy { src: dir.join("x"), dest: dir.join("a.cr2"), checksum: Some(checksum.clone()), stamp: None },
+        JournalEntry::Move { src: dir.join("x"), dest: dir.join("b.cr2"), checksum: Some(checksum.clone()) },
+        JournalEntry::Move { src: dir.join("x"), dest: dir.join("c.cr2"), checksum: None },
+        JournalEntry::Copy { src: dir.join("x"), dest: dir.join("d.cr2"), checksum: Some(checksum.clone()), stamp: None },
+        // changed by the run itself after the transfer
+        JournalEntry::Copy { src: dir.join("x.pp3"), dest: dir.join("a.cr2.pp3"), checksum: Some(checksum), stamp: None },
+        JournalEntry::ReplaceRef {
+            file: dir.join("a.cr2.pp3"),
+            old: "x".into(),
+            new: "a.cr2".into(),
+            checksum: Some(adjusted),
+            stamp: None,
+        },
+    ];
+    fs::write(dir.join("b.cr2"), b"raw dat4").unwrap();
+
+    let e = vec![
+        (dir.join("a.cr2"), Verification::Intact),
+        (dir.join("b.cr2"), Verification::Modified),
+        (dir.join("d.cr2"), Verification::Missing),
+        (dir.join("a.cr2.pp3"), Verification::Intact),
+    ];
+    assert_eq!(verify(&entries).unwrap(), e);
+}
+
+#[test]
+fn test_undo_keeps_changed_copies() {
+    let dir = make_test_dir("journal-undo-changed");
+    let out = dir.join("out");
+    fs::create_dir(&out).unwrap();
+    for name in &["a.cr2", "b.cr2", "c.cr2"] {
+        fs::write(dir.join(name), b"raw data").unwrap();
+        fs::copy(dir.join(name), out.join(name)).unwrap();
+    }
+    fs::write(out.join("a.cr2.xmp"), b"a.cr2").unwrap();
+    let copy = |name: &str, checksum| JournalEntry::Copy {
+        src: dir.join(name),
+        dest: out.join(name),
+        checksum,
+        stamp: Some(FileStamp::of(&out.join(name)).unwrap()),
+    };
+    let entries = vec![
+        JournalEntry::CreateDirectory { dir: out.clone() },
+        copy("a.cr2", Some(hash_file(&out.join("a.cr2"), HashAlgorithm::Blake3).unwrap())),
+        copy("b.cr2", None),
+        copy("c.cr2", None),
+        JournalEntry::Copy { src: dir.join("a.cr2.xmp"), dest: out.join("a.cr2.xmp"), checksum: None, stamp: None },
+        JournalEntry::AdjustRef {
+            file: out.join("a.cr2.xmp"),
+            old: Some("IMG_1.CR2".into()),
+            new: "a.cr2".into(),
+            checksum: None,
+            stamp: Some(FileStamp::of(&out.join("a.cr2.xmp")).unwrap()),
+        },
+    ];
+    // edited in the library after the run
+    fs::write(out.join("a.cr2"), b"raw dat4").unwrap();
+    fs::write(out.join("b.cr2"), b"raw data, edited").unwrap();
+
+    let not_removed = undo(&entries).unwrap();
+
+    let kept: Vec<_> = not_removed.iter().map(|(path, _)| path.clone()).collect();
+    assert_eq!(kept, vec![out.join("b.cr2"), out.join("a.cr2")]);
+    assert!(out.join("a.cr2").exists());
+    assert!(out.join("b.cr2").exists());
+    assert!(!out.join("c.cr2").exists());
+    assert!(!out.join("a.cr2.xmp").exists());
+    assert!(dir.join("c.cr2").exists());
+}
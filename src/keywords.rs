@@ -0,0 +1,210 @@
+// Copyright 2017 Thorben Kroeger.
+// Dual-licensed MIT and Apache 2.0 (see LICENSE files for details).
+
+// Keywords (tags) as written by darktable and most DAMs: flat ones in
+// `dc:subject` (e.g. `berlin`), hierarchical ones in `lr:hierarchicalSubject`
+// (e.g. `places|germany|berlin`). The flat keywords include the last part
+// of every hierarchical one, and are kept in sync with them.
+
+use errors::*;
+use metadata::{Metadata, TagEdit, TagValue, XMP_DC_SUBJECT, XMP_LR_HIERARCHICALSUBJECT};
+use photo::Photo;
+
+const SEPARATOR: char = '|';
+
+// `berlin` for `places|germany|berlin`
+fn leaf(keyword: &str) -> &str {
+    keyword.rsplit(SEPARATOR).next().unwrap_or(keyword)
+}
+
+// whether `keyword` is `parent` or below it
+fn is_below(keyword: &str, parent: &str) -> bool {
+    keyword == parent
+        || keyword.starts_with(parent) && keyword[parent.len()..].starts_with(SEPARATOR)
+}
+
+fn push_unique(list: &mut Vec<String>, s: &str) {
+    if !list.iter().any(|e| e == s) {
+        list.push(s.to_string());
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Keywords {
+    pub flat: Vec<String>,
+    pub hierarchical: Vec<String>,
+}
+
+impl Keywords {
+    // maps every hierarchical keyword to its new value (`None` to remove
+    // it), and updates the flat keywords accordingly
+    fn map_hierarchical<F>(&mut self, f: F)
+    where
+        F: Fn(&str) -> Option<String>,
+    {
+        let old_leaves: Vec<String> = self.hierarchical.iter().map(|k| leaf(k).to_string()).collect();
+        let mut hierarchical = vec![];
+        for k in self.hierarchical.iter().filter_map(|k| f(k)) {
+            push_unique(&mut hierarchical, &k);
+        }
+        let new_leaves: Vec<&str> = hierarchical.iter().map(|k| leaf(k)).collect();
+        self.flat.retain(|k| !old_leaves.contains(k) || new_leaves.contains(&k.as_str()));
+        for l in new_leaves {
+            push_unique(&mut self.flat, l);
+        }
+        self.hierarchical = hierarchical;
+    }
+
+    pub fn add(&mut self, keyword: &str) {
+        push_unique(&mut self.hierarchical, keyword);
+        push_unique(&mut self.flat, leaf(keyword));
+    }
+
+    /// Removes `keyword` and all keywords below it. A flat keyword
+    /// (`berlin`) also removes the hierarchical ones ending in it.
+    pub fn remove(&mut self, keyword: &str) {
+        let flat = !keyword.contains(SEPARATOR);
+        self.map_hierarchical(|k| {
+            if is_below(k, keyword) || flat && leaf(k) == keyword {
+                None
+            } else {
+                Some(k.to_string())
+            }
+        });
+        self.flat.retain(|k| k != keyword);
+    }
+
+    /// Renames `old` and moves all keywords below it. A flat keyword
+    /// (`berlin`) also renames the last part of the hierarchical ones
+    /// ending in it, to the last part of `new`.
+    pub fn rename(&mut self, old: &str, new: &str) {
+        let flat = !old.contains(SEPARATOR);
+        self.map_hierarchical(|k| {
+            if is_below(k, old) {
+                Some(format!("{}{}", new, &k[old.len()..]))
+            } else if flat && leaf(k) == old {
+                Some(format!("{}{}", &k[..k.len() - old.len()], leaf(new)))
+            } else {
+                Some(k.to_string())
+            }
+        });
+        if self.flat.iter().any(|k| k == old) {
+            self.flat.retain(|k| k != old);
+            push_unique(&mut self.flat, leaf(new));
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum KeywordChange {
+    Add(String),
+    Remove(String),
+    Rename(String, String),
+}
+
+impl KeywordChange {
+    /// Parses `OLD=NEW`.
+    pub fn rename(s: &str) -> Result<KeywordChange> {
+        let mut parts = s.splitn(2, '=');
+        match (parts.next(), parts.next()) {
+            (Some(old), Some(new)) if !old.is_empty() && !new.is_empty() => {
+                Ok(KeywordChange::Rename(old.to_string(), new.to_string()))
+            }
+            _ => bail!("invalid rename '{}', expected OLD=NEW", s),
+        }
+    }
+
+    pub fn apply(&self, keywords: &mut Keywords) {
+        match *self {
+            KeywordChange::Add(ref k) => keywords.add(k),
+            KeywordChange::Remove(ref k) => keywords.remove(k),
+            KeywordChange::Rename(ref old, ref new) => keywords.rename(old, new),
+        }
+    }
+}
+
+fn list_value(list: &[String]) -> Option<TagValue> {
+    if list.is_empty() {
+        None
+    } else {
+        Some(TagValue::List(list.to_vec()))
+    }
+}
+
+/// The edits which apply `changes` to all sidecars of `photo`.
+pub fn plan_keyword_edits(photo: &Photo, changes: &[KeywordChange]) -> Result<Vec<TagEdit>> {
+    let mut edits = vec![];
+    for file in photo.editable_files()? {
+        let old = Metadata::new(&file)?.keywords();
+        let mut new = old.clone();
+        for change in changes {
+            change.apply(&mut new);
+        }
+        for &(tag, ref old, ref new) in &[(XMP_DC_SUBJECT, &old.flat, &new.flat),
+                                           (XMP_LR_HIERARCHICALSUBJECT, &old.hierarchical, &new.hierarchical)] {
+            if old != new {
+                edits.push(TagEdit {
+                    file: file.clone(),
+                    tag: tag.to_string(),
+                    old: list_value(old),
+                    new: list_value(new),
+                });
+            }
+        }
+    }
+    Ok(edits)
+}
+
+#[cfg(test)]
+fn keywords(flat: &[&str], hierarchical: &[&str]) -> Keywords {
+    Keywords {
+        flat: flat.iter().map(|s| s.to_string()).collect(),
+        hierarchical: hierarchical.iter().map(|s| s.to_string()).collect(),
+    }
+}
+
+#[test]
+fn test_keywords() {
+    let mut k = keywords(&["portrait"], &[]);
+    k.add("places|germany|berlin");
+    k.add("places|germany|hamburg");
+    k.add("portrait");
+    assert_eq!(k, keywords(&["portrait", "berlin", "hamburg"],
+                           &["places|germany|berlin", "places|germany|hamburg", "portrait"]));
+
+    k.rename("places|germany", "places|deutschland");
+    assert_eq!(k.hierarchical, vec!["places|deutschland|berlin", "places|deutschland|hamburg", "portrait"]);
+    k.rename("portrait", "people|portrait");
+    assert_eq!(k.flat, vec!["berlin", "hamburg", "portrait"]);
+
+    k.remove("berlin");
+    assert_eq!(k, keywords(&["hamburg", "portrait"], &["places|deutschland|hamburg", "people|portrait"]));
+    k.remove("places");
+    assert_eq!(k, keywords(&["portrait"], &["people|portrait"]));
+    // not below `people|port`
+    k.remove("people|port");
+    assert_eq!(k, keywords(&["portrait"], &["people|portrait"]));
+}
+
+#[test]
+fn test_rename_flat() {
+    let mut k = keywords(&["berlin", "portrait", "zoo"], &["places|germany|berlin", "portrait", "berlin|zoo"]);
+    k.rename("berlin", "berlin-mitte");
+    assert_eq!(k, keywords(&["portrait", "zoo", "berlin-mitte"],
+                           &["places|germany|berlin-mitte", "portrait", "berlin-mitte|zoo"]));
+    k.rename("portrait", "people|portraits");
+    assert_eq!(k, keywords(&["zoo", "berlin-mitte", "portraits"],
+                           &["places|germany|berlin-mitte", "people|portraits", "berlin-mitte|zoo"]));
+
+    // only flat
+    let mut k = keywords(&["berlin"], &[]);
+    k.rename("berlin", "places|berlin");
+    assert_eq!(k, keywords(&["berlin"], &[]));
+}
+
+#[test]
+fn test_keyword_change() {
+    assert_eq!(KeywordChange::rename("a|b=a|c").unwrap(), KeywordChange::Rename("a|b".into(), "a|c".into()));
+    assert!(KeywordChange::rename("a").is_err());
+    assert!(KeywordChange::rename("=a").is_err());
+}
@@ -0,0 +1,165 @@
+// Copyright 2017 Thorben Kroeger.
+// Dual-licensed MIT and Apache 2.0 (see LICENSE files for details).
+
+// Changing ratings (`xmp:Rating`) and darktable color labels
+// (`darktable:colorlabels`) of photos in bulk.
+
+use std::str::FromStr;
+
+use errors::*;
+use metadata::{DarktableColor, Metadata, TagEdit, TagValue, XMP_DARKTABLE_COLORLABELS, XMP_XMP_RATING};
+use photo::Photo;
+
+/// A change of the rating (-1 means rejected).
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RatingChange {
+    Set(i32),
+    // set, or reset to 0 if already set
+    Toggle(i32),
+    Clear,
+}
+
+impl FromStr for RatingChange {
+    type Err = Error;
+
+    // `0` to `5`, `reject` or `clear`
+    fn from_str(s: &str) -> Result<RatingChange> {
+        match s {
+            "reject" => Ok(RatingChange::Set(-1)),
+            "clear" => Ok(RatingChange::Clear),
+            _ => match s.parse::<i32>() {
+                Ok(rating) if (0..=5).contains(&rating) => Ok(RatingChange::Set(rating)),
+                _ => bail!("invalid rating '{}' (expected 0 to 5, reject or clear)", s),
+            },
+        }
+    }
+}
+
+impl RatingChange {
+    pub fn apply(&self, rating: Option<i32>) -> Option<i32> {
+        match *self {
+            RatingChange::Set(r) => Some(r),
+            RatingChange::Toggle(r) if rating == Some(r) => Some(0),
+            RatingChange::Toggle(r) => Some(r),
+            RatingChange::Clear => None,
+        }
+    }
+}
+
+/// A change of the color labels.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LabelChange {
+    Add(DarktableColor),
+    Remove(DarktableColor),
+    Toggle(DarktableColor),
+    Clear,
+}
+
+impl FromStr for LabelChange {
+    type Err = Error;
+
+    // `+red`, `-red`, `red` (toggle) or `clear`
+    fn from_str(s: &str) -> Result<LabelChange> {
+        if s == "clear" {
+            return Ok(LabelChange::Clear);
+        }
+        Ok(match s.chars().next() {
+            Some('+') => LabelChange::Add(s[1..].parse()?),
+            Some('-') => LabelChange::Remove(s[1..].parse()?),
+            _ => LabelChange::Toggle(s.parse()?),
+        })
+    }
+}
+
+impl LabelChange {
+    pub fn apply(&self, labels: &[DarktableColor]) -> Vec<DarktableColor> {
+        let mut labels = labels.to_vec();
+        match *self {
+            LabelChange::Add(c) => labels.push(c),
+            LabelChange::Remove(c) => labels.retain(|&l| l != c),
+            LabelChange::Toggle(c) if labels.contains(&c) => labels.retain(|&l| l != c),
+            LabelChange::Toggle(c) => labels.push(c),
+            LabelChange::Clear => labels.clear(),
+        }
+        labels.sort_by_key(|c| c.index());
+        labels.dedup();
+        labels
+    }
+}
+
+/// Parses a comma separated list of label changes, e.g. `+red,-blue`.
+pub fn parse_label_changes(s: &str) -> Result<Vec<LabelChange>> {
+    s.split(',').map(|c| c.trim().parse()).collect()
+}
+
+fn rating_value(rating: Option<i32>) -> Option<TagValue> {
+    rating.map(|r| TagValue::Text(r.to_string()))
+}
+
+fn labels_value(labels: &[DarktableColor]) -> Option<TagValue> {
+    if labels.is_empty() {
+        None
+    } else {
+        Some(TagValue::List(labels.iter().map(|c| c.index().to_string()).collect()))
+    }
+}
+
+/// The edits which apply `rating` and `labels` to all sidecars of `photo`.
+/// Sidecars which already have the resulting values are left alone.
+pub fn plan_label_edits(photo: &Photo, rating: Option<RatingChange>, labels: &[LabelChange])
+    -> Result<Vec<TagEdit>>
+{
+    let mut edits = vec![];
+    for file in photo.editable_files()? {
+        let meta = Metadata::new(&file)?;
+        if let Some(change) = rating {
+            let old = meta.rating()?;
+            let new = change.apply(old);
+            if new != old {
+                edits.push(TagEdit {
+                    file: file.clone(),
+                    tag: XMP_XMP_RATING.to_string(),
+                    old: rating_value(old),
+                    new: rating_value(new),
+                });
+            }
+        }
+        if !labels.is_empty() {
+            let old = meta.darktable_colorlabels()?;
+            let new = labels.iter().fold(old.clone(), |l, change| change.apply(&l));
+            if new != old {
+                edits.push(TagEdit {
+                    file: file.clone(),
+                    tag: XMP_DARKTABLE_COLORLABELS.to_string(),
+                    old: labels_value(&old),
+                    new: labels_value(&new),
+                });
+            }
+        }
+    }
+    Ok(edits)
+}
+
+#[test]
+fn test_rating_change() {
+    let a: Vec<_> = ["3", "reject", "clear", "6", "x"].iter().map(|s| s.parse::<RatingChange>().ok()).collect();
+    assert_eq!(a, vec![Some(RatingChange::Set(3)), Some(RatingChange::Set(-1)), Some(RatingChange::Clear),
+                       None, None]);
+    assert_eq!(RatingChange::Toggle(3).apply(Some(3)), Some(0));
+    assert_eq!(RatingChange::Toggle(3).apply(Some(1)), Some(3));
+    assert_eq!(RatingChange::Clear.apply(Some(1)), None);
+}
+
+#[test]
+fn test_label_change() {
+    use metadata::DarktableColor::*;
+    let changes = parse_label_changes("+blue, -red,green").unwrap();
+    assert_eq!(changes, vec![LabelChange::Add(Blue), LabelChange::Remove(Red), LabelChange::Toggle(Green)]);
+    assert!(parse_label_changes("+pink").is_err());
+
+    let labels = changes.iter().fold(vec![Red, Green], |l, c| c.apply(&l));
+    assert_eq!(labels, vec![Blue]);
+    assert_eq!(LabelChange::Add(Red).apply(&[Blue, Red]), vec![Red, Blue]);
+    assert_eq!(LabelChange::Clear.apply(&[Blue]), vec![]);
+    assert_eq!(labels_value(&[Red, Blue]), Some(TagValue::List(vec!["0".into(), "3".into()])));
+}
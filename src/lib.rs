@@ -3,26 +3,77 @@
 
 #![recursion_limit = "1024"]
 
+extern crate blake3;
 extern crate chrono;
+extern crate gexiv2_sys;
+extern crate glob;
+extern crate hex;
+extern crate rayon;
+extern crate regex;
 extern crate rexiv2;
+extern crate serde;
+extern crate serde_json;
+extern crate sha2;
+extern crate toml;
 
 #[macro_use]
-extern crate itertools;
+extern crate serde_derive;
 
 #[macro_use]
-extern crate try_opt;
+extern crate lazy_static;
 
 #[macro_use]
 extern crate error_chain;
 
+pub mod capture;
+pub mod checksum;
+pub mod config;
+pub mod darktable;
+pub mod datesource;
 pub mod errors;
+pub mod fileops;
+pub mod filetypes;
+pub mod geocode;
 pub mod metadata;
+pub mod gps;
 pub mod grouping;
+pub mod journal;
+pub mod keywords;
+pub mod labels;
+pub mod pairing;
 pub mod paths;
+pub mod plan;
 pub mod photo;
+pub mod quicktime;
+pub mod repair;
+pub mod scan;
+pub mod shift;
+pub mod sidecars;
+pub mod template;
+pub mod validate;
 
+pub use capture::{CaptureTime, DefaultZone};
+pub use checksum::{Checksum, HashAlgorithm, hash_file, verify_file};
+pub use config::{Config, load_config};
+pub use darktable::{DarktableHistory, HistoryItem};
+pub use datesource::{DateSource, DEFAULT_DATE_SOURCES, extract_capture_time, parse_date_sources};
 pub use errors::*;
-pub use metadata::{extract_datetime, Metadata, write_derivedfrom};
+pub use fileops::{copy_file, move_file, MoveOutcome, TransferMode};
+pub use filetypes::{FileKind, FileType, FileTypes, MetadataBackend};
+pub use metadata::{DarktableColor, Metadata, TagEdit, TagValue, read_derivedfrom, write_derivedfrom, write_tags};
+pub use geocode::{Gazetteer, Place};
+pub use gps::{Coordinates, Track, TrackPoint, photo_position, plan_geotag_edits};
 pub use grouping::group_by_fn;
-pub use paths::{common_prefix, CommonPrefix};
-pub use photo::{Photo, group_photo_files};
+pub use journal::{FileStamp, Journal, JournalEntry, Verification, read_journal, undo, verify};
+pub use keywords::{KeywordChange, Keywords, plan_keyword_edits};
+pub use labels::{LabelChange, RatingChange, parse_label_changes, plan_label_edits};
+pub use pairing::{DEFAULT_PAIRING, PairingStrategy, pair_photos, parse_pairing_strategies};
+pub use paths::{common_prefix, CommonPrefix, make_new_filename, replace_word};
+pub use plan::{Cmd, Plan, check_cmd, check_preconditions, read_plan, write_plan};
+pub use photo::{Derived, Grouped, Orphan, Photo, group_photo_files};
+pub use repair::{MatchedBy, Repair, derived_orphans, find_target, plan_quarantine, plan_repair};
+pub use scan::{collect_files, ScanOptions};
+pub use shift::{CameraFilter, TagChange, parse_duration, parse_positive_duration, plan_time_shift};
+pub use sidecars::{Owners, SidecarFormat, find_capture_one_sidecars, replace_reference};
+pub use template::{NamingContext, NamingScheme, Template};
+pub use validate::{Conflict, ConflictKind, ConflictPolicy, find_conflicts, resolve_conflicts};
@@ -3,102 +3,165 @@
 
 extern crate chrono;
 extern crate rexiv2;
-extern crate clap;
 extern crate imgor;
+extern crate rayon;
+#[macro_use]
+extern crate clap;
+#[macro_use]
 extern crate error_chain;
 
+use std::collections::HashMap;
 use std::fs;
 use std::io;
 use std::path::{Path, PathBuf};
 use std::cmp::Ordering;
-use clap::{Arg, App, SubCommand};
-use chrono::{UTC, DateTime};
+use clap::{Arg, App, AppSettings, ArgMatches, SubCommand};
+use chrono::{Duration, UTC};
+use rayon::prelude::*;
 
 use imgor::*;
-use metadata::{extract_datetime};
 
-#[derive(Debug, PartialEq)]
-enum Cmd {
-    CreateDirectory(PathBuf),
-    Rename(PathBuf, PathBuf),
-    AdjustRef(PathBuf, PathBuf),
+struct RawMeta {
+    datetime_original: Option<CaptureTime>,
+    // where `datetime_original` was taken from
+    date_source: Option<DateSource>,
+    camera: Option<String>,
+    rating: Option<i32>,
+    place: Option<Place>,
+}
+
+struct AnnotatedPhoto {
+    photo: Photo,
+    meta: RawMeta,
 }
 
-fn collect_files(dirname: &Path) -> io::Result<Vec<PathBuf>> {
-    let entries = fs::read_dir(dirname)?;
+fn extract_raw_meta(photo: &Photo, options: &GroupOptions) -> imgor::Result<RawMeta> {
+    let camera = Metadata::new(&photo.source).ok().and_then(|m| m.camera_model());
 
-    let mut paths = Vec::<PathBuf>::new();
-    for dir_entry in entries {
-        let path = dir_entry?.path();
-        if path.is_file() {
-            paths.push(path);
+    // darktable keeps the rating in the sidecar files
+    let mut rating = None;
+    for f in photo.sidecars().into_iter().chain(Some(&photo.source)) {
+        if let Ok(m) = Metadata::new(f) {
+            rating = m.rating()?;
+            if rating.is_some() {
+                break;
+            }
         }
     }
-    paths.sort();
-    Ok(paths)
-}
 
-struct RawMeta {
-    datetime_original: Option<DateTime<UTC>>,
-}
+    let capture_time = extract_capture_time(&photo.source, &options.types, &options.date_sources,
+                                            options.zone)?;
+    let place = match options.places {
+        Some(ref places) => places.locate(photo)?,
+        None => None,
+    };
 
-struct AnnotatedPhoto {
-    photo: Photo,
-    meta: RawMeta,
+    Ok(RawMeta {
+        datetime_original: capture_time.map(|(t, _)| t),
+        date_source: capture_time.map(|(_, s)| s),
+        camera,
+        rating,
+        place,
+    })
 }
 
-fn extract_raw_meta(photo: &Photo) -> RawMeta {
-    RawMeta { datetime_original: extract_datetime(&photo.source) }
+// reads the metadata of all photos in parallel; the results are in
+// the order of `files`, without the photos whose metadata is broken
+fn date_photo_files(files: &Vec<Photo>, options: &GroupOptions, problems: &mut Problems)
+    -> imgor::Result<Vec<AnnotatedPhoto>>
+{
+    metadata::initialize();
+    let results: Vec<_> = files
+        .par_iter()
+        .map(|f| (f, extract_raw_meta(&f, options)))
+        .collect();
+
+    let mut dated = vec![];
+    for (f, meta) in results {
+        match meta {
+            Ok(meta) => dated.push(AnnotatedPhoto { photo: f.clone(), meta }),
+            Err(e) => problems.add(f.source.clone(), e)?,
+        }
+    }
+    Ok(dated)
 }
 
-fn date_photo_files(files: &Vec<Photo>) -> Vec<AnnotatedPhoto> {
-    files
-        .iter()
-        .map(
-            |f| {
-                let meta = extract_raw_meta(&f);
-                AnnotatedPhoto {
-                    photo: f.clone(),
-                    meta: meta,
-                }
-            }
-        )
-        .collect()
+// files which cannot be processed, e.g. because of malformed metadata;
+// the first one aborts the run, unless running with `--keep-going`
+struct Problems {
+    keep_going: bool,
+    files: Vec<(PathBuf, imgor::Error)>,
 }
 
-/// replaces `old` with `new` in `file_name`s stem, and returns
-/// the new filename with lowercased extensions
-fn make_new_filename(file_name: &str, old: &str, new: &str) -> String {
-    // note: because a file (like 1.cr2.xmp) may have multiple extensions
-    //       we search for "." ourselves
-    let first_dot = file_name.find(".");
-    match first_dot {
-        Some(index) => {
-            let (ref stem, ref ext) = file_name.split_at(index);
-            let new_stem = stem.replace(&old, &new);
-            let new_ext = ext.to_lowercase();
+impl Problems {
+    fn new(keep_going: bool) -> Problems {
+        Problems { keep_going, files: vec![] }
+    }
+
+    fn add(&mut self, file: PathBuf, error: imgor::Error) -> imgor::Result<()> {
+        if !self.keep_going {
+            return Err(error).chain_err(|| {
+                format!("cannot process '{}' (use --keep-going to skip such files)", file.display())
+            });
+        }
+        self.files.push((file, error));
+        Ok(())
+    }
+
+    fn add_all(&mut self, problems: Vec<(PathBuf, imgor::Error)>) -> imgor::Result<()> {
+        for (file, error) in problems {
+            self.add(file, error)?;
+        }
+        Ok(())
+    }
 
-            format!("{}{}", new_stem, new_ext)
-        },
-        None => {
-            // there is no extension
-            file_name.replace(&old, &new)
+    // prints the skipped files, and fails if there are any
+    fn report(&self) -> imgor::Result<()> {
+        if self.files.is_empty() {
+            return Ok(());
+        }
+        eprintln!("{} file(s) were skipped:", self.files.len());
+        for (file, e) in &self.files {
+            eprintln!("  {}: {}", file.display(), e);
         }
+        bail!("{} file(s) could not be processed", self.files.len())
     }
 }
 
-#[test]
-fn test_make_new_filename() {
+// reverse geocoding of photos with coordinates
+struct Places {
+    gazetteer: Gazetteer,
+    // km
+    max_distance: f64,
+}
 
-    let inputs = vec!["my_file.JPG", "my_file.CR2.JPG", "my_file.cr2.JPG", "my_file"];
-    let old = "my_file";
-    let new = "0000";
+impl Places {
+    // from `--gazetteer` or the configuration; `None` if neither is given
+    fn load(matches: &ArgMatches, config: &Config) -> imgor::Result<Option<Places>> {
+        let path = match matches.value_of("gazetteer") {
+            Some(p) => PathBuf::from(p),
+            None => match config.places.gazetteer {
+                Some(ref p) => p.clone(),
+                None => return Ok(None),
+            },
+        };
+        let gazetteer = Gazetteer::read(&path)?;
+        if gazetteer.is_empty() {
+            bail!("gazetteer '{}' contains no places", path.display());
+        }
+        Ok(Some(Places { gazetteer, max_distance: config.places.max_distance }))
+    }
 
-    let a : Vec<_> = inputs.iter()
-        .map(|e| make_new_filename(e, &old, &new)).collect();
-    let e = vec!["0000.jpg", "0000.cr2.jpg", "0000.cr2.jpg", "0000"];
+    fn require(matches: &ArgMatches, config: &Config, what: &str) -> imgor::Result<Places> {
+        Places::load(matches, config)?.ok_or_else(|| {
+            format!("{} need a gazetteer (use --gazetteer or set `places.gazetteer` in the configuration)",
+                    what).into()
+        })
+    }
 
-    assert_eq!(a, e);
+    fn locate(&self, photo: &Photo) -> imgor::Result<Option<Place>> {
+        Ok(photo_position(photo)?.and_then(|p| self.gazetteer.lookup(&p, self.max_distance)))
+    }
 }
 
 fn create_move_commands(photo: &Photo, new_stem: &str, out_dir: &Path) -> imgor::Result<Vec<Cmd>> {
@@ -119,48 +182,239 @@ fn create_move_commands(photo: &Photo, new_stem: &str, out_dir: &Path) -> imgor:
 
     cmds.push(Cmd::Rename(photo.source.clone(), new_source_file.clone()));
 
-    for derived in &photo.derived {
-        let derived_file_name = &derived.file_name()
+    derived_move_commands(&photo.derived, new_source_file, source_stem, new_stem, out_dir, &mut cmds)?;
+
+    // sidecars of other raw developers keep their place relative to the
+    // photo, e.g. in `CaptureOne/Settings`
+    let source_dir = photo.source.parent().unwrap_or_else(|| Path::new(""));
+    for sidecar in &photo.attached {
+        let sidecar_file_name = sidecar.file_name()
             .expect("need filename")
             .to_str()
-            .ok_or(ErrorKind::PathNotUtf8(derived.clone()))?;
+            .ok_or(ErrorKind::PathNotUtf8(sidecar.clone()))?;
+        let sub_dir = sidecar.parent()
+            .and_then(|d| d.strip_prefix(source_dir).ok())
+            .unwrap_or_else(|| Path::new(""));
+        let new_dir = out_dir.join(sub_dir);
+        if new_dir != out_dir {
+            cmds.push(Cmd::CreateDirectory(new_dir.clone()));
+        }
+        let new_sidecar_file = new_dir.join(make_new_filename(sidecar_file_name, source_stem, new_stem));
+
+        cmds.push(Cmd::Rename(sidecar.clone(), new_sidecar_file.clone()));
+        cmds.push(Cmd::ReplaceRef(new_sidecar_file, photo.source.clone(), new_source_file.clone()));
+    }
+
+    // files shot together get the same name, unless one of the photo's
+    // files has it already (e.g. the camera's JPG and one exported from
+    // the raw): then they are numbered
+    for companion in &photo.companions {
+        for n in 0.. {
+            let stem = match n {
+                0 => new_stem.to_string(),
+                n => format!("{}_{}", new_stem, n),
+            };
+            let mut companion_cmds = create_move_commands(companion, &stem, out_dir)?;
+            let collides = companion_cmds.iter().any(|c| match *c {
+                Cmd::Rename(_, ref dest) => cmds.iter().any(|c| matches!(*c, Cmd::Rename(_, ref d) if d == dest)),
+                _ => false,
+            });
+            if !collides {
+                cmds.append(&mut companion_cmds);
+                break;
+            }
+        }
+    }
+
+    Ok(cmds)
+}
+
+// renames `derived` and the files derived from them in turn, each one
+// referring to the new name of the file it was derived from
+fn derived_move_commands(derived: &[Derived], new_parent: &Path, source_stem: &str, new_stem: &str,
+                         out_dir: &Path, cmds: &mut Vec<Cmd>)
+    -> imgor::Result<()>
+{
+    for d in derived {
+        let derived_file_name = &d.file.file_name()
+            .expect("need filename")
+            .to_str()
+            .ok_or(ErrorKind::PathNotUtf8(d.file.clone()))?;
 
         let new_derived_file = &out_dir.join(
             make_new_filename(&derived_file_name, source_stem, new_stem));
 
-        cmds.push(Cmd::Rename(derived.clone(), new_derived_file.clone()));
+        cmds.push(Cmd::Rename(d.file.clone(), new_derived_file.clone()));
         cmds.push(
-            Cmd::AdjustRef(new_derived_file.clone(), new_source_file.clone())
+            Cmd::AdjustRef(new_derived_file.clone(), new_parent.to_path_buf())
         );
+        derived_move_commands(&d.derived, new_derived_file, source_stem, new_stem, out_dir, cmds)?;
     }
-
-    Ok(cmds)
+    Ok(())
 }
 
 #[test]
 fn test_create_move_commands() {
     let p = Photo {
         source: PathBuf::from("/a/1.CR2"),
-        derived: vec![PathBuf::from("/a/1.cr2.xmp"), PathBuf::from("/a/1_v2.CR2.xmp"), PathBuf::from("/a/1.jpg")]
+        derived: ["/a/1.cr2.xmp", "/a/1_v2.CR2.xmp", "/a/1.jpg", "/a/1_01.cr2.xmp", "/a/DSC_1234-edit.jpg"]
+            .iter()
+            .map(|d| Derived::new(PathBuf::from(d)))
+            .collect(),
+        attached: vec![PathBuf::from("/a/1.CR2.pp3"), PathBuf::from("/a/CaptureOne/Settings/1.CR2.cos")],
+        companions: vec![
+            Photo::new(PathBuf::from("/a/1.WAV")),
+            Photo {
+                source: PathBuf::from("/card2/IMG_9.JPG"),
+                derived: vec![Derived {
+                    file: PathBuf::from("/card2/IMG_9.JPG.xmp"),
+                    derived: vec![Derived::new(PathBuf::from("/card2/IMG_9-edit.JPG"))],
+                }],
+                attached: vec![],
+                companions: vec![],
+            },
+        ],
     };
     let out_dir = PathBuf::from("/tmp");
     let a = create_move_commands(&p, &"x", &out_dir);
     let e = vec![
         Cmd::Rename(p.source.clone(),           out_dir.join("x.cr2")),
-        Cmd::Rename(p.derived[0].clone(),      out_dir.join("x.cr2.xmp")),
+        Cmd::Rename(p.derived[0].file.clone(),      out_dir.join("x.cr2.xmp")),
         Cmd::AdjustRef(out_dir.join("x.cr2.xmp"), out_dir.join("x.cr2")),
-        Cmd::Rename(p.derived[1].clone(),      out_dir.join("x_v2.cr2.xmp")),
+        Cmd::Rename(p.derived[1].file.clone(),      out_dir.join("x_v2.cr2.xmp")),
         Cmd::AdjustRef(out_dir.join("x_v2.cr2.xmp"), out_dir.join("x.cr2")),
-        Cmd::Rename(p.derived[2].clone(), out_dir.join("x.jpg")),
+        Cmd::Rename(p.derived[2].file.clone(), out_dir.join("x.jpg")),
         Cmd::AdjustRef(out_dir.join("x.jpg"), out_dir.join("x.cr2")),
+        Cmd::Rename(p.derived[3].file.clone(), out_dir.join("x_01.cr2.xmp")),
+        Cmd::AdjustRef(out_dir.join("x_01.cr2.xmp"), out_dir.join("x.cr2")),
+        Cmd::Rename(p.derived[4].file.clone(), out_dir.join("x_DSC_1234-edit.jpg")),
+        Cmd::AdjustRef(out_dir.join("x_DSC_1234-edit.jpg"), out_dir.join("x.cr2")),
+        Cmd::Rename(PathBuf::from("/a/1.CR2.pp3"), out_dir.join("x.cr2.pp3")),
+        Cmd::ReplaceRef(out_dir.join("x.cr2.pp3"), p.source.clone(), out_dir.join("x.cr2")),
+        Cmd::CreateDirectory(out_dir.join("CaptureOne/Settings")),
+        Cmd::Rename(PathBuf::from("/a/CaptureOne/Settings/1.CR2.cos"), out_dir.join("CaptureOne/Settings/x.cr2.cos")),
+        Cmd::ReplaceRef(out_dir.join("CaptureOne/Settings/x.cr2.cos"), p.source.clone(), out_dir.join("x.cr2")),
+        Cmd::Rename(PathBuf::from("/a/1.WAV"), out_dir.join("x.wav")),
+        // `x.jpg` is the JPG exported from the raw
+        Cmd::Rename(PathBuf::from("/card2/IMG_9.JPG"), out_dir.join("x_1.jpg")),
+        // with a sidecar of its own, from which another JPG was exported
+        Cmd::Rename(PathBuf::from("/card2/IMG_9.JPG.xmp"), out_dir.join("x_1.jpg.xmp")),
+        Cmd::AdjustRef(out_dir.join("x_1.jpg.xmp"), out_dir.join("x_1.jpg")),
+        Cmd::Rename(PathBuf::from("/card2/IMG_9-edit.JPG"), out_dir.join("x_1-edit.jpg")),
+        Cmd::AdjustRef(out_dir.join("x_1-edit.jpg"), out_dir.join("x_1.jpg.xmp")),
     ];
     assert_eq!(a.unwrap(), e);
 }
 
-fn group_files_by_date(in_dir: &Path, out_dir: &Path) -> imgor::Result<Vec<Cmd>> {
-    let files = collect_files(&in_dir)?;
-    let grouped = group_photo_files(&files)?;
-    let mut dated = date_photo_files(&grouped);
+#[test]
+fn test_create_move_commands_companion_collision() {
+    // darktable's export and the camera's JPG
+    let mut p = Photo::new(PathBuf::from("/a/IMG_1.CR2"));
+    p.derived = vec![Derived::new(PathBuf::from("/a/IMG_1.jpg"))];
+    p.companions = vec![Photo::new(PathBuf::from("/a/IMG_1.JPG")), Photo::new(PathBuf::from("/a/IMG_1.WAV"))];
+    let out_dir = PathBuf::from("/out");
+    let e = vec![
+        Cmd::Rename(PathBuf::from("/a/IMG_1.CR2"), out_dir.join("x.cr2")),
+        Cmd::Rename(PathBuf::from("/a/IMG_1.jpg"), out_dir.join("x.jpg")),
+        Cmd::AdjustRef(out_dir.join("x.jpg"), out_dir.join("x.cr2")),
+        Cmd::Rename(PathBuf::from("/a/IMG_1.JPG"), out_dir.join("x_1.jpg")),
+        Cmd::Rename(PathBuf::from("/a/IMG_1.WAV"), out_dir.join("x.wav")),
+    ];
+    assert_eq!(create_move_commands(&p, "x", &out_dir).unwrap(), e);
+}
+
+#[test]
+fn test_execute_cmds_verify() {
+    let dir = std::env::temp_dir().join("imgor-test-execute-verify");
+    let _ = fs::remove_dir_all(&dir);
+    let (card, out) = (dir.join("card"), dir.join("out"));
+    fs::create_dir_all(&card).unwrap();
+    fs::write(card.join("IMG_7506.CR2"), b"raw data").unwrap();
+    fs::copy(Path::new(env!("CARGO_MANIFEST_DIR")).join("test_data/IMG_7506.CR2.xmp"),
+             card.join("IMG_7506.CR2.xmp")).unwrap();
+    fs::write(card.join("IMG_7506.CR2.pp3"), "[Source]\nFile=IMG_7506.CR2\n").unwrap();
+    let photo = Photo {
+        source: card.join("IMG_7506.CR2"),
+        derived: vec![Derived::new(card.join("IMG_7506.CR2.xmp"))],
+        attached: vec![card.join("IMG_7506.CR2.pp3")],
+        companions: vec![],
+    };
+    let mut cmds = vec![Cmd::CreateDirectory(out.clone())];
+    cmds.extend(create_move_commands(&photo, "x", &out).unwrap());
+
+    // the sidecars are changed after they were copied
+    let journal_path = dir.join("journal.jsonl");
+    let mut journal = Journal::create(&journal_path).unwrap();
+    execute_cmds(&cmds, TransferMode::Copy, Some(HashAlgorithm::Blake3), &mut journal).unwrap();
+    assert_eq!(read_derivedfrom(&out.join("x.cr2.xmp")).unwrap(), Some("x.cr2".to_string()));
+
+    let e = vec![
+        (out.join("x.cr2"), Verification::Intact),
+        (out.join("x.cr2.xmp"), Verification::Intact),
+        (out.join("x.cr2.pp3"), Verification::Intact),
+    ];
+    assert_eq!(verify(&read_journal(&journal_path).unwrap()).unwrap(), e);
+}
+
+/// returns the number following the highest sequence number used
+/// by files in `dir` named according to `template`
+fn next_sequence_number(dir: &Path, template: &Template) -> imgor::Result<usize> {
+    let mut next = 0;
+    for entry in fs::read_dir(dir)? {
+        let name = entry?.file_name();
+        // note: the stem ends at the first dot, see `make_new_filename`
+        let stem = name.to_str().and_then(|n| n.split('.').next());
+        if let Some(n) = stem.and_then(|s| template.parse_seq(s)) {
+            next = std::cmp::max(next, n + 1);
+        }
+    }
+    Ok(next)
+}
+
+struct GroupOptions {
+    scan: ScanOptions,
+    types: FileTypes,
+    pairing: Vec<PairingStrategy>,
+    naming: NamingScheme,
+    // group titles (for `{title}`), by date `YYYY-MM-DD`
+    titles: HashMap<String, String>,
+    // zone of capture times without offset
+    zone: DefaultZone,
+    date_sources: Vec<DateSource>,
+    // only if the templates refer to places
+    places: Option<Places>,
+}
+
+// e.g. `dates: 120 from datetime_original, 3 from filename, 2 without date`
+fn print_date_sources(dated: &[AnnotatedPhoto], sources: &[DateSource]) {
+    let count = |source: Option<DateSource>| {
+        dated.iter().filter(|f| f.meta.date_source == source).count()
+    };
+    let mut parts: Vec<_> = sources.iter()
+        .map(|&s| (count(Some(s)), format!("from {}", s)))
+        .chain(Some((count(None), "without date".to_string())))
+        .filter(|&(n, _)| n > 0)
+        .map(|(n, what)| format!("{} {}", n, what))
+        .collect();
+    if parts.is_empty() {
+        parts.push("no files".into());
+    }
+    eprintln!("dates: {}", parts.join(", "));
+}
+
+fn group_files_by_date(in_dir: &Path, out_dir: &Path, options: &GroupOptions, problems: &mut Problems)
+    -> imgor::Result<Plan>
+{
+    // when scanning recursively, do not pick up previously grouped files
+    let files = collect_files(&in_dir, &options.scan)?
+        .into_iter()
+        .filter(|f| !f.starts_with(out_dir))
+        .collect();
+    let grouped = group_photo_files(&files, &options.types, &options.pairing)?;
+    problems.add_all(grouped.unreadable)?;
+    warn_orphans(&grouped.orphans);
+    let mut dated = date_photo_files(&grouped.photos, options, problems)?;
+    print_date_sources(&dated, &options.date_sources);
 
     dated.sort_by(
         |ref a, ref b| match (a.meta.datetime_original, b.meta.datetime_original) {
@@ -171,7 +425,7 @@ fn group_files_by_date(in_dir: &Path, out_dir: &Path) -> imgor::Result<Vec<Cmd>>
         }
     );
 
-    // group by date
+    // group by the local date of capture
     let groups = group_by_fn(
         &dated, |a, b| {
             match (a.meta.datetime_original, b.meta.datetime_original) {
@@ -183,25 +437,55 @@ fn group_files_by_date(in_dir: &Path, out_dir: &Path) -> imgor::Result<Vec<Cmd>>
         }
     );
 
-    let mut cmds = vec![];
+    let mut plan = Plan::default();
+    // next sequence number, per folder
+    let mut next_seq = HashMap::<PathBuf, usize>::new();
 
     for group in groups {
         let group_name = match group[0].meta.datetime_original {
             Some(d) => format!("{}", d.date().format("%Y-%m-%d")),
             None => "no-date".into()
         };
-        let group_dir = out_dir.join(&group_name);
+        let title = options.titles.get(&group_name).map(String::as_str);
+
+        for f in group {
+            let stem = f.photo.source.file_stem()
+                .ok_or(format!("file `{}` has no basename", f.photo.source.display()))?
+                .to_str()
+                .ok_or(ErrorKind::PathNotUtf8(f.photo.source.clone()))?;
+            let mut ctx = NamingContext {
+                datetime: f.meta.datetime_original.map(|t| t.local),
+                seq: 0,
+                camera: f.meta.camera.as_deref(),
+                stem,
+                rating: f.meta.rating,
+                title,
+                country: f.meta.place.as_ref().map(|p| p.country.as_str()),
+                region: f.meta.place.as_ref().map(|p| p.region.as_str()),
+                city: f.meta.place.as_ref().map(|p| p.city.as_str()),
+            };
+            let folder = out_dir.join(options.naming.folder.render_path(&ctx)?);
 
-        cmds.push(Cmd::CreateDirectory(out_dir.join(&group_name)));
+            if !next_seq.contains_key(&folder) {
+                // merge into an existing folder, continuing its numbering
+                let first = if folder.is_dir() {
+                    next_sequence_number(&folder, &options.naming.file)?
+                } else {
+                    plan.push(Cmd::CreateDirectory(folder.clone()));
+                    0
+                };
+                next_seq.insert(folder.clone(), first);
+            }
+            let seq = next_seq.get_mut(&folder).unwrap();
+            ctx.seq = *seq;
+            *seq += 1;
 
-        for (i, f) in group.iter().enumerate() {
-            let new_stem = format!("{:04}_{}", i, group_name);
-            let mut c = create_move_commands(&f.photo, &new_stem, &group_dir)?;
-            cmds.append(&mut c);
+            let new_stem = options.naming.file.render(&ctx)?;
+            plan.push_photo(create_move_commands(&f.photo, &new_stem, &folder)?);
         }
     }
 
-    Ok(cmds)
+    Ok(plan)
 }
 
 fn print_rename(src: &Path, dest: &Path) -> String {
@@ -209,6 +493,571 @@ fn print_rename(src: &Path, dest: &Path) -> String {
     format!("{}/{{{} => {}}}", c.prefix.display(), c.suffix1.display(), c.suffix2.display())
 }
 
+fn print_cmds(cmds: &[Cmd], mode: TransferMode) {
+    for cmd in cmds {
+        match *cmd {
+            Cmd::Rename(ref src, ref dest) => {
+                let verb = match mode {
+                    TransferMode::Copy => "copy",
+                    TransferMode::Move => "move",
+                };
+                println!("{:<10} {}", verb, print_rename(src, dest));
+            },
+            Cmd::CreateDirectory(ref dir) => {
+                println!("create dir {}", dir.display());
+            },
+            Cmd::AdjustRef(ref file, ref referenced_image) |
+            Cmd::ReplaceRef(ref file, _, ref referenced_image) => {
+                let name = referenced_image.file_name().unwrap_or_default();
+                println!("adjust ref {} --> {}", file.display(), name.to_string_lossy());
+            }
+        }
+    }
+}
+
+// creates `dir` and all its missing parents, recording each directory
+// that had to be created
+fn create_dir_journaled(dir: &Path, journal: &mut Journal) -> imgor::Result<()> {
+    let mut missing = dir.ancestors()
+        .take_while(|d| !d.as_os_str().is_empty() && !d.exists())
+        .collect::<Vec<_>>();
+    missing.reverse();
+    for d in missing {
+        fs::create_dir(d)?;
+        journal.record(&JournalEntry::CreateDirectory { dir: d.to_path_buf() })?;
+    }
+    Ok(())
+}
+
+/// Executes `cmds`, recording every executed step in `journal`.
+/// Returns the source files which were copied successfully in
+/// `TransferMode::Move`, but could not be removed.
+fn execute_cmds(cmds: &[Cmd], mode: TransferMode, algorithm: Option<HashAlgorithm>,
+                journal: &mut Journal)
+    -> imgor::Result<Vec<(PathBuf, io::Error)>>
+{
+    let mut not_removed = vec![];
+    // the checksum of a transferred file changes with its references
+    let rehash = |file: &Path| match algorithm {
+        Some(algorithm) => hash_file(file, algorithm).map(Some),
+        None => Ok(None),
+    };
+
+    // reject a broken plan before anything is changed
+    for (i, cmd) in cmds.iter().enumerate() {
+        check_cmd(cmd).chain_err(|| format!("invalid step {} of the plan", i + 1))?;
+    }
+
+    for (i, cmd) in cmds.iter().enumerate() {
+        check_preconditions(cmd)
+            .chain_err(|| format!("cannot execute step {} of the plan", i + 1))?;
+
+        match *cmd {
+            Cmd::Rename(ref src, ref dest) => {
+                let (src, dest) = (src.clone(), dest.clone());
+                match mode {
+                    TransferMode::Copy => {
+                        let checksum = copy_file(&src, &dest, algorithm)?;
+                        let stamp = Some(FileStamp::of(&dest)?);
+                        journal.record(&JournalEntry::Copy { src, dest, checksum, stamp })?;
+                    },
+                    TransferMode::Move => {
+                        match move_file(&src, &dest, algorithm)? {
+                            (MoveOutcome::SourceNotRemoved(e), checksum) => {
+                                not_removed.push((src.clone(), e));
+                                let stamp = Some(FileStamp::of(&dest)?);
+                                journal.record(&JournalEntry::Copy { src, dest, checksum, stamp })?;
+                            }
+                            (_, checksum) => {
+                                journal.record(&JournalEntry::Move { src, dest, checksum })?;
+                            }
+                        }
+                    }
+                }
+            },
+            Cmd::CreateDirectory(ref dir) => {
+                create_dir_journaled(dir, journal)?;
+            },
+            Cmd::AdjustRef(ref file, ref referenced_image) => {
+                // `check_cmd` made sure that it is in the same folder
+                let derived_from = referenced_image.file_name()
+                    .and_then(|n| n.to_str())
+                    .ok_or_else(|| ErrorKind::PathNotUtf8(referenced_image.clone()))?;
+                let old = read_derivedfrom(file)?;
+                write_derivedfrom(&file, &derived_from)?;
+                journal.record(&JournalEntry::AdjustRef {
+                    file: file.clone(),
+                    old,
+                    new: derived_from.to_string(),
+                    checksum: rehash(file)?,
+                    stamp: Some(FileStamp::of(file)?),
+                })?;
+            }
+            Cmd::ReplaceRef(ref file, ref old_image, ref new_image) => {
+                let name = |path: &PathBuf| -> imgor::Result<String> {
+                    path.file_name()
+                        .and_then(|n| n.to_str())
+                        .map(String::from)
+                        .ok_or_else(|| ErrorKind::PathNotUtf8(path.clone()).into())
+                };
+                let (old, new) = (name(old_image)?, name(new_image)?);
+                if replace_reference(file, &old, &new)? {
+                    let checksum = rehash(file)?;
+                    let stamp = Some(FileStamp::of(file)?);
+                    journal.record(&JournalEntry::ReplaceRef { file: file.clone(), old, new, checksum, stamp })?;
+                }
+            }
+        }
+    }
+
+    Ok(not_removed)
+}
+
+fn print_not_removed(not_removed: &[(PathBuf, io::Error)]) {
+    if !not_removed.is_empty() {
+        eprintln!("{} file(s) were copied, but could not be removed:", not_removed.len());
+        for (file, e) in not_removed {
+            eprintln!("  {}: {}", file.display(), e);
+        }
+    }
+}
+
+fn print_journal_entry(entry: &JournalEntry) {
+    match *entry {
+        JournalEntry::CreateDirectory { ref dir } => {
+            println!("remove dir {}", dir.display());
+        }
+        JournalEntry::Copy { ref dest, .. } => {
+            println!("remove     {}", dest.display());
+        }
+        JournalEntry::Move { ref src, ref dest, .. } => {
+            println!("move       {}", print_rename(dest, src));
+        }
+        JournalEntry::AdjustRef { ref file, ref old, .. } => {
+            match *old {
+                Some(ref old) => println!("adjust ref {} --> {}", file.display(), old),
+                None => println!("clear ref  {}", file.display()),
+            }
+        }
+        JournalEntry::ReplaceRef { ref file, ref old, .. } => {
+            println!("adjust ref {} --> {}", file.display(), old);
+        }
+        JournalEntry::SetTag { ref file, ref tag, ref old, .. } => {
+            match *old {
+                Some(ref old) => println!("set tag    {} {}: {}", file.display(), tag, old),
+                None => println!("clear tag  {} {}", file.display(), tag),
+            }
+        }
+    }
+}
+
+// the given files, and the files in the given directories
+fn collect_paths(paths: &[PathBuf], scan: &ScanOptions) -> imgor::Result<Vec<PathBuf>> {
+    let mut files = vec![];
+    for path in paths {
+        if path.is_dir() {
+            files.extend(collect_files(path, scan)?);
+        } else {
+            files.push(path.clone());
+        }
+    }
+    Ok(files)
+}
+
+// the photos given by the `PATH` arguments of the subcommand
+fn collect_photos(matches: &ArgMatches, types: &FileTypes, pairing: &[PairingStrategy],
+                  problems: &mut Problems)
+    -> imgor::Result<Vec<Photo>>
+{
+    let paths: Vec<_> = matches.values_of("PATH").unwrap().map(PathBuf::from).collect();
+    let files = collect_paths(&paths, &scan_options(matches)?)?;
+    let grouped = group_photo_files(&files, types, pairing)?;
+    problems.add_all(grouped.unreadable)?;
+    warn_orphans(&grouped.orphans);
+    Ok(grouped.photos)
+}
+
+// orphans are left alone, they are not worth aborting the run for
+fn warn_orphans(orphans: &[Orphan]) {
+    if orphans.is_empty() {
+        return;
+    }
+    eprintln!("warning: {} file(s) refer to files which are missing or were not included, \
+               and are left alone (see `imgor repair`):", orphans.len());
+    for o in orphans {
+        eprintln!("  {} --> {}", o.file.display(), o.derived_from.display());
+    }
+}
+
+// the files (photos, their derived files and companions) whose capture
+// times are to be shifted; videos are skipped, their metadata cannot be written
+fn time_shift_files(photos: &[Photo], types: &FileTypes, filter: &CameraFilter) -> Vec<PathBuf> {
+    photos.par_iter()
+        .filter(|p| filter.matches(&p.source))
+        .flat_map(|p| p.files())
+        .filter(|f| types.backend(f) == Some(MetadataBackend::Exiv2))
+        .collect()
+}
+
+// plans the edits of all photos in parallel
+fn photo_edits<F>(photos: &[Photo], problems: &mut Problems, plan: F) -> imgor::Result<Vec<TagEdit>>
+where
+    F: Fn(&Photo) -> imgor::Result<Vec<TagEdit>> + Sync,
+{
+    metadata::initialize();
+    let planned: Vec<_> = photos.par_iter()
+        .map(|p| (p, plan(p)))
+        .collect();
+
+    let mut edits = vec![];
+    for (photo, planned) in planned {
+        match planned {
+            Ok(mut e) => edits.append(&mut e),
+            Err(e) => problems.add(photo.source.clone(), e)?,
+        }
+    }
+    Ok(edits)
+}
+
+// the position of `photo` on `track` at its capture time, corrected by `offset`
+fn locate_photo(photo: &Photo, types: &FileTypes, track: &Track, zone: DefaultZone, offset: Duration,
+                max_gap: Duration)
+    -> imgor::Result<Option<Coordinates>>
+{
+    // only the dates recorded by the camera are precise enough
+    let sources = [DateSource::DateTimeOriginal, DateSource::CreateDate, DateSource::Xmp];
+    let time = extract_capture_time(&photo.source, types, &sources, zone)?.and_then(|(t, _)| t.to_utc());
+    let time = match time {
+        Some(t) => t,
+        None => return Ok(None),
+    };
+    let time = time.checked_add_signed(offset)
+        .ok_or_else(|| format!("capture time {} is out of range when corrected by {}", time, offset))?;
+    Ok(track.locate(time, max_gap))
+}
+
+// e.g. `img.cr2.xmp: portrait, places|germany|berlin`
+fn print_keywords(photos: &[Photo], problems: &mut Problems) -> imgor::Result<()> {
+    metadata::initialize();
+    let files: Vec<_> = photos.iter().flat_map(|p| p.metadata_files()).collect();
+    let keywords: Vec<_> = files.par_iter()
+        .map(|f| Metadata::new(f).map(|m| m.keywords()))
+        .collect();
+    for (file, keywords) in files.iter().zip(keywords) {
+        match keywords {
+            Ok(k) => {
+                // flat keywords which are not part of a hierarchical one
+                let flat = k.flat.iter().filter(|f| {
+                    !k.hierarchical.iter().any(|h| h.rsplit('|').next() == Some(f.as_str()))
+                });
+                let all: Vec<_> = flat.chain(&k.hierarchical).map(String::as_str).collect();
+                if !all.is_empty() {
+                    println!("{}: {}", file.display(), all.join(", "));
+                }
+            }
+            Err(e) => problems.add(file.clone(), e)?,
+        }
+    }
+    Ok(())
+}
+
+// e.g. `img.cr2.xmp: exposure, clipping`, for the sidecars with a
+// darktable history which match the `operation` and `edited` filters
+fn print_histories(files: &[PathBuf], matches: &ArgMatches, problems: &mut Problems) -> imgor::Result<()> {
+    let operations: Vec<_> = matches.values_of("operation").map(|v| v.collect()).unwrap_or_default();
+    let edited = if matches.is_present("edited") {
+        Some(true)
+    } else if matches.is_present("untouched") {
+        Some(false)
+    } else {
+        None
+    };
+
+    metadata::initialize();
+    let sidecars: Vec<_> = files.iter()
+        .filter(|f| f.extension().is_some_and(|e| e.eq_ignore_ascii_case("xmp")))
+        .collect();
+    let histories: Vec<_> = sidecars.par_iter()
+        .map(|f| (f, Metadata::new(f).and_then(|m| m.darktable_history())))
+        .collect();
+
+    for (file, history) in histories {
+        let history = match history {
+            Ok(Some(history)) => history,
+            Ok(None) => continue,
+            Err(e) => {
+                problems.add(file.to_path_buf(), e)?;
+                continue;
+            }
+        };
+        if !operations.iter().all(|op| history.applies(op))
+            || edited.is_some_and(|e| e != history.is_edited()) {
+            continue;
+        }
+        let ops: Vec<_> = history.operations().iter().map(|i| i.operation.as_str()).collect();
+        println!("{}: {}", file.display(), ops.join(", "));
+    }
+    Ok(())
+}
+
+fn print_tag_value(value: Option<&TagValue>) -> String {
+    value.map_or("(none)".into(), |v| v.to_string())
+}
+
+fn print_tag_edits(edits: &[TagEdit], verb: &str) {
+    for e in edits {
+        println!("{:<10} {} {}: {} --> {}", verb, e.file.display(), e.tag,
+                 print_tag_value(e.old.as_ref()), print_tag_value(e.new.as_ref()));
+    }
+}
+
+/// Writes the edited tags, recording the previous values in `journal`.
+fn execute_tag_edits(edits: &[TagEdit], journal: &mut Journal) -> imgor::Result<()> {
+    // edits of the same file are written at once
+    for file_edits in group_by_fn(edits, |a, b| a.file == b.file) {
+        let tags: Vec<_> = file_edits.iter().map(|e| (e.tag.as_str(), e.new.as_ref())).collect();
+        write_tags(&file_edits[0].file, &tags)?;
+        for e in file_edits {
+            journal.record(&JournalEntry::SetTag {
+                file: e.file.clone(),
+                tag: e.tag.clone(),
+                old: e.old.clone(),
+                new: e.new.clone(),
+            })?;
+        }
+    }
+    Ok(())
+}
+
+// prints or executes `edits`, according to the `journal` argument of the
+// subcommand; returns the number of changed files
+fn run_tag_edits(edits: &[TagEdit], verb: &str, matches: &ArgMatches, dry_run: bool)
+    -> imgor::Result<usize>
+{
+    if dry_run {
+        print_tag_edits(edits, verb);
+    } else {
+        let journal_path = matches.value_of("journal")
+            .map(PathBuf::from)
+            .unwrap_or_else(default_journal_path);
+        let mut journal = Journal::create(&journal_path)?;
+        let result = execute_tag_edits(edits, &mut journal);
+        println!("journal written to {}", journal.path().display());
+        result?;
+    }
+    Ok(group_by_fn(edits, |a, b| a.file == b.file).count())
+}
+
+// `imgor-journal-<time>.jsonl`, with a counter for further runs within
+// the same second (the journal must not exist yet)
+fn default_journal_path() -> PathBuf {
+    let stem = format!("imgor-journal-{}", UTC::now().format("%Y%m%d-%H%M%S"));
+    (0..)
+        .map(|n| match n {
+            0 => PathBuf::from(format!("{}.jsonl", stem)),
+            n => PathBuf::from(format!("{}-{}.jsonl", stem, n)),
+        })
+        .find(|p| !p.exists())
+        .unwrap()
+}
+
+// reports the conflicts in `plan` and resolves them according to the
+// `on conflict` argument of the subcommand
+fn resolve_plan(plan: &Plan, matches: &ArgMatches) -> imgor::Result<Plan> {
+    let policy: ConflictPolicy = matches.value_of("on conflict").unwrap().parse()?;
+    for conflict in find_conflicts(plan) {
+        eprintln!("conflict: {}", conflict);
+    }
+    let (plan, _) = resolve_conflicts(plan, policy)?;
+    Ok(plan)
+}
+
+// prints or executes `cmds`, according to the `move`, `checksum` and
+// `journal` arguments of the subcommand
+fn run_cmds(cmds: &[Cmd], matches: &ArgMatches, dry_run: bool) -> imgor::Result<()> {
+    let mode = if matches.is_present("move") {
+        TransferMode::Move
+    } else {
+        TransferMode::Copy
+    };
+    let algorithm = match matches.value_of("checksum") {
+        Some(a) => Some(a.parse::<HashAlgorithm>()?),
+        None => None,
+    };
+
+    if dry_run {
+        print_cmds(cmds, mode);
+        Ok(())
+    } else {
+        execute_journaled(cmds, mode, algorithm, matches)
+    }
+}
+
+// executes `cmds`, recording them in the journal given by the `journal`
+// argument of the subcommand
+fn execute_journaled(cmds: &[Cmd], mode: TransferMode, algorithm: Option<HashAlgorithm>,
+                     matches: &ArgMatches)
+    -> imgor::Result<()>
+{
+    let journal_path = matches.value_of("journal")
+        .map(PathBuf::from)
+        .unwrap_or_else(default_journal_path);
+    let mut journal = Journal::create(&journal_path)?;
+    let result = execute_cmds(cmds, mode, algorithm, &mut journal);
+    println!("journal written to {}", journal.path().display());
+    print_not_removed(&result?);
+    Ok(())
+}
+
+// asks on the terminal, anything but `y` is a no
+fn confirm(question: &str) -> imgor::Result<bool> {
+    use std::io::Write;
+    print!("{} [y/N] ", question);
+    io::stdout().flush()?;
+    let mut answer = String::new();
+    io::stdin().read_line(&mut answer)?;
+    Ok(answer.trim().eq_ignore_ascii_case("y"))
+}
+
+// plans the repair of the orphans among the photos given by the `PATH`
+// arguments: references to files which can be found are adjusted, the
+// other orphans are moved to the quarantine folder, if one is given
+fn plan_repairs(matches: &ArgMatches, types: &FileTypes, problems: &mut Problems)
+    -> imgor::Result<Plan>
+{
+    let paths: Vec<_> = matches.values_of("PATH").unwrap().map(PathBuf::from).collect();
+    let files = collect_paths(&paths, &scan_options(matches)?)?;
+    // pairing makes no difference to orphans
+    let grouped = group_photo_files(&files, types, &[])?;
+    problems.add_all(grouped.unreadable)?;
+
+    let mut plan = Plan::default();
+    let mut unresolved = vec![];
+    let orphaned: Vec<_> = grouped.orphans.iter().map(|o| o.file.clone()).collect();
+    for orphan in &grouped.orphans {
+        // e.g. a JPG exported from an orphaned TIFF, which is repaired
+        // along with it
+        if orphaned.contains(&orphan.derived_from) {
+            println!("{}: refers to '{}', which is an orphan itself", orphan.file.display(),
+                     orphan.derived_from.display());
+            continue;
+        }
+        if orphan.derived_from.exists() {
+            println!("{}: refers to '{}', which was not included", orphan.file.display(),
+                     orphan.derived_from.display());
+            continue;
+        }
+        match find_target(orphan, types) {
+            Some(repair) => {
+                println!("{}: '{}' found by {}", orphan.file.display(), repair.target.display(),
+                         repair.matched_by);
+                plan.push_photo(plan_repair(&repair, &derived_orphans(&orphan.file, &grouped.orphans)));
+            }
+            None => {
+                println!("{}: no file found for '{}'", orphan.file.display(), orphan.derived_from.display());
+                unresolved.push(orphan.clone());
+                // quarantined along with it, so that their references hold
+                let mut derived = derived_orphans(&orphan.file, &grouped.orphans);
+                while let Some(d) = derived.pop() {
+                    unresolved.extend(grouped.orphans.iter().find(|o| o.file == d.file).cloned());
+                    derived.extend(d.derived);
+                }
+            }
+        }
+    }
+    if let Some(dir) = matches.value_of("quarantine") {
+        for cmd in plan_quarantine(&unresolved, Path::new(dir)) {
+            plan.push(cmd);
+        }
+    }
+
+    // e.g. a sidecar of the same name next to the found file
+    let (plan, conflicts) = resolve_conflicts(&plan, ConflictPolicy::Skip)?;
+    for conflict in conflicts {
+        eprintln!("conflict: {}, skipped", conflict);
+    }
+    Ok(plan)
+}
+
+// arguments shared by all subcommands which execute a plan
+fn execution_args<'a, 'b>() -> Vec<Arg<'a, 'b>> {
+    vec![
+        Arg::with_name("move")
+            .long("move")
+            .help("move files instead of copying them"),
+        Arg::with_name("checksum")
+            .long("checksum")
+            .takes_value(true)
+            .value_name("ALGORITHM")
+            .possible_values(&["blake3", "sha256"])
+            .help("verify copies by hashing source and destination, and record the hashes \
+                   in the journal (see `verify`)"),
+        Arg::with_name("journal")
+            .long("journal")
+            .takes_value(true)
+            .value_name("FILE")
+            .help("where to record the executed operations (default: ./imgor-journal-<time>.jsonl)"),
+        Arg::with_name("on conflict")
+            .long("on-conflict")
+            .takes_value(true)
+            .value_name("POLICY")
+            .possible_values(&["abort", "skip", "suffix"])
+            .default_value("abort")
+            .help("what to do with photos which would overwrite files or break references: \
+                   refuse to run, leave them out, or append a number to their new names"),
+    ]
+}
+
+// arguments shared by all subcommands which scan directories for photos
+fn scan_args<'a, 'b>() -> Vec<Arg<'a, 'b>> {
+    vec![
+        Arg::with_name("recursive")
+            .short("r")
+            .long("recursive")
+            .help("also scan subdirectories"),
+        Arg::with_name("include")
+            .long("include")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .value_name("PATTERN")
+            .help("only consider files matching PATTERN (glob, may be repeated)"),
+        Arg::with_name("exclude")
+            .long("exclude")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .value_name("PATTERN")
+            .help("skip files and directories matching PATTERN (glob, may be repeated); \
+                   patterns are also read from `.imgorignore` files"),
+    ]
+}
+
+// `gazetteer` argument of subcommands which look up places
+fn gazetteer_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("gazetteer")
+        .long("gazetteer")
+        .takes_value(true)
+        .value_name("FILE")
+        .help("GeoNames dump (e.g. cities1000.txt) to look up the places of photos with coordinates \
+               (default: `places.gazetteer` from the configuration)")
+}
+
+// `journal` argument of subcommands which change metadata
+fn tag_journal_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("journal")
+        .long("journal")
+        .takes_value(true)
+        .value_name("FILE")
+        .help("where to record the previous values (default: ./imgor-journal-<time>.jsonl)")
+}
+
+fn scan_options(matches: &ArgMatches) -> imgor::Result<ScanOptions> {
+    let include: Vec<_> = matches.values_of("include").map(|v| v.collect()).unwrap_or_default();
+    let exclude: Vec<_> = matches.values_of("exclude").map(|v| v.collect()).unwrap_or_default();
+    ScanOptions::new(matches.is_present("recursive"), &include, &exclude)
+}
+
 fn run() -> imgor::Result<()> {
     let matches = App::new("imgor")
         .version("0.01")
@@ -218,64 +1067,494 @@ fn run() -> imgor::Result<()> {
             .short("n")
             .long("dry-run")
             .help("only print which commands would be executed"))
+        .arg(Arg::with_name("keep going")
+            .short("k")
+            .long("keep-going")
+            .help("skip files with unreadable or malformed metadata, and list them at the end, \
+                   instead of aborting"))
+        .arg(Arg::with_name("jobs")
+            .short("j")
+            .long("jobs")
+            .takes_value(true)
+            .value_name("N")
+            .help("number of files to read metadata from in parallel (default: number of CPUs)"))
+        .arg(Arg::with_name("config")
+            .long("config")
+            .takes_value(true)
+            .value_name("FILE")
+            .help("configuration file (default: ~/.config/imgor/config.toml)"))
+        .arg(Arg::with_name("pairing")
+            .long("pairing")
+            .takes_value(true)
+            .value_name("LIST")
+            .help("how to pair files shot together (e.g. RAW+JPEG) which do not refer to each other, \
+                   tried in order (comma separated): stem, capture_time, or none (default: all of them)"))
         .subcommand(SubCommand::with_name("group")
             .about("sort photos into groups")
+            .args(&scan_args())
+            .args(&execution_args())
+            .arg(Arg::with_name("output")
+                .short("o")
+                .long("output")
+                .takes_value(true)
+                .value_name("LIBRARY")
+                .help("directory to group the photos into, merging with existing day folders \
+                       (default: <DIRECTORY>/grouped)"))
+            .arg(Arg::with_name("folder template")
+                .long("folder-template")
+                .takes_value(true)
+                .value_name("TEMPLATE")
+                .help("folder names, e.g. `{year}/{date} {title}` or `{country}/{city}/{date}` \
+                       (default: `{date}`)"))
+            .arg(Arg::with_name("file template")
+                .long("name-template")
+                .takes_value(true)
+                .value_name("TEMPLATE")
+                .help("file names (without extension), e.g. `{date}_{seq:3}_{camera}` \
+                       (default: `{seq:4}_{date}`)"))
+            .arg(Arg::with_name("title")
+                .long("title")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .value_name("DATE=TITLE")
+                .help("title of the group of photos shot at DATE (YYYY-MM-DD), for `{title}`"))
+            .arg(gazetteer_arg())
+            .arg(Arg::with_name("timezone")
+                .long("timezone")
+                .takes_value(true)
+                .value_name("ZONE")
+                .help("zone of capture times which do not record one: `local` or an offset \
+                       like `+02:00` (default: `local`)"))
+            .arg(Arg::with_name("date sources")
+                .long("date-sources")
+                .takes_value(true)
+                .value_name("LIST")
+                .help("where to take capture dates from, tried in order (comma separated): \
+                       datetime_original, create_date, xmp, filename, folder, mtime \
+                       (default: all of them)"))
+            .arg(Arg::with_name("plan out")
+                .long("plan-out")
+                .takes_value(true)
+                .value_name("FILE")
+                .help("write the planned operations to FILE (JSON) instead of executing them"))
             .arg(Arg::with_name("DIRECTORY")
                 .help("directory containing the photos to be grouped")
                 .required(true)
                 .index(1)))
+        .subcommand(SubCommand::with_name("apply")
+            .about("execute a plan written by `group --plan-out`")
+            .args(&execution_args())
+            .arg(Arg::with_name("PLAN")
+                .help("plan file (JSON)")
+                .required(true)
+                .index(1)))
+        .subcommand(SubCommand::with_name("shift-time")
+            .about("shift the capture times of photos, e.g. to correct a wrongly set camera clock")
+            .args(&scan_args())
+            .arg(Arg::with_name("by")
+                .long("by")
+                .takes_value(true)
+                .allow_hyphen_values(true)
+                .required(true)
+                .value_name("DURATION")
+                .help("how much to shift the capture times, e.g. `+2h`, `-1h30m` or `-00:45`"))
+            .arg(Arg::with_name("camera")
+                .long("camera")
+                .takes_value(true)
+                .value_name("MODEL")
+                .help("only shift photos taken with this camera model"))
+            .arg(Arg::with_name("serial")
+                .long("serial")
+                .takes_value(true)
+                .value_name("SERIAL")
+                .help("only shift photos taken with the camera with this serial number"))
+            .arg(tag_journal_arg())
+            .arg(Arg::with_name("PATH")
+                .help("photos, or directories containing them")
+                .required(true)
+                .multiple(true)
+                .index(1)))
+        .subcommand(SubCommand::with_name("rate")
+            .about("set the rating of photos, in all their sidecars")
+            .args(&scan_args())
+            .arg(Arg::with_name("toggle")
+                .long("toggle")
+                .help("reset the rating to 0 where it already is RATING"))
+            .arg(tag_journal_arg())
+            .arg(Arg::with_name("RATING")
+                .help("0 to 5, `reject` or `clear`")
+                .required(true)
+                .index(1))
+            .arg(Arg::with_name("PATH")
+                .help("photos, or directories containing them")
+                .required(true)
+                .multiple(true)
+                .index(2)))
+        .subcommand(SubCommand::with_name("label")
+            .about("change the darktable color labels of photos, in all their sidecars")
+            // for `-red`
+            .setting(AppSettings::AllowLeadingHyphen)
+            .args(&scan_args())
+            .arg(tag_journal_arg())
+            .arg(Arg::with_name("LABELS")
+                .help("comma separated changes: `+red` (add), `-red` (remove), `red` (toggle) \
+                       or `clear`; colors are red, yellow, green, blue and magenta")
+                .required(true)
+                .allow_hyphen_values(true)
+                .index(1))
+            .arg(Arg::with_name("PATH")
+                .help("photos, or directories containing them")
+                .required(true)
+                .multiple(true)
+                .index(2)))
+        .subcommand(SubCommand::with_name("tag")
+            .about("add, remove or rename keywords of photos, in all their sidecars \
+                    (without changes: list them)")
+            .args(&scan_args())
+            .arg(Arg::with_name("add")
+                .long("add")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .value_name("KEYWORD")
+                .help("add KEYWORD, e.g. `portrait` or `places|germany|berlin` (may be repeated)"))
+            .arg(Arg::with_name("remove")
+                .long("remove")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .value_name("KEYWORD")
+                .help("remove KEYWORD and all keywords below it (may be repeated)"))
+            .arg(Arg::with_name("rename")
+                .long("rename")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .value_name("OLD=NEW")
+                .help("rename OLD, moving all keywords below it (may be repeated); \
+                       renames are applied first, then removals, then additions"))
+            .arg(Arg::with_name("places")
+                .long("places")
+                .help("add keywords for the places of photos with coordinates, \
+                       e.g. `places|Germany|Bavaria|Munich`"))
+            .arg(gazetteer_arg())
+            .arg(tag_journal_arg())
+            .arg(Arg::with_name("PATH")
+                .help("photos, or directories containing them")
+                .required(true)
+                .multiple(true)
+                .index(1)))
+        .subcommand(SubCommand::with_name("geotag")
+            .about("write the positions of photos along GPS tracks into their sidecars")
+            .args(&scan_args())
+            .arg(Arg::with_name("gpx")
+                .long("gpx")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .required(true)
+                .value_name("FILE")
+                .help("track recorded by a GPS logger (may be repeated)"))
+            .arg(Arg::with_name("max gap")
+                .long("max-gap")
+                .takes_value(true)
+                .value_name("DURATION")
+                .default_value("5m")
+                .help("how far apart track points may be to interpolate between them, \
+                       and how far away the nearest one may be otherwise"))
+            .arg(Arg::with_name("offset")
+                .long("offset")
+                .takes_value(true)
+                .allow_hyphen_values(true)
+                .value_name("DURATION")
+                .help("added to the capture times before matching, if the camera clock was off \
+                       (e.g. `+1m30s`)"))
+            .arg(Arg::with_name("timezone")
+                .long("timezone")
+                .takes_value(true)
+                .value_name("ZONE")
+                .help("zone of capture times which do not record one: `local` or an offset \
+                       like `+02:00` (default: `local`)"))
+            .arg(Arg::with_name("overwrite")
+                .long("overwrite")
+                .help("also geotag photos which already have a position"))
+            .arg(tag_journal_arg())
+            .arg(Arg::with_name("PATH")
+                .help("photos, or directories containing them")
+                .required(true)
+                .multiple(true)
+                .index(1)))
+        .subcommand(SubCommand::with_name("repair")
+            .about("fix the references of files to missing photos, or move such files aside")
+            .args(&scan_args())
+            .arg(Arg::with_name("quarantine")
+                .long("quarantine")
+                .takes_value(true)
+                .value_name("DIR")
+                .help("move the files whose photo cannot be found into DIR"))
+            .arg(Arg::with_name("yes")
+                .short("y")
+                .long("yes")
+                .help("do not ask for confirmation"))
+            .arg(Arg::with_name("journal")
+                .long("journal")
+                .takes_value(true)
+                .value_name("FILE")
+                .help("where to record the executed operations (default: ./imgor-journal-<time>.jsonl)"))
+            .arg(Arg::with_name("PATH")
+                .help("photos and their sidecars, or directories containing them")
+                .required(true)
+                .multiple(true)
+                .index(1)))
+        .subcommand(SubCommand::with_name("history")
+            .about("list the darktable operations applied to photos, e.g. to find cropped ones")
+            .args(&scan_args())
+            .arg(Arg::with_name("operation")
+                .long("operation")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .value_name("OPERATION")
+                .help("only list sidecars where OPERATION (e.g. `clipping`, `temperature`, \
+                       `exposure`) is in effect (may be repeated)"))
+            .arg(Arg::with_name("edited")
+                .long("edited")
+                .conflicts_with("untouched")
+                .help("only list sidecars with changes besides darktable's defaults"))
+            .arg(Arg::with_name("untouched")
+                .long("untouched")
+                .help("only list sidecars without changes besides darktable's defaults"))
+            .arg(Arg::with_name("PATH")
+                .help("sidecar files, or directories containing them")
+                .required(true)
+                .multiple(true)
+                .index(1)))
+        .subcommand(SubCommand::with_name("verify")
+            .about("check the files transferred in a previous run against the recorded checksums")
+            .arg(Arg::with_name("JOURNAL")
+                .help("journal written by a run with `--checksum`")
+                .required(true)
+                .index(1)))
+        .subcommand(SubCommand::with_name("undo")
+            .about("revert the operations recorded in a journal")
+            .arg(Arg::with_name("JOURNAL")
+                .help("journal written by a previous run")
+                .required(true)
+                .index(1)))
         .get_matches();
 
     let dry_run = matches.is_present("dry run");
+    let mut problems = Problems::new(matches.is_present("keep going"));
+    if matches.is_present("jobs") {
+        let jobs = value_t!(matches, "jobs", usize).unwrap_or_else(|e| e.exit());
+        rayon::ThreadPoolBuilder::new()
+            .num_threads(jobs)
+            .build_global()
+            .chain_err(|| "cannot start worker threads")?;
+    }
+    let config = load_config(matches.value_of("config").map(Path::new))?;
+    let types = FileTypes::new(&config.file_types).chain_err(|| "invalid file type in the configuration")?;
+    let pairing = match matches.value_of("pairing") {
+        Some(list) => parse_pairing_strategies(list)?,
+        None => config.pairing.strategies.iter()
+            .map(|s| s.parse())
+            .collect::<imgor::Result<_>>()?,
+    };
 
     if let Some(matches) = matches.subcommand_matches("group") {
         let from_dir = PathBuf::from(matches.value_of("DIRECTORY").unwrap());
-        let to_dir = from_dir.join("grouped");
+        let to_dir = matches.value_of("output")
+            .map(PathBuf::from)
+            .unwrap_or_else(|| from_dir.join("grouped"));
 
-        let cmds = group_files_by_date(&from_dir, &to_dir)?;
-        if dry_run {
-            for cmd in cmds {
-                match cmd {
-                    Cmd::Rename(ref src, ref dest) => {
-                        println!("rename     {}", print_rename(&src, &dest));
-                    },
-                    Cmd::CreateDirectory(dir) => {
-                        println!("create dir {}", dir.display());
-                    },
-                    Cmd::AdjustRef(ref file, ref referenced_image) => {
-                        let c = common_prefix(&file, &referenced_image);
-                        assert!(c.suffix1.components().count() == 1);
-                        assert!(c.suffix2.components().count() == 1);
-                        println!("adjust ref {} --> {}", file.display(), c.suffix2.display());
-                    }
+        let naming = NamingScheme::new(
+            matches.value_of("folder template").unwrap_or(&config.naming.folder),
+            matches.value_of("file template").unwrap_or(&config.naming.file))?;
+        let mut titles = HashMap::new();
+        for t in matches.values_of("title").into_iter().flatten() {
+            let mut parts = t.splitn(2, '=');
+            match (parts.next(), parts.next()) {
+                (Some(date), Some(title)) => titles.insert(date.to_string(), title.to_string()),
+                _ => bail!("invalid title '{}', expected DATE=TITLE", t),
+            };
+        }
+        let places = if naming.uses_places() {
+            Some(Places::require(matches, &config, "`{country}`, `{region}` and `{city}`")?)
+        } else {
+            None
+        };
+        let options = GroupOptions {
+            scan: scan_options(matches)?,
+            types: types.clone(),
+            pairing: pairing.clone(),
+            naming,
+            titles,
+            zone: matches.value_of("timezone").unwrap_or(&config.time.default_zone).parse()?,
+            date_sources: match matches.value_of("date sources") {
+                Some(list) => parse_date_sources(list)?,
+                None => config.time.date_sources.iter()
+                    .map(|s| s.parse())
+                    .collect::<imgor::Result<_>>()?,
+            },
+            places,
+        };
+
+        let plan = group_files_by_date(&from_dir, &to_dir, &options, &mut problems)?;
+        let plan = resolve_plan(&plan, matches)?;
+        if let Some(plan_file) = matches.value_of("plan out") {
+            write_plan(Path::new(plan_file), &plan)?;
+        } else {
+            run_cmds(&plan.cmds, matches, dry_run)?;
+        }
+    }
+
+    if let Some(matches) = matches.subcommand_matches("apply") {
+        let plan = read_plan(Path::new(matches.value_of("PLAN").unwrap()))?;
+        let plan = resolve_plan(&plan, matches)?;
+        run_cmds(&plan.cmds, matches, dry_run)?;
+    }
+
+    if let Some(matches) = matches.subcommand_matches("shift-time") {
+        let by = parse_duration(matches.value_of("by").unwrap())?;
+        let filter = CameraFilter {
+            model: matches.value_of("camera").map(String::from),
+            serial: matches.value_of("serial").map(String::from),
+        };
+        let files = time_shift_files(&collect_photos(matches, &types, &pairing, &mut problems)?, &types, &filter);
+        let edits = plan_time_shift(&files, by);
+        let count = run_tag_edits(&edits, "shift", matches, dry_run)?;
+        println!("{} file(s) shifted by {}", count, matches.value_of("by").unwrap());
+    }
+
+    if let Some(matches) = matches.subcommand_matches("rate") {
+        let rating = match matches.value_of("RATING").unwrap().parse()? {
+            RatingChange::Set(r) if matches.is_present("toggle") => RatingChange::Toggle(r),
+            rating => rating,
+        };
+        let photos = collect_photos(matches, &types, &pairing, &mut problems)?;
+        let edits = photo_edits(&photos, &mut problems, |p| plan_label_edits(p, Some(rating), &[]))?;
+        let count = run_tag_edits(&edits, "rate", matches, dry_run)?;
+        println!("{} file(s) rated", count);
+    }
+
+    if let Some(matches) = matches.subcommand_matches("label") {
+        let labels = parse_label_changes(matches.value_of("LABELS").unwrap())?;
+        let photos = collect_photos(matches, &types, &pairing, &mut problems)?;
+        let edits = photo_edits(&photos, &mut problems, |p| plan_label_edits(p, None, &labels))?;
+        let count = run_tag_edits(&edits, "label", matches, dry_run)?;
+        println!("{} file(s) labeled", count);
+    }
+
+    if let Some(matches) = matches.subcommand_matches("tag") {
+        let values = |name| matches.values_of(name).into_iter().flatten();
+        let mut changes = values("rename").map(KeywordChange::rename).collect::<imgor::Result<Vec<_>>>()?;
+        changes.extend(values("remove").map(|k| KeywordChange::Remove(k.to_string())));
+        changes.extend(values("add").map(|k| KeywordChange::Add(k.to_string())));
+
+        let places = if matches.is_present("places") {
+            Some(Places::require(matches, &config, "location keywords")?)
+        } else {
+            None
+        };
+
+        let photos = collect_photos(matches, &types, &pairing, &mut problems)?;
+        if changes.is_empty() && places.is_none() {
+            print_keywords(&photos, &mut problems)?;
+        } else {
+            let edits = photo_edits(&photos, &mut problems, |p| {
+                let mut changes = changes.clone();
+                if let Some(ref places) = places {
+                    changes.extend(places.locate(p)?.map(|place| KeywordChange::Add(place.keyword())));
                 }
+                plan_keyword_edits(p, &changes)
+            })?;
+            let count = run_tag_edits(&edits, "tag", matches, dry_run)?;
+            println!("{} file(s) tagged", count);
+        }
+    }
+
+    if let Some(matches) = matches.subcommand_matches("geotag") {
+        let gpx: Vec<_> = matches.values_of("gpx").unwrap().map(Path::new).collect();
+        let track = Track::read(&gpx)?;
+        if track.is_empty() {
+            bail!("no track points with time found");
+        }
+        let max_gap = parse_positive_duration(matches.value_of("max gap").unwrap())?;
+        let offset = match matches.value_of("offset") {
+            Some(o) => parse_duration(o)?,
+            None => Duration::zero(),
+        };
+        let zone: DefaultZone = matches.value_of("timezone").unwrap_or(&config.time.default_zone).parse()?;
+        let overwrite = matches.is_present("overwrite");
+
+        let photos = collect_photos(matches, &types, &pairing, &mut problems)?;
+        let edits = photo_edits(&photos, &mut problems, |p| {
+            match locate_photo(p, &types, &track, zone, offset, max_gap)? {
+                Some(position) => plan_geotag_edits(p, &position, overwrite),
+                None => Ok(vec![]),
             }
+        })?;
+        let count = run_tag_edits(&edits, "geotag", matches, dry_run)?;
+        println!("{} file(s) geotagged from {} track point(s)", count, track.len());
+    }
+
+    if let Some(matches) = matches.subcommand_matches("repair") {
+        let cmds = plan_repairs(matches, &types, &mut problems)?.cmds;
+        if cmds.is_empty() {
+            println!("nothing to repair");
         } else {
-            for cmd in cmds {
-                match cmd {
-                    Cmd::Rename(ref src, ref dest) => {
-                        std::fs::copy(&src, &dest)?;
-                    },
-                    Cmd::CreateDirectory(dir) => {
-                        if dir.exists() {
-                            panic!();
-                        }
-                        std::fs::create_dir_all(&dir)?;
-                    },
-                    Cmd::AdjustRef(ref file, ref referenced_image) => {
-                        let c = common_prefix(&file, &referenced_image);
-                        assert!(c.suffix1.components().count() == 1);
-                        assert!(c.suffix2.components().count() == 1);
-                        let derived_from = c.suffix2.to_str()
-                            .ok_or(ErrorKind::PathNotUtf8(c.suffix2.clone()))?;
-                        write_derivedfrom(&file, &derived_from);
-                    }
+            print_cmds(&cmds, TransferMode::Move);
+            if !dry_run && (matches.is_present("yes") || confirm("apply these changes?")?) {
+                execute_journaled(&cmds, TransferMode::Move, None, matches)?;
+            }
+        }
+    }
+
+    if let Some(matches) = matches.subcommand_matches("history") {
+        let paths: Vec<_> = matches.values_of("PATH").unwrap().map(PathBuf::from).collect();
+        let files = collect_paths(&paths, &scan_options(matches)?)?;
+        print_histories(&files, matches, &mut problems)?;
+    }
+
+    if let Some(matches) = matches.subcommand_matches("verify") {
+        let entries = read_journal(Path::new(matches.value_of("JOURNAL").unwrap()))?;
+        let results = verify(&entries)?;
+        let mut failed = 0;
+        for (file, verification) in &results {
+            match *verification {
+                Verification::Intact => {
+                    println!("{:<10}{}", "ok", file.display());
+                }
+                Verification::Modified => {
+                    println!("{:<10}{}", "MODIFIED", file.display());
+                    failed += 1;
+                }
+                Verification::Missing => {
+                    println!("{:<10}{}", "MISSING", file.display());
+                    failed += 1;
                 }
             }
         }
+        if failed > 0 {
+            bail!("{} of {} file(s) failed verification", failed, results.len());
+        }
+        println!("{} file(s) verified", results.len());
     }
 
-    Ok(())
+    if let Some(matches) = matches.subcommand_matches("undo") {
+        let journal_path = PathBuf::from(matches.value_of("JOURNAL").unwrap());
+        let entries = read_journal(&journal_path)?;
+        if dry_run {
+            for entry in entries.iter().rev() {
+                print_journal_entry(entry);
+            }
+        } else {
+            print_not_removed(&undo(&entries)?);
+        }
+    }
+
+    problems.report()
 }
 
 fn main() {
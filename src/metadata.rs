@@ -4,29 +4,63 @@
 extern crate chrono;
 extern crate rexiv2;
 
-use std::process::Command;
+use std::fmt;
 use std::path::{Path, PathBuf};
-use std::ascii::AsciiExt;
+use std::str::FromStr;
+use std::sync::Once;
 
 #[cfg(test)]
 use std::env;
 #[cfg(test)]
 use std::ffi::OsStr;
 
-use chrono::offset::TimeZone;
-use chrono::{UTC, DateTime};
+use gexiv2_sys;
+use chrono::NaiveDateTime;
 
-use errors::Result;
-
-static REXIV2_EXTENSIONS: &[&str] = &["jpg", "cr2"];
-static EXIFTOOL_EXTENSIONS: &[&str] = &["mov"];
+use capture::{CaptureTime, parse_iso_datetime, parse_offset};
+use darktable::{self, DarktableHistory};
+use gps::{self, Coordinates};
+use keywords::Keywords;
+use errors::*;
 
 static XMP_XMPMM_DERIVEDFROM: &str = &"Xmp.xmpMM.DerivedFrom";
-static XMP_XMP_RATING: &str = &"Xmp.xmp.Rating";
-static XMP_DARKTABLE_COLORLABELS: &str = &"Xmp.darktable.colorlabels";
+pub static XMP_XMP_RATING: &str = &"Xmp.xmp.Rating";
+pub static XMP_DARKTABLE_COLORLABELS: &str = &"Xmp.darktable.colorlabels";
+static XMP_DARKTABLE_MASK_ID: &str = "Xmp.darktable.mask_id";
+pub static XMP_DC_SUBJECT: &str = "Xmp.dc.subject";
+pub static XMP_LR_HIERARCHICALSUBJECT: &str = "Xmp.lr.hierarchicalSubject";
 static EXIF_PHOTO_DATETIMEORIGINAL: &str = &"Exif.Photo.DateTimeOriginal";
+static EXIF_PHOTO_OFFSETTIMEORIGINAL: &str = "Exif.Photo.OffsetTimeOriginal";
+static EXIF_PHOTO_OFFSETTIME: &str = "Exif.Photo.OffsetTime";
+static EXIF_PHOTO_DATETIMEDIGITIZED: &str = "Exif.Photo.DateTimeDigitized";
+static EXIF_PHOTO_OFFSETTIMEDIGITIZED: &str = "Exif.Photo.OffsetTimeDigitized";
+static XMP_PHOTOSHOP_DATECREATED: &str = "Xmp.photoshop.DateCreated";
+static XMP_XMP_CREATEDATE: &str = "Xmp.xmp.CreateDate";
+static EXIF_IMAGE_MODEL: &str = &"Exif.Image.Model";
+static EXIF_SERIAL_NUMBERS: &[&str] = &[
+    "Exif.Photo.BodySerialNumber",
+    "Exif.Canon.SerialNumber",
+    "Exif.Nikon3.SerialNumber",
+];
+
+static DARKTABLE_NAMESPACE: &str = "http://darktable.sf.net/";
+
+static INIT: Once = Once::new();
+
+/// Initializes exiv2 such that files may be read from several threads
+/// at once. Must be called before metadata is read in parallel.
+pub fn initialize() {
+    INIT.call_once(|| {
+        unsafe {
+            gexiv2_sys::gexiv2_initialize();
+        }
+        // exiv2 cannot write `Xmp.darktable.*` tags without knowing the
+        // namespace; fails if it is known already
+        let _ = rexiv2::register_xmp_namespace(DARKTABLE_NAMESPACE, "darktable");
+    });
+}
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum DarktableColor {
     Red,
     Yellow,
@@ -35,17 +69,49 @@ pub enum DarktableColor {
     Magenta,
 }
 
+// in the order of their index in `darktable:colorlabels`
+static DARKTABLE_COLORS: &[(DarktableColor, &str)] = &[
+    (DarktableColor::Red, "red"),
+    (DarktableColor::Yellow, "yellow"),
+    (DarktableColor::Green, "green"),
+    (DarktableColor::Blue, "blue"),
+    (DarktableColor::Magenta, "magenta"),
+];
+
+impl DarktableColor {
+    pub fn index(&self) -> usize {
+        DARKTABLE_COLORS.iter().position(|&(c, _)| c == *self).unwrap()
+    }
+}
+
+impl FromStr for DarktableColor {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<DarktableColor> {
+        match DARKTABLE_COLORS.iter().find(|&&(_, name)| name == s) {
+            Some(&(color, _)) => Ok(color),
+            None => bail!("unknown color label '{}' (expected red, yellow, green, blue or magenta)", s),
+        }
+    }
+}
+
 pub struct Metadata {
     path: PathBuf,
     meta: rexiv2::Metadata
 }
 
-fn parse_exif_datetime(datetime: &str) -> DateTime<UTC> {
+/// Parses an EXIF date, `None` if the camera did not know it.
+pub fn parse_exif_datetime(datetime: &str) -> Result<Option<NaiveDateTime>> {
     // http://www.awaresystems.be/imaging/tiff/tifftags/privateifd/exif/datetimeoriginal.html
-    // YYYY:MM:DD HH:MM:SS
-    chrono::UTC
-        .datetime_from_str(datetime, "%Y:%m:%d %H:%M:%S")
-        .unwrap()
+    // YYYY:MM:DD HH:MM:SS (local time, without time zone); unknown parts
+    // are left blank, cameras without a set clock write zeros
+    let datetime = datetime.trim_end_matches('\0');
+    if datetime.chars().all(|c| c == ' ' || c == ':' || c == '0') {
+        return Ok(None);
+    }
+    NaiveDateTime::parse_from_str(datetime, "%Y:%m:%d %H:%M:%S")
+        .map(Some)
+        .map_err(|_| ErrorKind::InvalidDateTime(datetime.to_string()).into())
 }
 
 impl Metadata {
@@ -57,71 +123,216 @@ impl Metadata {
         })
     }
 
+    fn invalid_tag(&self, tag: &str, value: &str) -> Error {
+        ErrorKind::InvalidTag(self.path.clone(), tag.to_string(), value.to_string()).into()
+    }
+
     // -1 means rejected
-    pub fn rating(&self) -> Option<i32> {
-        self.meta.get_tag_string(&XMP_XMP_RATING)
-            .unwrap()
-            .parse::<i32>().ok()
-    }
-
-    pub fn darktable_colorlabels(&self) -> Option<Vec<DarktableColor>> {
-        let colors = self.meta.get_tag_string(&XMP_DARKTABLE_COLORLABELS).unwrap();
-        let colors: Vec<i32> = colors
-            .split(',')
-            .map(str::trim)
-            .map(|s| s.parse::<i32>().unwrap())
-            .collect();
-
-        Some(colors.iter().map(
-            |number| match *number {
-                0 => DarktableColor::Red,
-                1 => DarktableColor::Yellow,
-                2 => DarktableColor::Green,
-                3 => DarktableColor::Blue,
-                4 => DarktableColor::Magenta,
-                _ => panic!(),
+    pub fn rating(&self) -> Result<Option<i32>> {
+        match self.meta.get_tag_string(XMP_XMP_RATING) {
+            Ok(rating) => {
+                let r = rating.trim().parse::<i32>()
+                    .map_err(|_| self.invalid_tag(XMP_XMP_RATING, &rating))?;
+                Ok(Some(r))
+            }
+            Err(_) => Ok(None),
+        }
+    }
+
+    pub fn camera_model(&self) -> Option<String> {
+        self.meta.get_tag_string(EXIF_IMAGE_MODEL)
+            .ok()
+            .map(|m| m.trim().to_string())
+            .filter(|m| !m.is_empty())
+    }
+
+    /// The value of any (single valued) tag, e.g. `Exif.Image.DateTime`.
+    pub fn tag_string(&self, tag: &str) -> Option<String> {
+        self.meta.get_tag_string(tag).ok()
+    }
+
+    pub fn camera_serial(&self) -> Option<String> {
+        EXIF_SERIAL_NUMBERS.iter()
+            .filter_map(|tag| self.meta.get_tag_string(tag).ok())
+            .map(|s| s.trim().to_string())
+            .find(|s| !s.is_empty())
+    }
+
+    pub fn darktable_colorlabels(&self) -> Result<Vec<DarktableColor>> {
+        let colors = match self.meta.get_tag_multiple_strings(XMP_DARKTABLE_COLORLABELS) {
+            Ok(colors) => colors,
+            Err(_) => return Ok(vec![]),
+        };
+        colors.iter()
+            .map(|s| {
+                s.trim().parse::<usize>().ok()
+                    .and_then(|i| DARKTABLE_COLORS.get(i))
+                    .map(|&(color, _)| color)
+                    .ok_or_else(|| ErrorKind::UnknownColorLabel(self.path.clone(), s.clone()).into())
             })
-            .collect())
+            .collect()
+    }
+
+    pub fn keywords(&self) -> Keywords {
+        let list = |tag| self.meta.get_tag_multiple_strings(tag).unwrap_or_default();
+        Keywords {
+            flat: list(XMP_DC_SUBJECT),
+            hierarchical: list(XMP_LR_HIERARCHICALSUBJECT),
+        }
+    }
+
+    /// The location from the XMP (sidecars) or EXIF GPS tags.
+    pub fn gps(&self) -> Result<Option<Coordinates>> {
+        if let Some(c) = gps::parse_xmp_coordinates(&self.path, |tag| self.meta.get_tag_string(tag).ok())? {
+            return Ok(Some(c));
+        }
+        Ok(self.meta.get_gps_info().map(|g| Coordinates {
+            latitude: g.latitude,
+            longitude: g.longitude,
+            altitude: Some(g.altitude),
+        }))
+    }
+
+    /// The editing history of a darktable sidecar, `None` for other files.
+    pub fn darktable_history(&self) -> Result<Option<DarktableHistory>> {
+        let masks = self.meta.get_tag_multiple_strings(XMP_DARKTABLE_MASK_ID)
+            .map(|m| m.len())
+            .unwrap_or(0);
+        darktable::parse_history(&self.path, |tag| self.meta.get_tag_string(tag).ok(), masks)
     }
 
-    pub fn datetime_original(&self) -> Option<DateTime<UTC>> {
-        self.meta.get_tag_string(&EXIF_PHOTO_DATETIMEORIGINAL)
-            .ok().map(|d| parse_exif_datetime(&d))
+    fn exif_capture_time(&self, tag: &str, offset_tags: &[&str]) -> Result<Option<CaptureTime>> {
+        let value = match self.meta.get_tag_string(tag) {
+            Ok(value) => value,
+            Err(_) => return Ok(None),
+        };
+        let local = match parse_exif_datetime(&value) {
+            Ok(Some(local)) => local,
+            Ok(None) => return Ok(None),
+            Err(_) => return Err(self.invalid_tag(tag, &value)),
+        };
+        let offset = offset_tags.iter()
+            .filter_map(|tag| self.meta.get_tag_string(tag).ok())
+            .filter_map(|o| parse_offset(&o))
+            .next();
+        Ok(Some(CaptureTime::new(local, offset)))
     }
-    
-    pub fn derived_from(&self) -> Option<PathBuf> {
-        let file_dir = self.path.parent().unwrap();
-        let file_str = self.path.to_str().unwrap();
 
-        let meta = try_opt!(rexiv2::Metadata::new_from_path(&file_str).ok());
-        
-        meta.get_tag_string(&XMP_XMPMM_DERIVEDFROM).ok().map(
-            |derived_from| file_dir.join(&derived_from)
-        )
+    /// `DateTimeOriginal`, with the offset from `OffsetTimeOriginal`
+    /// (or `OffsetTime`) if the camera recorded one (EXIF 2.31)
+    pub fn datetime_original(&self) -> Result<Option<CaptureTime>> {
+        self.exif_capture_time(EXIF_PHOTO_DATETIMEORIGINAL,
+                               &[EXIF_PHOTO_OFFSETTIMEORIGINAL, EXIF_PHOTO_OFFSETTIME])
     }
+
+    /// `DateTimeDigitized` (exiftool: `CreateDate`), e.g. the time a film was scanned
+    pub fn create_date(&self) -> Result<Option<CaptureTime>> {
+        self.exif_capture_time(EXIF_PHOTO_DATETIMEDIGITIZED,
+                               &[EXIF_PHOTO_OFFSETTIMEDIGITIZED, EXIF_PHOTO_OFFSETTIME])
+    }
+
+    /// `photoshop:DateCreated` or `xmp:CreateDate`, as written by many editors.
+    /// Dates without a day (`2017`, `2017-05`), which XMP allows, and other
+    /// values which are not a full date are skipped.
+    pub fn xmp_create_date(&self) -> Result<Option<CaptureTime>> {
+        for &tag in &[XMP_PHOTOSHOP_DATECREATED, XMP_XMP_CREATEDATE] {
+            if let Some(date) = self.meta.get_tag_string(tag).ok().and_then(|v| parse_iso_datetime(&v)) {
+                return Ok(Some(date));
+            }
+        }
+        Ok(None)
+    }
+
+    /// The file this one was derived from (e.g. the raw file of a sidecar),
+    /// resolved relative to this file's directory.
+    pub fn derived_from(&self) -> Result<Option<PathBuf>> {
+        let derived_from = match self.meta.get_tag_string(XMP_XMPMM_DERIVEDFROM) {
+            Ok(derived_from) => derived_from,
+            Err(_) => return Ok(None),
+        };
+        if derived_from.trim().is_empty() {
+            bail!(self.invalid_tag(XMP_XMPMM_DERIVEDFROM, &derived_from));
+        }
+        let file_dir = self.path.parent().unwrap_or_else(|| Path::new(""));
+        Ok(Some(file_dir.join(&derived_from)))
+    }
+}
+
+pub fn write_derivedfrom(file: &Path, derived_from: &str) -> Result<()> {
+    let meta = rexiv2::Metadata::new_from_path(file)?;
+    meta.set_tag_string(XMP_XMPMM_DERIVEDFROM, derived_from)?;
+    meta.save_to_file(file)?;
+    Ok(())
+}
+
+/// Value of a metadata tag: a single string, or a list for tags like
+/// `Xmp.dc.subject` which hold several values.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TagValue {
+    Text(String),
+    List(Vec<String>),
+}
+
+impl fmt::Display for TagValue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            TagValue::Text(ref text) => write!(f, "{}", text),
+            TagValue::List(ref list) => write!(f, "[{}]", list.join(", ")),
+        }
+    }
+}
+
+/// A planned change of a tag of `file`, with its current value (for undo).
+#[derive(Debug, Clone, PartialEq)]
+pub struct TagEdit {
+    pub file: PathBuf,
+    pub tag: String,
+    pub old: Option<TagValue>,
+    pub new: Option<TagValue>,
 }
 
-fn run_exiftool_and_get_create_date(file: &str) -> Option<DateTime<UTC>> {
-    // rexiv2 apparently does not deal with .MOV files
-    // We use the commandline `exiftool` to get at the information
-    let output = Command::new("exiftool")
-        .arg("-DateTimeOriginal")
-        .arg("-S")
-        .arg(&file)
-        .output()
-        .unwrap();
-    let stdout = String::from_utf8(output.stdout).unwrap();
-    let sep = stdout.find(":").unwrap();
-    let newline = stdout.rfind("\n").unwrap();
-    let datetime = &stdout[sep + 2..newline];
-    Some(parse_exif_datetime(&datetime))
+/// Sets (or, for `None`, removes) the given tags of `file`, saving it once.
+pub fn write_tags(file: &Path, tags: &[(&str, Option<&TagValue>)]) -> Result<()> {
+    initialize();
+    let meta = rexiv2::Metadata::new_from_path(file)?;
+    for &(tag, value) in tags {
+        match value {
+            Some(TagValue::Text(text)) => meta.set_tag_string(tag, text)?,
+            Some(TagValue::List(list)) if tag == XMP_DARKTABLE_COLORLABELS => {
+                // exiv2 does not know the type of darktable's tags, so the
+                // rdf:Seq is declared and its items are set one by one
+                meta.clear_tag(tag);
+                meta.set_tag_string(tag, "type=Seq")?;
+                for (i, item) in list.iter().enumerate() {
+                    meta.set_tag_string(&format!("{}[{}]", tag, i + 1), item)?;
+                }
+            }
+            Some(TagValue::List(list)) => {
+                let list: Vec<&str> = list.iter().map(String::as_str).collect();
+                meta.clear_tag(tag);
+                meta.set_tag_multiple_strings(tag, &list)?
+            }
+            None => {
+                meta.clear_tag(tag);
+            }
+        }
+    }
+    meta.save_to_file(file)?;
+    Ok(())
+}
+
+/// returns the raw (unresolved) `DerivedFrom` value stored in `file`
+pub fn read_derivedfrom(file: &Path) -> Result<Option<String>> {
+    let meta = rexiv2::Metadata::new_from_path(file)?;
+    Ok(meta.get_tag_string(XMP_XMPMM_DERIVEDFROM).ok())
 }
 
-pub fn write_derivedfrom(file: &Path, derived_from: &str) {
-    let file_str = file.to_str().unwrap();
-    let meta = rexiv2::Metadata::new_from_path(&file_str).unwrap();
-    meta.set_tag_string(&XMP_XMPMM_DERIVEDFROM, derived_from).unwrap();
-    meta.save_to_file(&file).unwrap();
+pub fn clear_derivedfrom(file: &Path) -> Result<()> {
+    let meta = rexiv2::Metadata::new_from_path(file)?;
+    meta.clear_tag(XMP_XMPMM_DERIVEDFROM);
+    meta.save_to_file(file)?;
+    Ok(())
 }
 
 #[cfg(test)]
@@ -142,24 +353,74 @@ fn test_extract_derivedfrom() {
     let xmp_file = base.join("IMG_7506.CR2.xmp");
     let derivedfrom_file = base.join("IMG_7506.CR2");
     let meta = Metadata::new(&xmp_file).unwrap();
-    let d = meta.derived_from();
+    let d = meta.derived_from().unwrap();
 
     assert_eq!(d.unwrap(), derivedfrom_file);
 }
 
-pub fn extract_datetime(path: &Path) -> Option<DateTime<UTC>> {
-    let ext = path.extension()
-        .unwrap()
-        .to_str()
-        .unwrap()
-        .to_ascii_lowercase();
-    let path_str = path.to_str().unwrap();
+#[test]
+fn test_darktable_history() {
+    let xmp_file = get_target_dir().parent().unwrap().join("test_data").join("IMG_7506.CR2.xmp");
+    let history = Metadata::new(&xmp_file).unwrap().darktable_history().unwrap().unwrap();
+
+    assert_eq!((history.items.len(), history.end, history.masks), (10, 10, 0));
+    let ops: Vec<_> = history.operations().iter().map(|i| i.operation.as_str()).collect();
+    assert_eq!(ops, vec!["sharpen", "flip", "basecurve", "clipping", "exposure", "levels",
+                         "temperature", "tonecurve"]);
+    assert!(history.applies("clipping"));
+    assert!(history.is_edited());
+}
+
+#[test]
+fn test_write_tags() {
+    use std::fs;
+    use fileops::make_test_dir;
+    use gps::plan_geotag_edits;
+    use keywords::{KeywordChange, plan_keyword_edits};
+    use labels::{LabelChange, RatingChange, plan_label_edits};
+    use photo::Photo;
 
-    if REXIV2_EXTENSIONS.iter().any(|&e| e == ext) {
-        let meta = Metadata::new(&path).unwrap();
-        return meta.datetime_original()
-    } else if EXIFTOOL_EXTENSIONS.iter().any(|&e| e == ext) {
-        return run_exiftool_and_get_create_date(path_str);
+    let dir = make_test_dir("metadata-write-tags");
+    let xmp_file = dir.join("IMG_7506.CR2.xmp");
+    fs::copy(get_target_dir().parent().unwrap().join("test_data").join("IMG_7506.CR2.xmp"), &xmp_file).unwrap();
+    let mut photo = Photo::new(dir.join("IMG_7506.CR2"));
+    photo.add_derived(xmp_file.clone());
+
+    let position = Coordinates { latitude: 52.51, longitude: 13.41, altitude: Some(34.0) };
+    let labels = [LabelChange::Add(DarktableColor::Red), LabelChange::Add(DarktableColor::Blue)];
+    let keywords = [KeywordChange::Add("places|germany|berlin".into())];
+    let mut edits = plan_label_edits(&photo, Some(RatingChange::Set(4)), &labels).unwrap();
+    edits.extend(plan_keyword_edits(&photo, &keywords).unwrap());
+    edits.extend(plan_geotag_edits(&photo, &position, false).unwrap());
+    assert!(edits.iter().all(|e| e.file == xmp_file));
+    let tags: Vec<_> = edits.iter().map(|e| (e.tag.as_str(), e.new.as_ref())).collect();
+    write_tags(&xmp_file, &tags).unwrap();
+
+    let meta = Metadata::new(&xmp_file).unwrap();
+    assert_eq!(meta.rating().unwrap(), Some(4));
+    assert_eq!(meta.darktable_colorlabels().unwrap(), vec![DarktableColor::Red, DarktableColor::Blue]);
+    assert_eq!(meta.keywords().flat, vec!["berlin".to_string()]);
+    assert_eq!(meta.keywords().hierarchical, vec!["places|germany|berlin".to_string()]);
+    let a = meta.gps().unwrap().unwrap();
+    assert!((a.latitude - 52.51).abs() < 1e-6 && (a.longitude - 13.41).abs() < 1e-6);
+    // the history is kept
+    assert!(meta.darktable_history().unwrap().unwrap().is_edited());
+
+    // never written to the raw file itself
+    let photo = Photo::new(dir.join("IMG_7506.CR2"));
+    assert!(plan_label_edits(&photo, Some(RatingChange::Set(4)), &[]).is_err());
+}
+
+#[test]
+fn test_parse_exif_datetime() {
+    use chrono::NaiveDate;
+    let e = NaiveDate::from_ymd(2017, 5, 12).and_hms(18, 30, 0);
+    assert_eq!(parse_exif_datetime("2017:05:12 18:30:00").unwrap(), Some(e));
+    assert_eq!(parse_exif_datetime("2017:05:12 18:30:00\0").unwrap(), Some(e));
+    assert_eq!(parse_exif_datetime("    :  :     :  :  ").unwrap(), None);
+    assert_eq!(parse_exif_datetime("0000:00:00 00:00:00").unwrap(), None);
+    match parse_exif_datetime("2017:13:12 18:30:00") {
+        Err(Error(ErrorKind::InvalidDateTime(ref d), _)) => assert_eq!(d, "2017:13:12 18:30:00"),
+        a => panic!("unexpected result {:?}", a),
     }
-    panic!();
 }
@@ -0,0 +1,239 @@
+// Copyright 2017 Thorben Kroeger.
+// Dual-licensed MIT and Apache 2.0 (see LICENSE files for details).
+
+// Pairing of files shot together, which do not refer to each other via
+// `DerivedFrom`: cameras set to RAW+JPEG write `IMG_1234.CR2` and
+// `IMG_1234.JPG`, phones write `IMG_1234.HEIC` and `IMG_1234.MOV` (live
+// photos), some cameras record voice memos as `IMG_1234.WAV`. The paired
+// files become companions of the main photo (the raw, if there is one),
+// and are renamed along with it.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use chrono::NaiveDateTime;
+use rayon::prelude::*;
+
+use errors::*;
+use filetypes::{FileKind, FileTypes};
+use metadata::Metadata;
+use photo::Photo;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PairingStrategy {
+    // same name up to the first dot, in the same folder
+    Stem,
+    // a raw and a developed file with the same `DateTimeOriginal` from the
+    // same camera (serial number), e.g. written to two memory cards
+    CaptureTime,
+}
+
+pub static DEFAULT_PAIRING: &[PairingStrategy] = &[PairingStrategy::Stem, PairingStrategy::CaptureTime];
+
+impl FromStr for PairingStrategy {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<PairingStrategy> {
+        match s.trim() {
+            "stem" => Ok(PairingStrategy::Stem),
+            "capture_time" => Ok(PairingStrategy::CaptureTime),
+            _ => bail!("invalid pairing strategy '{}' (expected stem or capture_time)", s.trim()),
+        }
+    }
+}
+
+impl fmt::Display for PairingStrategy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match *self {
+            PairingStrategy::Stem => "stem",
+            PairingStrategy::CaptureTime => "capture_time",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// Parses a comma separated list of strategies, e.g. `stem,capture_time`,
+/// or `none`.
+pub fn parse_pairing_strategies(s: &str) -> Result<Vec<PairingStrategy>> {
+    if s.trim() == "none" {
+        return Ok(vec![]);
+    }
+    s.split(',').map(|p| p.parse()).collect()
+}
+
+// which file of a pair is the main photo: the lowest rank
+fn rank(kind: FileKind) -> u8 {
+    match kind {
+        FileKind::Raw => 0,
+        FileKind::Developed => 1,
+        FileKind::Video => 2,
+        FileKind::Audio => 3,
+        FileKind::Sidecar => 4,
+    }
+}
+
+// folder and lowercase name up to the first dot
+fn stem_key(path: &Path) -> Option<(PathBuf, String)> {
+    let name = path.file_name()?.to_str()?;
+    let stem = name.split('.').next().unwrap_or(name);
+    Some((path.parent()?.to_path_buf(), stem.to_lowercase()))
+}
+
+/// Local capture time and camera serial number of a photo.
+pub type Shot = (NaiveDateTime, String);
+
+fn read_shot(path: &Path) -> Option<Shot> {
+    let meta = Metadata::new(path).ok()?;
+    // malformed dates are reported when dating the photos
+    let time = meta.datetime_original().ok()??;
+    Some((time.local, meta.camera_serial()?))
+}
+
+fn pair_photos_impl<F>(photos: Vec<Photo>, types: &FileTypes, strategies: &[PairingStrategy], shot: F)
+    -> Vec<Photo>
+where
+    F: Fn(&Path) -> Option<Shot> + Sync,
+{
+    let kinds: Vec<_> = photos.iter().map(|p| types.get(&p.source).map(|t| t.kind)).collect();
+    // `main[i]`: the photo which photo `i` becomes a companion of
+    let mut main: Vec<Option<usize>> = vec![None; photos.len()];
+    let mut has_companions = vec![false; photos.len()];
+
+    for strategy in strategies {
+        let free: Vec<usize> = (0..photos.len())
+            .filter(|&i| kinds[i].is_some() && main[i].is_none() && !has_companions[i])
+            .collect();
+        // candidate pairs, in order of `photos`
+        let mut groups: Vec<Vec<usize>> = vec![];
+        match *strategy {
+            PairingStrategy::Stem => {
+                let mut by_stem = HashMap::<(PathBuf, String), usize>::new();
+                for &i in &free {
+                    if let Some(key) = stem_key(&photos[i].source) {
+                        let g = *by_stem.entry(key).or_insert_with(|| {
+                            groups.push(vec![]);
+                            groups.len() - 1
+                        });
+                        groups[g].push(i);
+                    }
+                }
+            }
+            PairingStrategy::CaptureTime => {
+                let candidates: Vec<usize> = free.into_iter()
+                    .filter(|&i| matches!(kinds[i], Some(FileKind::Raw) | Some(FileKind::Developed)))
+                    .collect();
+                let shots: Vec<_> = candidates.par_iter().map(|&i| shot(&photos[i].source)).collect();
+                let mut by_shot = HashMap::<Shot, usize>::new();
+                for (&i, s) in candidates.iter().zip(shots) {
+                    if let Some(s) = s {
+                        let g = *by_shot.entry(s).or_insert_with(|| {
+                            groups.push(vec![]);
+                            groups.len() - 1
+                        });
+                        groups[g].push(i);
+                    }
+                }
+                // in a burst, several photos may be taken within a second
+                groups.retain(|g| {
+                    let raws = g.iter().filter(|&&i| kinds[i] == Some(FileKind::Raw)).count();
+                    raws == 1 && g.len() == 2
+                });
+            }
+        }
+
+        for mut group in groups.into_iter().filter(|g| g.len() > 1) {
+            group.sort_by_key(|&i| kinds[i].map(rank));
+            // e.g. `IMG_1234.CR2` and `IMG_1234.DNG`: no main photo
+            if kinds[group[0]].map(rank) == kinds[group[1]].map(rank) {
+                continue;
+            }
+            has_companions[group[0]] = true;
+            for &i in &group[1..] {
+                main[i] = Some(group[0]);
+            }
+        }
+    }
+
+    let mut companions: Vec<Vec<Photo>> = vec![vec![]; photos.len()];
+    let mut photos: Vec<_> = photos.into_iter().map(Some).collect();
+    for (i, m) in main.into_iter().enumerate() {
+        if let Some(m) = m {
+            companions[m].extend(photos[i].take());
+        }
+    }
+    photos.into_iter()
+        .zip(companions)
+        .filter_map(|(photo, mut companions)| {
+            photo.map(|mut p| {
+                p.companions.append(&mut companions);
+                p
+            })
+        })
+        .collect()
+}
+
+/// Pairs the photos which were shot together, see `PairingStrategy`.
+/// Strategies are tried in order; files which could belong to several
+/// photos are left alone.
+pub fn pair_photos(photos: Vec<Photo>, types: &FileTypes, strategies: &[PairingStrategy]) -> Vec<Photo> {
+    pair_photos_impl(photos, types, strategies, read_shot)
+}
+
+#[cfg(test)]
+fn test_photos(sources: &[&str]) -> Vec<Photo> {
+    sources.iter().map(|s| Photo::new(PathBuf::from(s))).collect()
+}
+
+#[test]
+fn test_parse_pairing_strategies() {
+    assert_eq!(parse_pairing_strategies("stem, capture_time").unwrap(), DEFAULT_PAIRING);
+    assert_eq!(parse_pairing_strategies("none").unwrap(), vec![]);
+    assert!(parse_pairing_strategies("stem,name").is_err());
+}
+
+#[test]
+fn test_pair_by_stem() {
+    let mut photos = test_photos(&["/a/IMG_1.JPG", "/a/IMG_1.CR2", "/a/IMG_1.WAV", "/a/IMG_2.HEIC", "/a/IMG_2.MOV",
+                                   "/a/IMG_3.cr2", "/a/IMG_3.dng", "/a/IMG_3.jpg", "/b/IMG_1.JPG", "/a/IMG_4.txt"]);
+    photos[0].add_derived(PathBuf::from("/a/IMG_1.JPG.xmp"));
+    let a = pair_photos_impl(photos.clone(), &FileTypes::default(), &[PairingStrategy::Stem], |_| None);
+
+    let mut raw = photos[1].clone();
+    raw.companions = vec![photos[0].clone(), photos[2].clone()];
+    let mut heic = photos[3].clone();
+    heic.companions = vec![photos[4].clone()];
+    let e = vec![raw, heic, photos[5].clone(), photos[6].clone(), photos[7].clone(), photos[8].clone(),
+                 photos[9].clone()];
+    assert_eq!(a, e);
+
+    let a = pair_photos_impl(photos.clone(), &FileTypes::default(), &[], |_| None);
+    assert_eq!(a, photos);
+}
+
+#[test]
+fn test_pair_by_capture_time() {
+    use chrono::NaiveDate;
+
+    let photos = test_photos(&["/card1/IMG_1.CR2", "/card2/IMG_1.JPG", "/card1/IMG_2.CR2", "/card2/IMG_2.JPG",
+                               "/card1/IMG_3.CR2", "/card1/IMG_4.CR2", "/card2/IMG_3.JPG", "/card2/IMG_5.JPG"]);
+    let at = |s| NaiveDate::from_ymd(2017, 5, 12).and_hms(10, 0, s);
+    let shot = |p: &Path| -> Option<Shot> {
+        let name = p.file_name().unwrap().to_str().unwrap();
+        match name {
+            "IMG_1.CR2" | "IMG_1.JPG" => Some((at(1), "123".into())),
+            // another camera
+            "IMG_2.CR2" => Some((at(2), "123".into())),
+            "IMG_2.JPG" => Some((at(2), "456".into())),
+            // burst
+            _ => Some((at(3), "123".into())),
+        }
+    };
+    let a = pair_photos_impl(photos.clone(), &FileTypes::default(), DEFAULT_PAIRING, shot);
+    let sources: Vec<_> = a.iter().map(|p| (p.source.to_str().unwrap(), p.companions.len())).collect();
+    assert_eq!(sources, vec![("/card1/IMG_1.CR2", 1), ("/card1/IMG_2.CR2", 0), ("/card2/IMG_2.JPG", 0),
+                             ("/card1/IMG_3.CR2", 0), ("/card1/IMG_4.CR2", 0), ("/card2/IMG_3.JPG", 0),
+                             ("/card2/IMG_5.JPG", 0)]);
+    assert_eq!(a[0].companions[0].source, PathBuf::from("/card2/IMG_1.JPG"));
+}
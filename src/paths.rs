@@ -37,4 +37,89 @@ pub fn common_prefix(path1: &Path, path2: &Path) -> CommonPrefix {
         suffix1: r1,
         suffix2: r2
     }
-}
\ No newline at end of file
+}
+
+/// Replaces the occurrences of `old` in `s` which are not part of a longer
+/// word or number (`1` in `DSC_1234`); `None` if there are none.
+pub fn replace_word(s: &str, old: &str, new: &str) -> Option<String> {
+    if old.is_empty() {
+        return None;
+    }
+    let mut result = String::new();
+    let mut last = 0;
+    for (i, _) in s.match_indices(old) {
+        let end = i + old.len();
+        let before = s[..i].chars().next_back();
+        let after = s[end..].chars().next();
+        if !before.is_some_and(char::is_alphanumeric) && !after.is_some_and(char::is_alphanumeric) {
+            result.push_str(&s[last..i]);
+            result.push_str(new);
+            last = end;
+        }
+    }
+    if last == 0 {
+        return None;
+    }
+    result.push_str(&s[last..]);
+    Some(result)
+}
+
+// `file_name` split into its stem and its (possibly multiple, e.g.
+// `.cr2.xmp`) extensions. The stem is at least `source_stem`, if the name
+// starts with it: `img.v1_01.cr2.xmp` for the source `img.v1.cr2`.
+fn split_file_name<'a>(file_name: &'a str, source_stem: &str) -> (&'a str, &'a str) {
+    let start = if starts_with_ignore_case(file_name, source_stem) { source_stem.len() } else { 0 };
+    match file_name[start..].find('.') {
+        Some(index) => file_name.split_at(start + index),
+        None => (file_name, ""),
+    }
+}
+
+fn starts_with_ignore_case(s: &str, prefix: &str) -> bool {
+    s.get(..prefix.len()).is_some_and(|p| p.eq_ignore_ascii_case(prefix))
+}
+
+/// returns the new filename of a file belonging to the source with stem
+/// `old`, when renaming the source to `new`, with lowercased extensions:
+/// - the source itself: `img.CR2` becomes `new.cr2`
+/// - anything after the source's stem is kept, e.g. darktable's duplicate
+///   index or an export suffix: `img_01.cr2.xmp`, `img-web.jpg` become
+///   `new_01.cr2.xmp`, `new-web.jpg`
+/// - names containing the stem as a word elsewhere: `edit of img.jpg`
+///   becomes `edit of new.jpg`
+/// - unrelated names are prefixed to tie them to the source:
+///   `DSC_1234-edit.jpg` becomes `new_DSC_1234-edit.jpg`
+pub fn make_new_filename(file_name: &str, old: &str, new: &str) -> String {
+    let (stem, ext) = split_file_name(file_name, old);
+    let new_stem = if starts_with_ignore_case(stem, old) {
+        format!("{}{}", new, &stem[old.len()..])
+    } else {
+        replace_word(stem, old, new).unwrap_or_else(|| format!("{}_{}", new, stem))
+    };
+    format!("{}{}", new_stem, ext.to_lowercase())
+}
+
+#[test]
+fn test_make_new_filename() {
+
+    let inputs = vec!["my_file.JPG", "my_file.CR2.JPG", "my_file.cr2.JPG", "my_file",
+                      "my_file_01.cr2.xmp", "MY_FILE_02.CR2.xmp", "my_file-web.jpg", "edit of my_file.tif",
+                      "DSC_1234-edit.jpg", "DSC_1234"];
+    let old = "my_file";
+    let new = "0000";
+
+    let a : Vec<_> = inputs.iter()
+        .map(|e| make_new_filename(e, &old, &new)).collect();
+    let e = vec!["0000.jpg", "0000.cr2.jpg", "0000.cr2.jpg", "0000",
+                 "0000_01.cr2.xmp", "0000_02.cr2.xmp", "0000-web.jpg", "edit of 0000.tif",
+                 "0000_DSC_1234-edit.jpg", "0000_DSC_1234"];
+
+    assert_eq!(a, e);
+
+    // a source stem with dots
+    assert_eq!(make_new_filename("img.v1.NEF", "img.v1", "0001"), "0001.nef");
+    assert_eq!(make_new_filename("img.v1_01.nef.xmp", "img.v1", "0001"), "0001_01.nef.xmp");
+    // not part of a longer number
+    assert_eq!(make_new_filename("DSC_1234.jpg", "1", "0001"), "0001_DSC_1234.jpg");
+    assert_eq!(make_new_filename("v 1 (1).jpg", "1", "x"), "v x (x).jpg");
+}
@@ -2,12 +2,26 @@
 // Dual-licensed MIT and Apache 2.0 (see LICENSE files for details).
 
 use std::path::{Path, PathBuf};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 
-use metadata::Metadata;
+use rayon::prelude::*;
+
+use metadata::{self, Metadata};
 use errors::*;
+use filetypes::{FileKind, FileTypes, MetadataBackend};
+use pairing::{PairingStrategy, pair_photos};
+use sidecars::{Owners, find_capture_one_sidecars};
+
+/// Files whose metadata could not be read, with the reason.
+pub type Unreadable = Vec<(PathBuf, Error)>;
 
-static MEDIA_EXTENSIONS: &[&str] = &["cr2", "jpg", "jpeg", "mov", "xmp"];
+/// A file whose `DerivedFrom` refers to a file which is not among the
+/// grouped ones, e.g. because the raw was renamed or deleted (see `repair`).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Orphan {
+    pub file: PathBuf,
+    pub derived_from: PathBuf,
+}
 
 #[derive(Debug, PartialEq, Eq)]
 struct File {
@@ -37,42 +51,54 @@ macro_rules! media_file_vec {
     }
 }
 
-fn classify_files_impl<F>(paths: &Vec<PathBuf>, derived_from: F) -> Result<Vec<File>>
+// returns the files among `paths` which are of a known type, and the files
+// whose `DerivedFrom` could not be read
+fn classify_files_impl<F>(paths: &Vec<PathBuf>, types: &FileTypes, derived_from: F)
+    -> (Vec<File>, Vec<(PathBuf, Error)>)
 where
-    F: Fn(&Path) -> Option<PathBuf>,
+    F: Fn(&Path) -> Result<Option<PathBuf>> + Sync,
 {
-    Ok(paths.iter()
-        .filter(|path| {
-            match path.extension() {
-                Some(e) => {
-                    let lowercase_ext = e.to_str()
-                        //.ok_or(ErrorKind::PathNotUtf8(path.to_path_buf()))? // FIXME
-                        .unwrap()
-                        .to_lowercase();
-                    MEDIA_EXTENSIONS.iter().any(|&e| e == lowercase_ext)
-                }
-                None => {
-                    // skip file without extension
-                    false
-                }
-            }
+    // the results are in the order of `paths`
+    let classified: Vec<_> = paths.par_iter()
+        .filter_map(|path| types.get(path).map(|t| (path, t)))
+        .map(|(path, t)| {
+            // only exiv2 reads XMP
+            let derived_from = if t.backend == MetadataBackend::Exiv2 {
+                derived_from(path)
+            } else {
+                Ok(None)
+            };
+            (path, derived_from)
         })
-        .map(|path| {
-            File { path: path.clone(), derived_from: derived_from(&path) }
-        }).collect())
+        .collect();
+
+    let mut files = vec![];
+    let mut problems = vec![];
+    for (path, derived_from) in classified {
+        match derived_from {
+            Ok(derived_from) => files.push(File { path: path.clone(), derived_from }),
+            Err(e) => problems.push((path.clone(), e)),
+        }
+    }
+    (files, problems)
 }
 
 #[test]
 fn test_classify_files() {
-    let paths = vec!["/a/1.jpg", "/a/1.cr2", "/a/x.mov", "/a/1.xmp", "/a/1.txt", "/a/2.JPG"]
+    let paths = vec!["/a/1.jpg", "/a/1.cr2", "/a/x.mov", "/a/1.xmp", "/a/1.txt", "/a/2.JPG", "/a/3.xmp", "/a/4",
+                     "/a/5.NEF", "/a/6.arw"]
         .iter()
         .map(|&e| PathBuf::from(e))
         .collect::<Vec<_>>();
-    let a = classify_files_impl(&paths, |path| {
-        if path == PathBuf::from("/a/1.jpg") || path == PathBuf::from("/a/1.xmp") {
-            Some(PathBuf::from("/a/1.cr2"))
+    let (a, problems) = classify_files_impl(&paths, &FileTypes::default(), |path| {
+        if path == PathBuf::from("/a/x.mov") {
+            panic!("DerivedFrom read from a video");
+        } else if path == PathBuf::from("/a/1.jpg") || path == PathBuf::from("/a/1.xmp") {
+            Ok(Some(PathBuf::from("/a/1.cr2")))
+        } else if path == PathBuf::from("/a/3.xmp") {
+            bail!(ErrorKind::InvalidTag(path.to_path_buf(), "Xmp.xmpMM.DerivedFrom".into(), "".into()))
         } else {
-            None
+            Ok(None)
         }
     });
     let e = media_file_vec![
@@ -80,83 +106,225 @@ fn test_classify_files() {
         "/a/1.cr2" => None,
         "/a/x.mov" => None,
         "/a/1.xmp" => Some("/a/1.cr2"),
-        "/a/2.JPG" => None
+        "/a/2.JPG" => None,
+        "/a/5.NEF" => None,
+        "/a/6.arw" => None
     ];
-    assert_eq!(a.unwrap(), e);
+    assert_eq!(a, e);
+    let problems: Vec<_> = problems.into_iter().map(|(p, _)| p).collect();
+    assert_eq!(problems, vec![PathBuf::from("/a/3.xmp")]);
+}
+
+#[test]
+fn test_classify_files_keeps_order() {
+    let paths = (0..1000)
+        .map(|i| PathBuf::from(format!("/a/{}.jpg", i)))
+        .collect::<Vec<_>>();
+    let (a, _) = classify_files_impl(&paths, &FileTypes::default(), |_| Ok(None));
+    let a = a.into_iter().map(|f| f.path).collect::<Vec<_>>();
+    assert_eq!(a, paths);
 }
 
-fn classify_files(paths: &Vec<PathBuf>) -> Result<Vec<File>> {
-    classify_files_impl(&paths, |path| {
+fn classify_files(paths: &Vec<PathBuf>, types: &FileTypes) -> (Vec<File>, Vec<(PathBuf, Error)>) {
+    metadata::initialize();
+    classify_files_impl(&paths, types, |path| {
         let meta = Metadata::new(&path);
         match meta {
             Ok(m) => {
                 m.derived_from()
             }    
             Err(_) => {
-                // cannot obtain `DerivedFrom` from .MOV file for example
-                // (or processed images without metadata)
-                None
+                // processed images without metadata, for example
+                Ok(None)
             }
         }
     })
 }
 
+/// A file derived from a photo or from another derived file (e.g. a JPG
+/// exported from a TIFF developed from the raw), with the files derived
+/// from it in turn.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Derived {
+    pub file: PathBuf,
+    pub derived: Vec<Derived>,
+}
+
+impl Derived {
+    pub fn new(file: PathBuf) -> Derived {
+        Derived { file, derived: vec![] }
+    }
+
+    // this file and all files derived from it, parents first
+    fn collect_files<'a>(&'a self, files: &mut Vec<&'a PathBuf>) {
+        files.push(&self.file);
+        for d in &self.derived {
+            d.collect_files(files);
+        }
+    }
+}
+
 // Represents a single photo file (e.g. a RAW file) together with
 // - any XMP sidecar files that may reference it (via XMP's DerivedFrom)
 // - any JPG files that may reference it (via XMP's DerivedFrom)
+// - the files derived from those in turn
+// - the sidecars of other raw developers (see `sidecars`)
+// - the files shot together with it, e.g. the JPG of a camera set to
+//   RAW+JPEG (see `pairing`), with their own derived files
 #[derive(Debug, Clone, PartialEq)]
 pub struct Photo {
     pub source: PathBuf,
-    pub derived: Vec<PathBuf>,
+    pub derived: Vec<Derived>,
+    pub attached: Vec<PathBuf>,
+    pub companions: Vec<Photo>,
 }
 
 impl Photo {
     pub fn new(file: PathBuf) -> Photo {
         Photo {
             source: file,
-            derived: Vec::<PathBuf>::new(),
+            derived: Vec::<Derived>::new(),
+            attached: vec![],
+            companions: vec![],
         }
     }
 
     pub fn add_derived(&mut self, file: PathBuf) {
-        self.derived.push(file);
+        self.derived.push(Derived::new(file));
+    }
+
+    /// The files derived from the source, directly or indirectly, parents
+    /// first; without those of the companions.
+    pub fn derived_files(&self) -> Vec<&PathBuf> {
+        let mut files = vec![];
+        for d in &self.derived {
+            d.collect_files(&mut files);
+        }
+        files
+    }
+
+    fn own_sidecars(&self) -> Vec<&PathBuf> {
+        self.derived_files()
+            .into_iter()
+            .filter(|d| d.extension().is_some_and(|e| e.eq_ignore_ascii_case("xmp")))
+            .collect()
+    }
+
+    /// The derived XMP sidecar files (one per darktable version), also
+    /// those of the companions.
+    pub fn sidecars(&self) -> Vec<&PathBuf> {
+        let mut sidecars = self.own_sidecars();
+        for c in &self.companions {
+            sidecars.extend(c.sidecars());
+        }
+        sidecars
+    }
+
+    /// The files to read ratings, labels and keywords from: darktable keeps
+    /// them in the sidecars, photos without sidecar in the file itself.
+    /// Companions are read along with the photo.
+    pub fn metadata_files(&self) -> Vec<PathBuf> {
+        let sidecars: Vec<_> = self.own_sidecars().into_iter().cloned().collect();
+        let mut files = if sidecars.is_empty() {
+            vec![self.source.clone()]
+        } else {
+            sidecars
+        };
+        for c in &self.companions {
+            files.extend(c.metadata_files());
+        }
+        files
+    }
+
+    /// The files to write ratings, labels and keywords to: the sidecars of
+    /// the photo and its companions. The photos themselves, e.g. raw files,
+    /// are never written to; a photo without sidecar is an error.
+    pub fn editable_files(&self) -> Result<Vec<PathBuf>> {
+        let mut files: Vec<_> = self.own_sidecars().into_iter().cloned().collect();
+        if files.is_empty() {
+            bail!(ErrorKind::NoSidecar(self.source.clone()));
+        }
+        for c in &self.companions {
+            files.extend(c.editable_files()?);
+        }
+        Ok(files)
+    }
+
+    /// All files of the photo: the source, derived and attached files, and
+    /// companions.
+    pub fn files(&self) -> Vec<PathBuf> {
+        let mut files = vec![self.source.clone()];
+        files.extend(self.derived_files().into_iter().cloned());
+        files.extend(self.attached.iter().cloned());
+        for c in &self.companions {
+            files.extend(c.files());
+        }
+        files
     }
 }
 
+// the files derived from `path`, directly or indirectly, in the order of
+// `children`; each one is added to `attached`
+fn derivations<'a>(path: &Path, children: &HashMap<&Path, Vec<&'a File>>, attached: &mut HashSet<&'a Path>)
+    -> Vec<Derived>
+{
+    let mut derived = vec![];
+    for file in children.get(path).into_iter().flatten() {
+        attached.insert(&file.path);
+        derived.push(Derived {
+            file: file.path.clone(),
+            derived: derivations(&file.path, children, attached),
+        });
+    }
+    derived
+}
 
-fn group_photo_files_impl(files: &Vec<File>) -> Vec<Photo> {
+// returns the photos, and the derived files which do not lead to a source
+// among `files`
+fn group_photo_files_impl(files: &Vec<File>, types: &FileTypes) -> (Vec<Photo>, Vec<Orphan>) {
     // 1.) first, create `Photo` instances for each RAW file found
     // 2.) associate all XMP and JPG files with the `Photo` instance
-    //     which has the corresponding RAW file as `Photo::source`
-    // 3.) remaining files become `Photo` instances of their own
+    //     which has the corresponding RAW file as `Photo::source`, and
+    //     files derived from those with them in turn
+    // 3.) attach the sidecars of other raw developers to the `Photo`
+    //     they are named after
+    // 4.) remaining files (whose chain of `DerivedFrom`s is broken, or
+    //     a cycle) are orphans
 
-    let mut h = HashMap::<&Path, Photo>::new();
+    let mut children = HashMap::<&Path, Vec<&File>>::new();
+    for file in files {
+        if let Some(ref derived_from) = file.derived_from {
+            children.entry(derived_from).or_default().push(file);
+        }
+    }
 
-    let mut files_used = vec![false; files.len()];
+    let mut h = HashMap::<&Path, Photo>::new();
+    let mut attached = HashSet::<&Path>::new();
 
-    // add all raw files
-    for (ref file, ref mut used) in izip!(files, &mut files_used) {
-        if file.is_source() {
-            h.insert(&file.path, Photo::new(file.path.clone()));
-            **used = true;
-        }
+    for file in files.iter().filter(|f| f.is_source()) {
+        let mut photo = Photo::new(file.path.clone());
+        photo.derived = derivations(&file.path, &children, &mut attached);
+        h.insert(&file.path, photo);
     }
 
-    // associate derived files with their corresponding source files
-    for (ref file, ref mut used) in izip!(files, &mut files_used) {
-        if !file.is_source() {
-            let derived_from = file.derived_from.as_ref().unwrap();
-            h.get_mut(derived_from.as_path())
-                .expect(&format!("referenced image {:?} does not exist", file.derived_from))
-                .add_derived(file.path.clone());
-            **used = true;
+    let is_sidecar = |path: &Path| types.get(path).is_some_and(|t| t.kind == FileKind::Sidecar);
+    let owners = Owners::new(h.keys().cloned().filter(|p| !is_sidecar(p)));
+    for file in files.iter().filter(|f| f.is_source() && is_sidecar(&f.path)) {
+        if let Some(owner) = owners.find(&file.path, types) {
+            h.remove(file.path.as_path());
+            h.get_mut(owner).unwrap().attached.push(file.path.clone());
         }
     }
 
+    let orphans = files.iter()
+        .filter(|f| !attached.contains(f.path.as_path()))
+        .filter_map(|f| f.derived_from.as_ref().map(|d| Orphan { file: f.path.clone(), derived_from: d.clone() }))
+        .collect();
+
     // flatten and sort by source path
     let mut result = h.values().cloned().collect::<Vec<Photo>>();
     result.sort_by(|ref a, ref b| a.source.cmp(&b.source));
-    result
+    (result, orphans)
 }
 
 #[cfg(test)]
@@ -169,7 +337,9 @@ macro_rules! photo {
             let derived : Vec<&str> = vec![$($y),*];
             Photo {
                 source: source,
-                derived: derived.iter().map(|e: &&str| PathBuf::from(e)).collect()
+                derived: derived.iter().map(|e: &&str| Derived::new(PathBuf::from(e))).collect(),
+                attached: vec![],
+                companions: vec![]
             }
         }
     }
@@ -189,7 +359,8 @@ fn test_group_photo_files_impl_1() {
         "/a/b/4.JPG" => None
     ];
 
-    let a = group_photo_files_impl(&f);
+    let (a, orphans) = group_photo_files_impl(&f, &FileTypes::default());
+    assert!(orphans.is_empty());
 
     // 1.) output is sorted
     // 2.) associations RAW <-> JPG are correct
@@ -214,7 +385,7 @@ fn test_group_photo_files_impl_2() {
         "/a/3.jpg"    => None
     ];
 
-    let a = group_photo_files_impl(&f);
+    let (a, _) = group_photo_files_impl(&f, &FileTypes::default());
     let e = vec![
         photo!("/a/1.cr2"; ["/a/1.jpg", "/a/1.xmp", "/a/1_v2.xmp"]),
         photo!("/a/2.mov"; []),
@@ -224,7 +395,101 @@ fn test_group_photo_files_impl_2() {
     assert_eq!(a, e);
 }
 
-pub fn group_photo_files(files: &Vec<PathBuf>) -> Result<Vec<Photo>> {
-    let classified = classify_files(&files)?;
-    Ok(group_photo_files_impl(&classified))
+#[test]
+fn test_group_photo_files_impl_orphans() {
+    let f = media_file_vec![
+        "/a/1.cr2"     => None,
+        "/a/1.cr2.xmp" => Some("/a/1.cr2"),
+        "/a/2.cr2.xmp" => Some("/a/2.cr2"),
+        "/a/3.jpg"     => Some("/a/3.CR2"),
+        "/a/3.cr2"     => None
+    ];
+
+    let (a, orphans) = group_photo_files_impl(&f, &FileTypes::default());
+    assert_eq!(a, vec![photo!("/a/1.cr2"; ["/a/1.cr2.xmp"]), photo!("/a/3.cr2"; [])]);
+    assert_eq!(orphans, vec![
+        Orphan { file: PathBuf::from("/a/2.cr2.xmp"), derived_from: PathBuf::from("/a/2.cr2") },
+        Orphan { file: PathBuf::from("/a/3.jpg"), derived_from: PathBuf::from("/a/3.CR2") },
+    ]);
+}
+
+#[test]
+fn test_group_photo_files_impl_chains() {
+    // TIFF developed from the raw, JPG exported from the TIFF
+    let f = media_file_vec![
+        "/a/1.jpg"      => Some("/a/1.tif"),
+        "/a/1.cr2"      => None,
+        "/a/1.tif"      => Some("/a/1.cr2"),
+        "/a/1.cr2.xmp"  => Some("/a/1.cr2"),
+        "/a/1.tif.xmp"  => Some("/a/1.tif"),
+        "/a/2.jpg"      => Some("/a/2.tif"),
+        "/a/2.tif"      => Some("/a/2.cr2"),
+        "/a/3.jpg"      => Some("/a/3.tif"),
+        "/a/3.tif"      => Some("/a/3.jpg")
+    ];
+
+    let (a, orphans) = group_photo_files_impl(&f, &FileTypes::default());
+    let derived = |file: &str, derived: Vec<Derived>| Derived { file: PathBuf::from(file), derived };
+    let mut e = photo!("/a/1.cr2"; []);
+    e.derived = vec![
+        derived("/a/1.tif", vec![derived("/a/1.jpg", vec![]), derived("/a/1.tif.xmp", vec![])]),
+        derived("/a/1.cr2.xmp", vec![]),
+    ];
+    assert_eq!(a, vec![e.clone()]);
+    let files: Vec<_> = e.derived_files().into_iter().map(|f| f.to_str().unwrap()).collect();
+    assert_eq!(files, vec!["/a/1.tif", "/a/1.jpg", "/a/1.tif.xmp", "/a/1.cr2.xmp"]);
+    assert_eq!(e.sidecars().len(), 2);
+
+    // a broken chain, and a cycle
+    let orphans: Vec<_> = orphans.iter().map(|o| o.file.to_str().unwrap()).collect();
+    assert_eq!(orphans, vec!["/a/2.jpg", "/a/2.tif", "/a/3.jpg", "/a/3.tif"]);
+}
+
+#[test]
+fn test_group_photo_files_impl_attached() {
+    let f = media_file_vec![
+        "/a/1.cr2"                         => None,
+        "/a/1.cr2.pp3"                     => None,
+        "/a/1.cr2.xmp"                     => Some("/a/1.cr2"),
+        "/a/CaptureOne/Settings/1.cr2.cos" => None,
+        "/a/2.nef"                         => None,
+        "/a/2.xmp"                         => None,
+        "/a/2.NEF.dop"                     => None,
+        "/a/3.xmp"                         => None
+    ];
+
+    let (a, _) = group_photo_files_impl(&f, &FileTypes::default());
+    let mut p1 = photo!("/a/1.cr2"; ["/a/1.cr2.xmp"]);
+    p1.attached = vec![PathBuf::from("/a/1.cr2.pp3"), PathBuf::from("/a/CaptureOne/Settings/1.cr2.cos")];
+    let mut p2 = photo!("/a/2.nef"; []);
+    p2.attached = vec![PathBuf::from("/a/2.xmp"), PathBuf::from("/a/2.NEF.dop")];
+    // without a photo of that name
+    let p3 = photo!("/a/3.xmp"; []);
+    assert_eq!(a, vec![p1, p2, p3]);
+}
+
+/// Photos grouped from files.
+#[derive(Debug, Default)]
+pub struct Grouped {
+    pub photos: Vec<Photo>,
+    // left out of `photos`
+    pub orphans: Vec<Orphan>,
+    pub unreadable: Unreadable,
+}
+
+/// Groups those of `files` which are of a type in `types` into photos,
+/// and pairs the ones shot together according to `pairing`. Files whose
+/// metadata cannot be read, and files referring to a file which is not
+/// among `files`, are left out and returned separately. Capture One's
+/// sidecars of the photos are added even if they are not among `files`.
+pub fn group_photo_files(files: &Vec<PathBuf>, types: &FileTypes, pairing: &[PairingStrategy])
+    -> Result<Grouped>
+{
+    // a non-recursive scan does not find Capture One's sidecars
+    let mut files = files.clone();
+    files.extend(find_capture_one_sidecars(&files));
+    let (classified, unreadable) = classify_files(&files, types);
+    let (photos, orphans) = group_photo_files_impl(&classified, types);
+    let photos = pair_photos(photos, types, pairing);
+    Ok(Grouped { photos, orphans, unreadable })
 }
\ No newline at end of file
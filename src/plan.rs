@@ -0,0 +1,255 @@
+// Copyright 2017 Thorben Kroeger.
+// Dual-licensed MIT and Apache 2.0 (see LICENSE files for details).
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+#[cfg(test)]
+use fileops::make_test_dir;
+
+use serde_json;
+
+use errors::*;
+use validate::photo_units;
+
+static PLAN_VERSION: u32 = 1;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Cmd {
+    CreateDirectory(PathBuf),
+    // copy or move, depending on the `TransferMode`
+    Rename(PathBuf, PathBuf),
+    // let the XMP metadata of the first file point to the second file
+    AdjustRef(PathBuf, PathBuf),
+    // in the sidecar of another raw developer (the first file), replace the
+    // references to the name of the second file by the name of the third
+    ReplaceRef(PathBuf, PathBuf, PathBuf),
+}
+
+/// `Cmd`s, each with the id of the photo it belongs to (`None` for
+/// `CreateDirectory`s). The commands of a photo, i.e. of its source,
+/// derived files, sidecars and companions, are skipped or renamed together
+/// when conflicts are resolved (see `validate`).
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Plan {
+    pub cmds: Vec<Cmd>,
+    pub photos: Vec<Option<usize>>,
+}
+
+impl Plan {
+    /// A plan whose photos are told by the references between `cmds`
+    /// alone; companions become photos of their own.
+    pub fn from_cmds(cmds: Vec<Cmd>) -> Plan {
+        let photos = photo_units(&cmds).into_iter()
+            .zip(&cmds)
+            .enumerate()
+            .map(|(i, (unit, cmd))| match *cmd {
+                Cmd::CreateDirectory(_) => None,
+                _ => Some(unit.unwrap_or(i)),
+            })
+            .collect();
+        Plan { cmds, photos }
+    }
+
+    fn push_with(&mut self, cmd: Cmd, photo: usize) {
+        self.photos.push(match cmd {
+            Cmd::CreateDirectory(_) => None,
+            _ => Some(photo),
+        });
+        self.cmds.push(cmd);
+    }
+
+    /// Appends the commands of one photo.
+    pub fn push_photo<I: IntoIterator<Item = Cmd>>(&mut self, cmds: I) {
+        // unique, as the plan only grows
+        let photo = self.cmds.len();
+        for cmd in cmds {
+            self.push_with(cmd, photo);
+        }
+    }
+
+    /// Appends a command which belongs to no other one.
+    pub fn push(&mut self, cmd: Cmd) {
+        self.push_photo(Some(cmd));
+    }
+}
+
+// On-disk representation of a `Plan`, as written by
+// `imgor group --plan-out` and read by `imgor apply`
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+struct PlanFile {
+    version: u32,
+    commands: Vec<Cmd>,
+    // missing in plans of earlier versions, see `Plan::from_cmds`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    photos: Vec<Option<usize>>,
+}
+
+pub fn write_plan(path: &Path, plan: &Plan) -> Result<()> {
+    let plan = PlanFile {
+        version: PLAN_VERSION,
+        commands: plan.cmds.clone(),
+        photos: plan.photos.clone(),
+    };
+    let file = fs::File::create(path)?;
+    serde_json::to_writer_pretty(file, &plan)?;
+    Ok(())
+}
+
+pub fn read_plan(path: &Path) -> Result<Plan> {
+    let file = fs::File::open(path)?;
+    let plan: PlanFile = serde_json::from_reader(file)?;
+    if plan.version != PLAN_VERSION {
+        bail!(ErrorKind::UnsupportedPlanVersion(plan.version));
+    }
+    if plan.photos.is_empty() {
+        return Ok(Plan::from_cmds(plan.commands));
+    }
+    if plan.photos.len() != plan.commands.len() {
+        bail!("invalid plan '{}': {} photo ids for {} commands", path.display(), plan.photos.len(),
+              plan.commands.len());
+    }
+    Ok(Plan { cmds: plan.commands, photos: plan.photos })
+}
+
+fn require_existing(path: &Path) -> Result<()> {
+    if !path.exists() {
+        bail!(ErrorKind::FileMissing(path.to_path_buf()));
+    }
+    Ok(())
+}
+
+fn require_free(path: &Path) -> Result<()> {
+    if path.exists() {
+        bail!(ErrorKind::DestinationExists(path.to_path_buf()));
+    }
+    Ok(())
+}
+
+/// Checks the parts of `cmd` which do not depend on the filesystem:
+/// references are file names, so an `AdjustRef` can only refer to a file
+/// in the same folder, a `ReplaceRef` to one in the folder of the sidecar
+/// or above it (Capture One keeps its sidecars in a subfolder).
+pub fn check_cmd(cmd: &Cmd) -> Result<()> {
+    match *cmd {
+        Cmd::AdjustRef(ref file, ref referenced) if file.parent() != referenced.parent() => {
+            bail!(ErrorKind::ReferenceToOtherFolder(file.clone(), referenced.clone()));
+        }
+        Cmd::ReplaceRef(ref file, _, ref referenced) => {
+            let dir = referenced.parent();
+            if !file.ancestors().skip(1).any(|a| Some(a) == dir) {
+                bail!(ErrorKind::ReferenceToOtherFolder(file.clone(), referenced.clone()));
+            }
+            Ok(())
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Checks whether `cmd` can be executed in the current state of the
+/// filesystem, i.e. whether all files it reads exist and all files it
+/// creates do not exist yet. Directories may exist already.
+pub fn check_preconditions(cmd: &Cmd) -> Result<()> {
+    check_cmd(cmd)?;
+    match *cmd {
+        Cmd::CreateDirectory(ref dir) => {
+            if dir.is_dir() {
+                Ok(())
+            } else {
+                require_free(dir)
+            }
+        }
+        Cmd::Rename(ref src, ref dest) => {
+            require_existing(src)?;
+            require_free(dest)
+        }
+        Cmd::AdjustRef(ref file, ref referenced) | Cmd::ReplaceRef(ref file, _, ref referenced) => {
+            require_existing(file)?;
+            require_existing(referenced)
+        }
+    }
+}
+
+#[test]
+fn test_plan_roundtrip() {
+    let dir = make_test_dir("plan-roundtrip");
+    let path = dir.join("plan.json");
+    let mut plan = Plan::default();
+    plan.push(Cmd::CreateDirectory(PathBuf::from("/out/2017-05-12")));
+    plan.push_photo(vec![
+        Cmd::Rename(PathBuf::from("/in/1.CR2"), PathBuf::from("/out/2017-05-12/x.cr2")),
+        Cmd::Rename(PathBuf::from("/in/1.cr2.xmp"), PathBuf::from("/out/2017-05-12/x.cr2.xmp")),
+        Cmd::AdjustRef(PathBuf::from("/out/2017-05-12/x.cr2.xmp"), PathBuf::from("/out/2017-05-12/x.cr2")),
+        Cmd::Rename(PathBuf::from("/in/1.CR2.pp3"), PathBuf::from("/out/2017-05-12/x.cr2.pp3")),
+        Cmd::ReplaceRef(PathBuf::from("/out/2017-05-12/x.cr2.pp3"), PathBuf::from("/in/1.CR2"),
+                        PathBuf::from("/out/2017-05-12/x.cr2")),
+        // a companion
+        Cmd::Rename(PathBuf::from("/in/1.JPG"), PathBuf::from("/out/2017-05-12/x.jpg")),
+    ]);
+    assert_eq!(plan.photos, vec![None, Some(1), Some(1), Some(1), Some(1), Some(1), Some(1)]);
+
+    write_plan(&path, &plan).unwrap();
+
+    assert_eq!(read_plan(&path).unwrap(), plan);
+}
+
+#[test]
+fn test_check_cmd() {
+    let cmd = |file: &str, referenced: &str| Cmd::AdjustRef(PathBuf::from(file), PathBuf::from(referenced));
+    assert!(check_cmd(&cmd("/a/x.cr2.xmp", "/a/x.cr2")).is_ok());
+    assert!(check_cmd(&cmd("/a/x.cr2.xmp", "/a/raw/x.cr2")).is_err());
+    assert!(check_cmd(&cmd("/a/raw/x.cr2.xmp", "/a/x.cr2")).is_err());
+
+    let cmd = |file: &str, referenced: &str| {
+        Cmd::ReplaceRef(PathBuf::from(file), PathBuf::from("/in/1.cr2"), PathBuf::from(referenced))
+    };
+    assert!(check_cmd(&cmd("/a/x.cr2.pp3", "/a/x.cr2")).is_ok());
+    assert!(check_cmd(&cmd("/a/CaptureOne/Settings/x.cr2.cos", "/a/x.cr2")).is_ok());
+    assert!(check_cmd(&cmd("/a/x.cr2.pp3", "/a/raw/x.cr2")).is_err());
+    assert!(check_cmd(&cmd("/a/x.cr2.pp3", "/b/x.cr2")).is_err());
+}
+
+#[test]
+fn test_read_plan_format() {
+    let dir = make_test_dir("plan-format");
+    let path = dir.join("plan.json");
+    fs::write(&path, r#"{
+        "version": 1,
+        "commands": [
+            {"create_directory": "/out/a"},
+            {"rename": ["/in/1.cr2", "/out/a/1.cr2"]},
+            {"adjust_ref": ["/out/a/1.cr2.xmp", "/out/a/1.cr2"]}
+        ]
+    }"#).unwrap();
+
+    let e = vec![
+        Cmd::CreateDirectory(PathBuf::from("/out/a")),
+        Cmd::Rename(PathBuf::from("/in/1.cr2"), PathBuf::from("/out/a/1.cr2")),
+        Cmd::AdjustRef(PathBuf::from("/out/a/1.cr2.xmp"), PathBuf::from("/out/a/1.cr2")),
+    ];
+    // photos told by the references
+    assert_eq!(read_plan(&path).unwrap(), Plan { cmds: e, photos: vec![None, Some(1), Some(1)] });
+
+    fs::write(&path, r#"{"version": 1, "commands": [{"create_directory": "/out/a"}], "photos": [null, 1]}"#)
+        .unwrap();
+    assert!(read_plan(&path).is_err());
+
+    fs::write(&path, r#"{"version": 2, "commands": []}"#).unwrap();
+    assert!(read_plan(&path).is_err());
+}
+
+#[test]
+fn test_check_preconditions() {
+    let dir = make_test_dir("plan-preconditions");
+    fs::write(dir.join("a.cr2"), b"a").unwrap();
+    fs::write(dir.join("b.cr2"), b"b").unwrap();
+
+    assert!(check_preconditions(&Cmd::Rename(dir.join("a.cr2"), dir.join("c.cr2"))).is_ok());
+    assert!(check_preconditions(&Cmd::Rename(dir.join("a.cr2"), dir.join("b.cr2"))).is_err());
+    assert!(check_preconditions(&Cmd::Rename(dir.join("x.cr2"), dir.join("c.cr2"))).is_err());
+    assert!(check_preconditions(&Cmd::CreateDirectory(dir.join("sub"))).is_ok());
+    assert!(check_preconditions(&Cmd::CreateDirectory(dir.clone())).is_ok());
+    assert!(check_preconditions(&Cmd::CreateDirectory(dir.join("a.cr2"))).is_err());
+    assert!(check_preconditions(&Cmd::AdjustRef(dir.join("a.cr2"), dir.join("x.cr2"))).is_err());
+}
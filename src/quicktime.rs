@@ -0,0 +1,403 @@
+// Copyright 2017 Thorben Kroeger.
+// Dual-licensed MIT and Apache 2.0 (see LICENSE files for details).
+
+// Reads the recording date of QuickTime/ISO-BMFF videos (MOV, MP4, M4V).
+//
+// The file is a sequence of atoms (boxes): a 32 bit big-endian size
+// (including the header; 1 means that a 64 bit size follows, 0 means that
+// the atom extends to the end of the file) and a four character type.
+// All metadata lives in the `moov` atom, which is read into memory; the
+// (large) media data is skipped.
+
+use std::convert::TryFrom;
+use std::fs;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::Path;
+
+#[cfg(test)]
+use chrono::{FixedOffset, NaiveDate};
+#[cfg(test)]
+use fileops::make_test_dir;
+
+use chrono::offset::TimeZone;
+use chrono::UTC;
+
+use capture::{CaptureTime, DefaultZone, parse_iso_datetime, parse_offset};
+use errors::*;
+use metadata::parse_exif_datetime;
+
+// seconds between 1904-01-01 (QuickTime epoch) and 1970-01-01
+const QUICKTIME_EPOCH_OFFSET: i64 = 2_082_844_800;
+
+// `moov` atoms are small (headers, sample tables and maybe a thumbnail);
+// anything bigger is most likely not a video file
+const MAX_MOOV_SIZE: u64 = 64 * 1024 * 1024;
+
+static APPLE_CREATIONDATE: &[u8] = b"com.apple.quicktime.creationdate";
+
+// EXIF tags
+const TAG_EXIF_IFD: u16 = 0x8769;
+const TAG_DATETIMEORIGINAL: u16 = 0x9003;
+const TAG_OFFSETTIME: u16 = 0x9010;
+const TAG_OFFSETTIMEORIGINAL: u16 = 0x9011;
+
+fn be_u16(data: &[u8]) -> u16 {
+    (u16::from(data[0]) << 8) | u16::from(data[1])
+}
+
+fn be_u32(data: &[u8]) -> u32 {
+    (u32::from(be_u16(data)) << 16) | u32::from(be_u16(&data[2..]))
+}
+
+fn be_u64(data: &[u8]) -> u64 {
+    (u64::from(be_u32(data)) << 32) | u64::from(be_u32(&data[4..]))
+}
+
+struct Atom<'a> {
+    kind: &'a [u8],
+    data: &'a [u8],
+}
+
+// Iterates over the atoms in `data`, stopping at the first malformed one.
+struct Atoms<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Iterator for Atoms<'a> {
+    type Item = Atom<'a>;
+
+    fn next(&mut self) -> Option<Atom<'a>> {
+        if self.data.len() < 8 {
+            return None;
+        }
+        let (size, header) = match be_u32(self.data) {
+            0 => (self.data.len() as u64, 8),
+            1 if self.data.len() >= 16 => (be_u64(&self.data[8..]), 16),
+            n => (u64::from(n), 8),
+        };
+        if size < header || size > self.data.len() as u64 {
+            return None;
+        }
+        let atom = Atom {
+            kind: &self.data[4..8],
+            data: &self.data[header as usize..size as usize],
+        };
+        self.data = &self.data[size as usize..];
+        Some(atom)
+    }
+}
+
+fn atoms<'a>(data: &'a [u8]) -> Atoms<'a> {
+    Atoms { data }
+}
+
+fn child<'a>(data: &'a [u8], kind: &[u8]) -> Option<&'a [u8]> {
+    atoms(data).find(|a| a.kind == kind).map(|a| a.data)
+}
+
+fn mvhd_creation_time(moov: &[u8], zone: DefaultZone) -> Option<CaptureTime> {
+    let mvhd = child(moov, b"mvhd")?;
+    // version (1 byte), flags (3 bytes), creation time (32 or 64 bit)
+    let seconds = match *mvhd.first()? {
+        0 if mvhd.len() >= 8 => i64::from(be_u32(&mvhd[4..])),
+        1 if mvhd.len() >= 12 => i64::try_from(be_u64(&mvhd[4..])).ok()?,
+        _ => return None,
+    };
+    if seconds == 0 {
+        // not set
+        return None;
+    }
+    // the only date which really is in UTC
+    let utc = UTC.timestamp_opt(seconds.checked_sub(QUICKTIME_EPOCH_OFFSET)?, 0).single()?;
+    Some(CaptureTime::from_utc(&utc, zone))
+}
+
+// `moov/meta` holds `keys` (the names) and `ilst` (the values, in atoms
+// whose type is the 1-based index into `keys`)
+fn apple_creation_date(moov: &[u8]) -> Option<CaptureTime> {
+    let mut meta = child(moov, b"meta")?;
+    if meta.len() >= 8 && &meta[4..8] != b"hdlr" {
+        // MP4 style `meta`, which starts with version and flags
+        meta = &meta[4..];
+    }
+
+    let keys = child(meta, b"keys")?;
+    if keys.len() < 8 {
+        return None;
+    }
+    let mut entries = &keys[8..];
+    let mut index = None;
+    for i in 1..=be_u32(&keys[4..]) {
+        if entries.len() < 8 {
+            return None;
+        }
+        let size = be_u32(entries) as usize;
+        if size < 8 || size > entries.len() {
+            return None;
+        }
+        // size, namespace, name
+        if &entries[8..size] == APPLE_CREATIONDATE {
+            index = Some(i);
+            break;
+        }
+        entries = &entries[size..];
+    }
+    let index = index?;
+
+    let item = atoms(child(meta, b"ilst")?).find(|a| be_u32(a.kind) == index)?;
+    let data = child(item.data, b"data")?;
+    // type indicator, locale, value (e.g. `2017-05-12T18:30:00+0200`)
+    let value = ::std::str::from_utf8(data.get(8..)?).ok()?;
+    parse_iso_datetime(value)
+}
+
+// `DateTimeOriginal` (and `OffsetTimeOriginal`) from the EXIF data of a JPEG
+fn jpeg_datetime_original(jpeg: &[u8]) -> Option<CaptureTime> {
+    if jpeg.get(..2)? != [0xff, 0xd8] {
+        return None;
+    }
+    let mut pos = 2;
+    let tiff = loop {
+        let marker = jpeg.get(pos..pos + 4)?;
+        if marker[0] != 0xff || marker[1] == 0xda {
+            // no more metadata segments
+            return None;
+        }
+        let len = be_u16(&marker[2..]) as usize;
+        let segment = jpeg.get(pos + 4..pos + 2 + len)?;
+        if marker[1] == 0xe1 && segment.starts_with(b"Exif\0\0") {
+            break &segment[6..];
+        }
+        pos += 2 + len;
+    };
+
+    let little_endian = match tiff.get(..2)? {
+        b"II" => true,
+        b"MM" => false,
+        _ => return None,
+    };
+    let u16_at = |d: &[u8]| if little_endian { u16::from(d[0]) | (u16::from(d[1]) << 8) } else { be_u16(d) };
+    let u32_at = |d: &[u8]| if little_endian {
+        u32::from(u16_at(d)) | (u32::from(u16_at(&d[2..])) << 16)
+    } else {
+        be_u32(d)
+    };
+    let find_entry = |ifd: usize, tag: u16| -> Option<&[u8]> {
+        let count = u16_at(tiff.get(ifd..ifd + 2)?) as usize;
+        (0..count)
+            .filter_map(|i| tiff.get(ifd + 2 + 12 * i..ifd + 14 + 12 * i))
+            .find(|e| u16_at(e) == tag)
+    };
+
+    let ifd0 = u32_at(tiff.get(4..8)?) as usize;
+    let exif_ifd = u32_at(&find_entry(ifd0, TAG_EXIF_IFD)?[8..]) as usize;
+    // ASCII values are stored in the entry itself if they fit into 4 bytes
+    let ascii = |tag: u16| -> Option<&str> {
+        let entry = find_entry(exif_ifd, tag)?;
+        let len = u32_at(&entry[4..]) as usize;
+        let value = if len <= 4 {
+            entry.get(8..8 + len)?
+        } else {
+            let offset = u32_at(&entry[8..]) as usize;
+            tiff.get(offset..offset + len)?
+        };
+        ::std::str::from_utf8(value).ok()
+    };
+
+    let local = parse_exif_datetime(ascii(TAG_DATETIMEORIGINAL)?).ok()??;
+    let offset = ascii(TAG_OFFSETTIMEORIGINAL)
+        .or_else(|| ascii(TAG_OFFSETTIME))
+        .and_then(|o| parse_offset(o.trim_end_matches('\0')));
+    Some(CaptureTime::new(local, offset))
+}
+
+// Canon cameras store a thumbnail JPEG, including EXIF data, in `moov/udta/CNTH/CNDA`
+fn canon_datetime_original(moov: &[u8]) -> Option<CaptureTime> {
+    let cnth = child(child(moov, b"udta")?, b"CNTH")?;
+    jpeg_datetime_original(child(cnth, b"CNDA")?)
+}
+
+fn moov_creation_date(moov: &[u8], zone: DefaultZone) -> Option<CaptureTime> {
+    // `mvhd` is last, as many cameras write the local time instead of UTC
+    // and some leave it at zero
+    apple_creation_date(moov)
+        .or_else(|| canon_datetime_original(moov))
+        .or_else(|| mvhd_creation_time(moov, zone))
+}
+
+// Finds the top-level `moov` atom and reads its content, skipping all other atoms.
+fn read_moov<R: Read + Seek>(r: &mut R) -> io::Result<Option<Vec<u8>>> {
+    let invalid = |msg: &str| io::Error::new(io::ErrorKind::InvalidData, msg.to_string());
+    loop {
+        let mut header = [0u8; 8];
+        match r.read_exact(&mut header) {
+            Ok(()) => {}
+            Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e),
+        }
+        let size = match be_u32(&header) {
+            // extends to the end of the file
+            0 => None,
+            1 => {
+                let mut large = [0u8; 8];
+                r.read_exact(&mut large)?;
+                Some(be_u64(&large).checked_sub(16).ok_or_else(|| invalid("invalid atom size"))?)
+            }
+            n => Some(u64::from(n).checked_sub(8).ok_or_else(|| invalid("invalid atom size"))?),
+        };
+
+        if &header[4..] == b"moov" {
+            let mut moov = vec![];
+            let limit = size.unwrap_or(MAX_MOOV_SIZE + 1);
+            r.take(limit).read_to_end(&mut moov)?;
+            if moov.len() as u64 > MAX_MOOV_SIZE || size.is_some_and(|s| s != moov.len() as u64) {
+                return Err(invalid("invalid `moov` atom"));
+            }
+            return Ok(Some(moov));
+        }
+        match size {
+            Some(size) => {
+                let size = i64::try_from(size).map_err(|_| invalid("invalid atom size"))?;
+                r.seek(SeekFrom::Current(size))?;
+            }
+            None => return Ok(None),
+        }
+    }
+}
+
+/// Returns the recording date of a QuickTime/MP4 video, or `None` if the
+/// file does not record one. Dates recorded in UTC only are converted to
+/// the local time of `zone`.
+pub fn creation_date(path: &Path, zone: DefaultZone) -> Result<Option<CaptureTime>> {
+    let mut file = fs::File::open(path)?;
+    let moov = read_moov(&mut file)
+        .chain_err(|| format!("cannot read video '{}'", path.display()))?;
+    Ok(moov.and_then(|moov| moov_creation_date(&moov, zone)))
+}
+
+#[cfg(test)]
+fn atom(kind: &[u8], content: &[u8]) -> Vec<u8> {
+    let mut a = vec![];
+    a.extend_from_slice(&[0, 0, 0, 0]);
+    a.extend_from_slice(kind);
+    a.extend_from_slice(content);
+    let size = a.len() as u32;
+    a[..4].copy_from_slice(&[(size >> 24) as u8, (size >> 16) as u8, (size >> 8) as u8, size as u8]);
+    a
+}
+
+#[cfg(test)]
+fn mvhd(seconds: u32) -> Vec<u8> {
+    let mut content = vec![0, 0, 0, 0];
+    content.extend_from_slice(&[(seconds >> 24) as u8, (seconds >> 16) as u8,
+                                (seconds >> 8) as u8, seconds as u8]);
+    content.extend_from_slice(&[0; 92]);
+    atom(b"mvhd", &content)
+}
+
+#[cfg(test)]
+fn mvhd_v1(seconds: u64) -> Vec<u8> {
+    let mut content = vec![1, 0, 0, 0];
+    content.extend((0..8).rev().map(|i| (seconds >> (8 * i)) as u8));
+    content.extend_from_slice(&[0; 104]);
+    atom(b"mvhd", &content)
+}
+
+#[cfg(test)]
+fn apple_meta(date: &str) -> Vec<u8> {
+    let mut keys = vec![0, 0, 0, 0, 0, 0, 0, 2];
+    for name in &[&b"com.apple.quicktime.make"[..], APPLE_CREATIONDATE] {
+        let mut key = atom(b"mdta", name);
+        key[3] = key.len() as u8;
+        keys.extend(key);
+    }
+    let value = [&[0, 0, 0, 1, 0, 0, 0, 0][..], date.as_bytes()].concat();
+    let ilst = [atom(&[0, 0, 0, 1], &atom(b"data", b"\0\0\0\x01\0\0\0\0Apple")),
+                atom(&[0, 0, 0, 2], &atom(b"data", &value))].concat();
+    let hdlr = atom(b"hdlr", &[0; 24]);
+    atom(b"meta", &[hdlr, atom(b"keys", &keys), atom(b"ilst", &ilst)].concat())
+}
+
+#[cfg(test)]
+fn exif_jpeg(datetime: &str) -> Vec<u8> {
+    // little endian TIFF: IFD0 at 8 with one entry (pointer to the EXIF IFD
+    // at 26), EXIF IFD with one entry (DateTimeOriginal, stored at 44)
+    let mut tiff = b"II\x2a\0\x08\0\0\0".to_vec();
+    tiff.extend_from_slice(&[1, 0, 0x69, 0x87, 4, 0, 1, 0, 0, 0, 26, 0, 0, 0, 0, 0, 0, 0]);
+    tiff.extend_from_slice(&[1, 0, 0x03, 0x90, 2, 0, 20, 0, 0, 0, 44, 0, 0, 0, 0, 0, 0, 0]);
+    tiff.extend_from_slice(datetime.as_bytes());
+    tiff.push(0);
+
+    let app1 = [&b"Exif\0\0"[..], &tiff].concat();
+    let len = app1.len() + 2;
+    [&[0xff, 0xd8, 0xff, 0xe0, 0, 4, 0, 0, 0xff, 0xe1, (len >> 8) as u8, len as u8][..],
+     &app1, &[0xff, 0xda]].concat()
+}
+
+#[cfg(test)]
+fn capture_time(hour: u32, offset: Option<i32>) -> CaptureTime {
+    let local = NaiveDate::from_ymd(2017, 5, 12).and_hms(hour, 30, 0);
+    CaptureTime::new(local, offset.map(FixedOffset::east))
+}
+
+#[cfg(test)]
+fn cest() -> DefaultZone {
+    DefaultZone::Fixed(FixedOffset::east(7200))
+}
+
+#[test]
+fn test_mvhd_creation_time() {
+    // 2017-05-12 18:30:00 UTC
+    let moov = mvhd(1_494_613_800 + QUICKTIME_EPOCH_OFFSET as u32);
+    assert_eq!(moov_creation_date(&moov, cest()), Some(capture_time(20, Some(7200))));
+    assert_eq!(moov_creation_date(&mvhd(0), cest()), None);
+
+    let moov = mvhd_v1(1_494_613_800 + QUICKTIME_EPOCH_OFFSET as u64);
+    assert_eq!(moov_creation_date(&moov, cest()), Some(capture_time(20, Some(7200))));
+    // out of range instead of overflowing
+    for &seconds in &[u64::MAX, i64::MAX as u64] {
+        assert_eq!(moov_creation_date(&mvhd_v1(seconds), cest()), None);
+    }
+}
+
+#[test]
+fn test_apple_creation_date() {
+    let moov = [mvhd(0), apple_meta("2017-05-12T20:30:00-0500")].concat();
+    assert_eq!(moov_creation_date(&moov, cest()), Some(capture_time(20, Some(-18000))));
+}
+
+#[test]
+fn test_canon_datetime_original() {
+    let cnth = atom(b"CNTH", &atom(b"CNDA", &exif_jpeg("2017:05:12 20:30:00")));
+    let moov = [mvhd(12345), atom(b"udta", &cnth)].concat();
+    assert_eq!(moov_creation_date(&moov, cest()), Some(capture_time(20, None)));
+}
+
+#[test]
+fn test_creation_date() {
+    let dir = make_test_dir("quicktime");
+
+    // `mdat` with a 64 bit size before `moov`, as written by most cameras
+    let mut mdat = vec![0, 0, 0, 1];
+    mdat.extend_from_slice(b"mdat");
+    mdat.extend_from_slice(&[0, 0, 0, 0, 0, 0, 0, 20]);
+    mdat.extend_from_slice(&[0xaa; 4]);
+    let video = [atom(b"ftyp", b"qt  \0\0\0\0"), mdat,
+                 atom(b"moov", &apple_meta("2017-05-12T20:30:00Z"))].concat();
+    let file = dir.join("MVI_0001.MOV");
+    fs::write(&file, &video).unwrap();
+    assert_eq!(creation_date(&file, cest()).unwrap(), Some(capture_time(20, Some(0))));
+
+    let file = dir.join("empty.mp4");
+    fs::write(&file, atom(b"ftyp", b"isom")).unwrap();
+    assert_eq!(creation_date(&file, cest()).unwrap(), None);
+
+    let file = dir.join("truncated.mp4");
+    fs::write(&file, &video[..video.len() - 4]).unwrap();
+    assert!(creation_date(&file, cest()).is_err());
+
+    let file = dir.join("huge.mp4");
+    fs::write(&file, [&[0, 0, 0, 1][..], b"mdat", &[0xff; 8]].concat()).unwrap();
+    let err = read_moov(&mut fs::File::open(&file).unwrap()).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+}
@@ -0,0 +1,329 @@
+// Copyright 2017 Thorben Kroeger.
+// Dual-licensed MIT and Apache 2.0 (see LICENSE files for details).
+
+// Repairing orphans: sidecars and developed files whose `DerivedFrom`
+// refers to a file which does not exist (any more), e.g. because the raw
+// was renamed by another tool, or the case of its extension changed when
+// it was copied from the memory card. The referenced file is looked for
+//
+// 1. by name ignoring case, in the referenced folder,
+// 2. by stem (the name up to the first dot) among the files of the same
+//    kind in the folders of the orphan and of the referenced file, their
+//    parents, the folders next to them and their subfolders,
+// 3. among the same files, by capture time (and camera serial number, if
+//    both files record one).
+//
+// Only unique matches are used. Orphans without one can be moved to a
+// quarantine folder instead. Files derived from an orphan, e.g. the sidecar
+// of an orphaned TIFF and the JPGs exported from it, are orphans as well;
+// they are repaired along with it.
+
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use chrono::NaiveDateTime;
+
+use capture::parse_iso_datetime;
+use filetypes::{FileKind, FileTypes};
+use metadata::Metadata;
+use photo::{Derived, Orphan};
+use plan::Cmd;
+
+// sidecars do not have `Exif.Photo.DateTimeOriginal`
+static XMP_EXIF_DATETIMEORIGINAL: &str = "Xmp.exif.DateTimeOriginal";
+
+/// How the file an orphan refers to was found.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MatchedBy {
+    Name,
+    Stem,
+    CaptureTime,
+}
+
+impl fmt::Display for MatchedBy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match *self {
+            MatchedBy::Name => "name",
+            MatchedBy::Stem => "stem",
+            MatchedBy::CaptureTime => "capture time",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// The file an orphan presumably refers to.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Repair {
+    pub orphan: Orphan,
+    pub target: PathBuf,
+    pub matched_by: MatchedBy,
+}
+
+/// Local capture time and camera serial number, if known.
+type Capture = (NaiveDateTime, Option<String>);
+
+fn read_capture(path: &Path) -> Option<Capture> {
+    let meta = Metadata::new(path).ok()?;
+    let time = match meta.datetime_original() {
+        Ok(Some(time)) => time,
+        _ => parse_iso_datetime(&meta.tag_string(XMP_EXIF_DATETIMEORIGINAL)?)?,
+    };
+    Some((time.local, meta.camera_serial()))
+}
+
+// lowercase name up to the first dot
+fn stem(path: &Path) -> Option<String> {
+    let name = path.file_name()?.to_str()?;
+    Some(name.split('.').next().unwrap_or(name).to_lowercase())
+}
+
+fn find_target_impl<F>(orphan: &Orphan, candidates: &[PathBuf], capture: F) -> Option<Repair>
+where
+    F: Fn(&Path) -> Option<Capture>,
+{
+    let repair = |matches: Vec<&PathBuf>, matched_by| match matches[..] {
+        [target] => Some(Repair { orphan: orphan.clone(), target: target.clone(), matched_by }),
+        _ => None,
+    };
+    let referenced = &orphan.derived_from;
+
+    let name = referenced.file_name()?;
+    let by_name = candidates.iter()
+        .filter(|c| c.parent() == referenced.parent())
+        .filter(|c| c.file_name().is_some_and(|n| n.eq_ignore_ascii_case(name)))
+        .collect();
+    if let Some(r) = repair(by_name, MatchedBy::Name) {
+        return Some(r);
+    }
+
+    let stem = stem(referenced);
+    let by_stem = candidates.iter().filter(|c| stem.is_some() && self::stem(c) == stem).collect();
+    if let Some(r) = repair(by_stem, MatchedBy::Stem) {
+        return Some(r);
+    }
+
+    let (time, serial) = capture(&orphan.file)?;
+    let by_time = candidates.iter()
+        .filter(|c| match capture(c) {
+            Some((t, s)) => t == time && (s.is_none() || serial.is_none() || s == serial),
+            None => false,
+        })
+        .collect();
+    repair(by_time, MatchedBy::CaptureTime)
+}
+
+// `read_dir` of the current folder for the parent of relative file names
+fn entries(dir: &Path) -> Vec<PathBuf> {
+    let listed = if dir.as_os_str().is_empty() { Path::new(".") } else { dir };
+    let mut entries: Vec<_> = fs::read_dir(listed).into_iter()
+        .flatten()
+        .filter_map(|e| e.ok())
+        .map(|e| dir.join(e.file_name()))
+        .collect();
+    entries.sort();
+    entries
+}
+
+// the folders of the orphan and of the file it refers to, their parents,
+// the folders next to them and their subfolders
+fn nearby_dirs(orphan: &Orphan) -> Vec<PathBuf> {
+    let mut dirs: Vec<PathBuf> = vec![];
+    for base in [orphan.file.parent(), orphan.derived_from.parent()].iter().flatten() {
+        let mut around = vec![base.to_path_buf()];
+        if let Some(parent) = base.parent() {
+            around.push(parent.to_path_buf());
+        }
+        for dir in around {
+            let subdirs = entries(&dir).into_iter().filter(|d| d.is_dir());
+            for d in Some(dir.clone()).into_iter().chain(subdirs) {
+                if !dirs.contains(&d) {
+                    dirs.push(d);
+                }
+            }
+        }
+    }
+    dirs
+}
+
+/// Looks for the file `orphan` presumably refers to, in the folders
+/// around it. `None` if there is no unique match.
+pub fn find_target(orphan: &Orphan, types: &FileTypes) -> Option<Repair> {
+    let kinds = match types.get(&orphan.derived_from) {
+        Some(t) => vec![t.kind],
+        None => vec![FileKind::Raw, FileKind::Developed],
+    };
+    let candidates: Vec<_> = nearby_dirs(orphan).iter()
+        .flat_map(|d| entries(d))
+        .filter(|f| *f != orphan.file && f.is_file())
+        .filter(|f| types.get(f).is_some_and(|t| kinds.contains(&t.kind)))
+        .collect();
+    find_target_impl(orphan, &candidates, read_capture)
+}
+
+fn derived_orphans_impl(file: &Path, orphans: &[Orphan], ancestors: &mut Vec<PathBuf>) -> Vec<Derived> {
+    // files referring to each other in a cycle
+    if ancestors.iter().any(|a| a == file) {
+        return vec![];
+    }
+    ancestors.push(file.to_path_buf());
+    let derived = orphans.iter()
+        .filter(|o| o.derived_from == file)
+        .map(|o| Derived { file: o.file.clone(), derived: derived_orphans_impl(&o.file, orphans, ancestors) })
+        .collect();
+    ancestors.pop();
+    derived
+}
+
+/// The files among `orphans` which are derived from `file`, directly or
+/// indirectly.
+pub fn derived_orphans(file: &Path, orphans: &[Orphan]) -> Vec<Derived> {
+    derived_orphans_impl(file, orphans, &mut vec![])
+}
+
+// moves `derived` into `dir`, each one referring to the file it was
+// derived from
+fn derived_move_commands(derived: &[Derived], parent: &Path, dir: &Path, cmds: &mut Vec<Cmd>) {
+    for d in derived {
+        let moved = match d.file.file_name() {
+            Some(name) => dir.join(name),
+            None => continue,
+        };
+        cmds.push(Cmd::Rename(d.file.clone(), moved.clone()));
+        cmds.push(Cmd::AdjustRef(moved.clone(), parent.to_path_buf()));
+        derived_move_commands(&d.derived, &moved, dir, cmds);
+    }
+}
+
+/// The commands repairing the reference of an orphan. Since references
+/// are relative to the folder of the file, orphans found to belong to a
+/// file in another folder are moved next to it, together with the files
+/// `derived` from them (see `derived_orphans`).
+pub fn plan_repair(repair: &Repair, derived: &[Derived]) -> Vec<Cmd> {
+    let orphan = &repair.orphan.file;
+    if orphan.parent() == repair.target.parent() {
+        return vec![Cmd::AdjustRef(orphan.clone(), repair.target.clone())];
+    }
+    let (moved, dir) = match (orphan.file_name(), repair.target.parent()) {
+        (Some(name), Some(dir)) => (dir.join(name), dir),
+        _ => return vec![],
+    };
+    let mut cmds = vec![
+        Cmd::Rename(orphan.clone(), moved.clone()),
+        Cmd::AdjustRef(moved.clone(), repair.target.clone()),
+    ];
+    derived_move_commands(derived, &moved, dir, &mut cmds);
+    cmds
+}
+
+/// The commands moving `orphans` into the folder `dir`.
+pub fn plan_quarantine(orphans: &[Orphan], dir: &Path) -> Vec<Cmd> {
+    if orphans.is_empty() {
+        return vec![];
+    }
+    let mut cmds = vec![Cmd::CreateDirectory(dir.to_path_buf())];
+    for o in orphans {
+        if let Some(name) = o.file.file_name() {
+            cmds.push(Cmd::Rename(o.file.clone(), dir.join(name)));
+        }
+    }
+    cmds
+}
+
+#[cfg(test)]
+fn orphan(file: &str, derived_from: &str) -> Orphan {
+    Orphan { file: PathBuf::from(file), derived_from: PathBuf::from(derived_from) }
+}
+
+#[test]
+fn test_find_target() {
+    use chrono::NaiveDate;
+
+    let candidates: Vec<_> = ["/a/IMG_1.cr2", "/a/raw/IMG_2.CR2", "/a/IMG_3.cr2", "/b/IMG_3.cr2", "/b/DSC_9.cr2"]
+        .iter()
+        .map(PathBuf::from)
+        .collect();
+    let at = |s| NaiveDate::from_ymd(2017, 5, 12).and_hms(10, 0, s);
+    let capture = |p: &Path| -> Option<Capture> {
+        match p.to_str().unwrap() {
+            "/a/x.cr2.xmp" | "/b/DSC_9.cr2" => Some((at(9), Some("123".into()))),
+            "/a/y.cr2.xmp" => Some((at(9), Some("456".into()))),
+            _ => Some((at(0), None)),
+        }
+    };
+    let found = |o: &Orphan| find_target_impl(o, &candidates, capture).map(|r| (r.target, r.matched_by));
+
+    assert_eq!(found(&orphan("/a/IMG_1.CR2.xmp", "/a/IMG_1.CR2")),
+               Some((PathBuf::from("/a/IMG_1.cr2"), MatchedBy::Name)));
+    assert_eq!(found(&orphan("/a/IMG_2.jpg", "/a/IMG_2.CR2")),
+               Some((PathBuf::from("/a/raw/IMG_2.CR2"), MatchedBy::Stem)));
+    // two raws with that stem
+    assert_eq!(found(&orphan("/c/IMG_3.cr2.xmp", "/c/IMG_3.cr2")), None);
+    assert_eq!(found(&orphan("/a/x.cr2.xmp", "/a/x.cr2")),
+               Some((PathBuf::from("/b/DSC_9.cr2"), MatchedBy::CaptureTime)));
+    // another camera
+    assert_eq!(found(&orphan("/a/y.cr2.xmp", "/a/y.cr2")), None);
+}
+
+#[test]
+fn test_plan_repair() {
+    use fileops::make_test_dir;
+
+    let dir = make_test_dir("repair");
+    for f in &["shoot/IMG_1.cr2.xmp", "shoot/raw/IMG_1.CR2", "shoot/jpg/IMG_2.jpg", "shoot/raw/IMG_2.cr2", "IMG_2.cr2"] {
+        let path = dir.join(f);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(&path, "").unwrap();
+    }
+    let types = FileTypes::default();
+
+    let o = orphan(dir.join("shoot/IMG_1.cr2.xmp").to_str().unwrap(), dir.join("shoot/IMG_1.cr2").to_str().unwrap());
+    let repair = find_target(&o, &types).unwrap();
+    assert_eq!(repair.target, dir.join("shoot/raw/IMG_1.CR2"));
+    assert_eq!(plan_repair(&repair, &[]), vec![
+        Cmd::Rename(dir.join("shoot/IMG_1.cr2.xmp"), dir.join("shoot/raw/IMG_1.cr2.xmp")),
+        Cmd::AdjustRef(dir.join("shoot/raw/IMG_1.cr2.xmp"), dir.join("shoot/raw/IMG_1.CR2")),
+    ]);
+
+    // in a folder next to the orphan's; two levels up is too far
+    let o = orphan(dir.join("shoot/jpg/IMG_2.jpg").to_str().unwrap(), dir.join("shoot/jpg/IMG_2.cr2").to_str().unwrap());
+    assert_eq!(find_target(&o, &types).unwrap().target, dir.join("shoot/raw/IMG_2.cr2"));
+
+    let o = orphan(dir.join("shoot/IMG_1.cr2.xmp").to_str().unwrap(), dir.join("shoot/IMG_1.cr2").to_str().unwrap());
+    let repair = Repair { orphan: o.clone(), target: dir.join("shoot/IMG_1.cr2"), matched_by: MatchedBy::Name };
+    assert_eq!(plan_repair(&repair, &[]), vec![Cmd::AdjustRef(o.file.clone(), dir.join("shoot/IMG_1.cr2"))]);
+
+    assert_eq!(plan_quarantine(&[o], &dir.join("orphans")), vec![
+        Cmd::CreateDirectory(dir.join("orphans")),
+        Cmd::Rename(dir.join("shoot/IMG_1.cr2.xmp"), dir.join("orphans/IMG_1.cr2.xmp")),
+    ]);
+}
+
+#[test]
+fn test_plan_repair_derived() {
+    let orphans = vec![
+        orphan("/a/IMG_1.tif", "/a/IMG_1.cr2"),
+        orphan("/a/IMG_1.tif.xmp", "/a/IMG_1.tif"),
+        orphan("/a/IMG_1.jpg", "/a/IMG_1.tif.xmp"),
+        orphan("/a/IMG_2.tif.xmp", "/a/IMG_2.tif"),
+        // referring to each other
+        orphan("/a/x.xmp", "/a/y.xmp"),
+        orphan("/a/y.xmp", "/a/x.xmp"),
+    ];
+    let derived = derived_orphans(Path::new("/a/IMG_1.tif"), &orphans);
+    assert_eq!(derived, vec![Derived {
+        file: PathBuf::from("/a/IMG_1.tif.xmp"),
+        derived: vec![Derived::new(PathBuf::from("/a/IMG_1.jpg"))],
+    }]);
+    assert_eq!(derived_orphans(Path::new("/a/x.xmp"), &orphans).len(), 1);
+
+    let repair = Repair { orphan: orphans[0].clone(), target: PathBuf::from("/b/IMG_1.CR2"), matched_by: MatchedBy::Stem };
+    assert_eq!(plan_repair(&repair, &derived), vec![
+        Cmd::Rename(PathBuf::from("/a/IMG_1.tif"), PathBuf::from("/b/IMG_1.tif")),
+        Cmd::AdjustRef(PathBuf::from("/b/IMG_1.tif"), PathBuf::from("/b/IMG_1.CR2")),
+        Cmd::Rename(PathBuf::from("/a/IMG_1.tif.xmp"), PathBuf::from("/b/IMG_1.tif.xmp")),
+        Cmd::AdjustRef(PathBuf::from("/b/IMG_1.tif.xmp"), PathBuf::from("/b/IMG_1.tif")),
+        Cmd::Rename(PathBuf::from("/a/IMG_1.jpg"), PathBuf::from("/b/IMG_1.jpg")),
+        Cmd::AdjustRef(PathBuf::from("/b/IMG_1.jpg"), PathBuf::from("/b/IMG_1.tif.xmp")),
+    ]);
+}
@@ -0,0 +1,250 @@
+// Copyright 2017 Thorben Kroeger.
+// Dual-licensed MIT and Apache 2.0 (see LICENSE files for details).
+
+use std::collections::HashSet;
+use std::fs;
+use std::io::{BufRead, BufReader};
+use std::path::{Path, PathBuf};
+
+#[cfg(test)]
+use fileops::make_test_dir;
+
+use glob::{MatchOptions, Pattern};
+
+use errors::*;
+
+// name of the file listing patterns to be excluded from scanning,
+// applying to the directory containing it and all its subdirectories
+pub static IGNORE_FILE: &str = ".imgorignore";
+
+// Patterns containing a `/` are matched against the path relative to the
+// directory they apply to, all others against the file name only
+// (like in `.gitignore`). A trailing `/` is ignored.
+#[derive(Debug)]
+pub struct ScanPattern {
+    pattern: Pattern,
+    anchored: bool,
+}
+
+impl ScanPattern {
+    pub fn new(pattern: &str) -> Result<ScanPattern> {
+        let pattern = pattern.trim_end_matches('/');
+        Ok(ScanPattern {
+            pattern: Pattern::new(pattern.trim_start_matches('/'))?,
+            anchored: pattern.contains('/'),
+        })
+    }
+
+    fn matches(&self, rel_path: &Path) -> bool {
+        let options = MatchOptions {
+            case_sensitive: true,
+            require_literal_separator: true,
+            require_literal_leading_dot: false,
+        };
+        if self.anchored {
+            self.pattern.matches_path_with(rel_path, options)
+        } else {
+            rel_path.file_name()
+                .and_then(|n| n.to_str())
+                .is_some_and(|n| self.pattern.matches_with(n, options))
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct ScanOptions {
+    pub recursive: bool,
+    // if not empty, only files matching at least one of these are collected
+    pub include: Vec<ScanPattern>,
+    // files and directories matching any of these are skipped
+    pub exclude: Vec<ScanPattern>,
+}
+
+impl ScanOptions {
+    pub fn new(recursive: bool, include: &[&str], exclude: &[&str]) -> Result<ScanOptions> {
+        Ok(ScanOptions {
+            recursive,
+            include: parse_patterns(include)?,
+            exclude: parse_patterns(exclude)?,
+        })
+    }
+}
+
+fn parse_patterns(patterns: &[&str]) -> Result<Vec<ScanPattern>> {
+    patterns.iter().map(|p| ScanPattern::new(p)).collect()
+}
+
+fn read_ignore_file(dir: &Path) -> Result<Vec<ScanPattern>> {
+    let path = dir.join(IGNORE_FILE);
+    if !path.is_file() {
+        return Ok(vec![]);
+    }
+    let mut patterns = vec![];
+    for line in BufReader::new(fs::File::open(&path)?).lines() {
+        let line = line?;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        patterns.push(ScanPattern::new(line)
+            .chain_err(|| format!("invalid pattern in '{}'", path.display()))?);
+    }
+    Ok(patterns)
+}
+
+// patterns read from an ignore file, together with the directory
+// they are relative to
+struct IgnoreRules {
+    base: PathBuf,
+    patterns: Vec<ScanPattern>,
+}
+
+struct Scanner<'a> {
+    root: &'a Path,
+    options: &'a ScanOptions,
+    // canonical paths of all directories entered so far,
+    // protecting against symlink loops
+    visited: HashSet<PathBuf>,
+    files: Vec<PathBuf>,
+}
+
+impl<'a> Scanner<'a> {
+    fn is_excluded(&self, path: &Path, rules: &[IgnoreRules]) -> bool {
+        let rel = path.strip_prefix(self.root).unwrap_or(path);
+        if self.options.exclude.iter().any(|p| p.matches(rel)) {
+            return true;
+        }
+        rules.iter().any(|r| {
+            let rel = path.strip_prefix(&r.base).unwrap_or(path);
+            r.patterns.iter().any(|p| p.matches(rel))
+        })
+    }
+
+    fn is_included(&self, path: &Path) -> bool {
+        let rel = path.strip_prefix(self.root).unwrap_or(path);
+        self.options.include.is_empty() || self.options.include.iter().any(|p| p.matches(rel))
+    }
+
+    fn scan_dir(&mut self, dir: &Path, rules: &mut Vec<IgnoreRules>) -> Result<()> {
+        if !self.visited.insert(fs::canonicalize(dir)?) {
+            return Ok(());
+        }
+
+        let patterns = read_ignore_file(dir)?;
+        let has_rules = !patterns.is_empty();
+        if has_rules {
+            rules.push(IgnoreRules { base: dir.to_path_buf(), patterns });
+        }
+
+        let mut entries = fs::read_dir(dir)?
+            .map(|e| e.map(|e| e.path()))
+            .collect::<::std::io::Result<Vec<_>>>()?;
+        entries.sort();
+
+        for path in entries {
+            if self.is_excluded(&path, rules) {
+                continue;
+            }
+            if path.is_file() {
+                if self.is_included(&path) {
+                    self.files.push(path);
+                }
+            } else if path.is_dir() && self.options.recursive {
+                self.scan_dir(&path, rules)?;
+            }
+        }
+
+        if has_rules {
+            rules.pop();
+        }
+        Ok(())
+    }
+}
+
+/// Returns the (sorted) files in `dir`, descending into subdirectories
+/// if `options.recursive` is set.
+pub fn collect_files(dir: &Path, options: &ScanOptions) -> Result<Vec<PathBuf>> {
+    let mut scanner = Scanner {
+        root: dir,
+        options,
+        visited: HashSet::new(),
+        files: vec![],
+    };
+    scanner.scan_dir(dir, &mut vec![])?;
+
+    let mut files = scanner.files;
+    files.sort();
+    Ok(files)
+}
+
+#[cfg(test)]
+fn touch(path: &Path) {
+    fs::create_dir_all(path.parent().unwrap()).unwrap();
+    fs::write(path, b"").unwrap();
+}
+
+#[cfg(test)]
+fn relative(dir: &Path, files: Vec<PathBuf>) -> Vec<String> {
+    files.iter()
+        .map(|f| f.strip_prefix(dir).unwrap().to_str().unwrap().to_string())
+        .collect()
+}
+
+#[test]
+fn test_collect_files() {
+    let dir = make_test_dir("scan-collect");
+    touch(&dir.join("a.jpg"));
+    touch(&dir.join("DCIM/100CANON/IMG_1.CR2"));
+    touch(&dir.join("DCIM/101CANON/IMG_2.CR2"));
+
+    let flat = collect_files(&dir, &ScanOptions::default()).unwrap();
+    assert_eq!(relative(&dir, flat), vec!["a.jpg"]);
+
+    let options = ScanOptions::new(true, &[], &[]).unwrap();
+    let recursive = collect_files(&dir, &options).unwrap();
+    assert_eq!(relative(&dir, recursive),
+               vec!["DCIM/100CANON/IMG_1.CR2", "DCIM/101CANON/IMG_2.CR2", "a.jpg"]);
+}
+
+#[test]
+fn test_collect_files_patterns() {
+    let dir = make_test_dir("scan-patterns");
+    touch(&dir.join("a.jpg"));
+    touch(&dir.join("b.txt"));
+    touch(&dir.join("DCIM/100CANON/IMG_1.CR2"));
+    touch(&dir.join("DCIM/100CANON/IMG_1.JPG"));
+    touch(&dir.join("DCIM/101CANON/IMG_2.CR2"));
+    touch(&dir.join("thumbs/a.jpg"));
+
+    let options = ScanOptions::new(true, &["*.CR2", "*.jpg"], &["thumbs", "DCIM/101CANON/"]).unwrap();
+    let files = collect_files(&dir, &options).unwrap();
+    assert_eq!(relative(&dir, files), vec!["DCIM/100CANON/IMG_1.CR2", "a.jpg"]);
+}
+
+#[test]
+fn test_collect_files_ignore_file() {
+    let dir = make_test_dir("scan-ignore-file");
+    touch(&dir.join("a.jpg"));
+    touch(&dir.join("b.jpg"));
+    touch(&dir.join("sub/c.jpg"));
+    touch(&dir.join("sub/d.jpg"));
+    touch(&dir.join("sub/tmp/e.jpg"));
+    fs::write(dir.join(IGNORE_FILE), "# comment\nb.jpg\n").unwrap();
+    fs::write(dir.join("sub").join(IGNORE_FILE), "tmp/\n/d.jpg\n").unwrap();
+
+    let options = ScanOptions::new(true, &["*.jpg"], &[]).unwrap();
+    let files = collect_files(&dir, &options).unwrap();
+    assert_eq!(relative(&dir, files), vec!["a.jpg", "sub/c.jpg"]);
+}
+
+#[cfg(unix)]
+#[test]
+fn test_collect_files_symlink_loop() {
+    let dir = make_test_dir("scan-symlink-loop");
+    touch(&dir.join("sub/a.jpg"));
+    ::std::os::unix::fs::symlink(&dir, dir.join("sub/loop")).unwrap();
+
+    let options = ScanOptions::new(true, &[], &[]).unwrap();
+    let files = collect_files(&dir, &options).unwrap();
+    assert_eq!(relative(&dir, files), vec!["sub/a.jpg"]);
+}
@@ -0,0 +1,226 @@
+// Copyright 2017 Thorben Kroeger.
+// Dual-licensed MIT and Apache 2.0 (see LICENSE files for details).
+
+// Shifting the capture timestamps of files, e.g. to correct a camera clock
+// which was set to the wrong time zone.
+
+use std::path::{Path, PathBuf};
+
+use chrono::{Duration, NaiveDateTime};
+
+use errors::*;
+use metadata::{Metadata, TagEdit, TagValue};
+
+// date tags in EXIF format (`YYYY:MM:DD HH:MM:SS`)
+static EXIF_DATE_TAGS: &[&str] = &[
+    "Exif.Photo.DateTimeOriginal",
+    "Exif.Photo.DateTimeDigitized",
+    "Exif.Image.DateTime",
+];
+
+// date tags in ISO 8601 format (`YYYY-MM-DDTHH:MM[:SS][.fff][offset]`)
+static XMP_DATE_TAGS: &[&str] = &[
+    "Xmp.exif.DateTimeOriginal",
+    "Xmp.exif.DateTimeDigitized",
+    "Xmp.photoshop.DateCreated",
+    "Xmp.xmp.CreateDate",
+];
+
+const EXIF_FORMAT: &str = "%Y:%m:%d %H:%M:%S";
+
+// a shift by more than 100 years is a typo rather than a wrong clock
+const MAX_SECONDS: i64 = 100 * 366 * 86400;
+
+/// Parses a signed duration, either with units (`+2h`, `-1h30m`, `+1d12h`,
+/// `-45s`) or as `+HH:MM[:SS]`. Durations beyond 100 years are rejected.
+pub fn parse_duration(s: &str) -> Result<Duration> {
+    let invalid = || -> Error {
+        format!("invalid duration '{}' (expected e.g. `+2h`, `-1h30m` or `-01:30`)", s).into()
+    };
+    let (sign, rest) = match s.chars().next() {
+        Some('+') => (1, &s[1..]),
+        Some('-') => (-1, &s[1..]),
+        _ => return Err(invalid()),
+    };
+    if rest.is_empty() {
+        return Err(invalid());
+    }
+
+    let seconds = if rest.contains(':') {
+        let parts = rest.split(':')
+            .map(|p| if p.is_empty() || !p.chars().all(|c| c.is_ascii_digit()) {
+                None
+            } else {
+                p.parse::<i64>().ok()
+            })
+            .collect::<Option<Vec<_>>>()
+            .ok_or_else(invalid)?;
+        let (h, m, sec) = match parts[..] {
+            [h, m] if m < 60 => (h, m, 0),
+            [h, m, sec] if m < 60 && sec < 60 => (h, m, sec),
+            _ => return Err(invalid()),
+        };
+        h.checked_mul(3600)
+            .and_then(|h| h.checked_add(m * 60 + sec))
+            .ok_or_else(invalid)?
+    } else {
+        let mut seconds = 0i64;
+        let mut number = String::new();
+        for c in rest.chars() {
+            if c.is_ascii_digit() {
+                number.push(c);
+                continue;
+            }
+            let unit = match c {
+                'd' => 86400,
+                'h' => 3600,
+                'm' => 60,
+                's' => 1,
+                _ => return Err(invalid()),
+            };
+            seconds = number.parse::<i64>().ok()
+                .and_then(|n| n.checked_mul(unit))
+                .and_then(|n| n.checked_add(seconds))
+                .ok_or_else(invalid)?;
+            number.clear();
+        }
+        if !number.is_empty() {
+            return Err(invalid());
+        }
+        seconds
+    };
+    if seconds > MAX_SECONDS {
+        bail!("duration '{}' is too long (at most 100 years)", s);
+    }
+    Ok(Duration::seconds(sign * seconds))
+}
+
+/// Parses a duration which is not negative; the sign may be left out (`5m`).
+pub fn parse_positive_duration(s: &str) -> Result<Duration> {
+    let signed = if s.starts_with('+') || s.starts_with('-') {
+        s.to_string()
+    } else {
+        format!("+{}", s)
+    };
+    match parse_duration(&signed) {
+        Ok(d) if d >= Duration::zero() => Ok(d),
+        _ => bail!("invalid duration '{}' (expected e.g. `30s`, `5m` or `00:05`)", s),
+    }
+}
+
+fn shift_exif(value: &str, by: Duration) -> Option<String> {
+    let datetime = NaiveDateTime::parse_from_str(value.trim_end_matches('\0'), EXIF_FORMAT).ok()?;
+    Some(datetime.checked_add_signed(by)?.format(EXIF_FORMAT).to_string())
+}
+
+// keeps the precision and any fraction of seconds and offset
+fn shift_iso(value: &str, by: Duration) -> Option<String> {
+    for &(len, format) in &[(19, "%Y-%m-%dT%H:%M:%S"), (16, "%Y-%m-%dT%H:%M")] {
+        let datetime = value.get(..len)
+            .and_then(|v| NaiveDateTime::parse_from_str(v, format).ok());
+        if let Some(datetime) = datetime {
+            let shifted = datetime.checked_add_signed(by)?;
+            return Some(format!("{}{}", shifted.format(format), &value[len..]));
+        }
+    }
+    // a date without time cannot be shifted by less than a day
+    None
+}
+
+/// A single corrected tag.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TagChange {
+    pub tag: &'static str,
+    pub old: String,
+    pub new: String,
+}
+
+/// Computes the changed date tags of `meta` when shifting its timestamps
+/// `by`. Tags which are missing or cannot be parsed are left alone.
+pub fn shift_tags(meta: &Metadata, by: Duration) -> Vec<TagChange> {
+    let change = |tag: &'static str, shift: fn(&str, Duration) -> Option<String>| {
+        let old = meta.tag_string(tag)?;
+        let new = shift(&old, by)?;
+        Some(TagChange { tag, old, new })
+    };
+    EXIF_DATE_TAGS.iter().filter_map(|&t| change(t, shift_exif))
+        .chain(XMP_DATE_TAGS.iter().filter_map(|&t| change(t, shift_iso)))
+        .collect()
+}
+
+/// Which files to shift: all, or only those of one camera.
+#[derive(Debug, Clone, Default)]
+pub struct CameraFilter {
+    pub model: Option<String>,
+    pub serial: Option<String>,
+}
+
+impl CameraFilter {
+    /// Whether the photo `source` was taken with the selected camera.
+    /// Models are compared case-insensitively.
+    pub fn matches(&self, source: &Path) -> bool {
+        if self.model.is_none() && self.serial.is_none() {
+            return true;
+        }
+        let meta = match Metadata::new(source) {
+            Ok(m) => m,
+            Err(_) => return false,
+        };
+        let model_ok = self.model.as_ref().is_none_or(|model| {
+            meta.camera_model().is_some_and(|m| m.eq_ignore_ascii_case(model.trim()))
+        });
+        let serial_ok = self.serial.as_ref().is_none_or(|serial| {
+            meta.camera_serial().as_deref() == Some(serial.trim())
+        });
+        model_ok && serial_ok
+    }
+}
+
+/// The edits to make to `files` (photos and their sidecars).
+pub fn plan_time_shift(files: &[PathBuf], by: Duration) -> Vec<TagEdit> {
+    files.iter()
+        .filter_map(|f| Metadata::new(f).ok().map(|m| (f, shift_tags(&m, by))))
+        .flat_map(|(f, changes)| {
+            changes.into_iter().map(move |c| TagEdit {
+                file: f.clone(),
+                tag: c.tag.to_string(),
+                old: Some(TagValue::Text(c.old)),
+                new: Some(TagValue::Text(c.new)),
+            })
+        })
+        .collect()
+}
+
+#[test]
+fn test_parse_duration() {
+    let a: Vec<_> = ["+2h", "-1h30m", "+1d12h", "-45s", "+01:30", "-1:00:05", "+90m"]
+        .iter()
+        .map(|s| parse_duration(s).unwrap().num_seconds())
+        .collect();
+    assert_eq!(a, vec![7200, -5400, 129600, -45, 5400, -3605, 5400]);
+
+    for s in &["2h", "+", "+2x", "+2h30", "+1:60", "+:30", "-1::"] {
+        assert!(parse_duration(s).is_err(), "{}", s);
+    }
+    // out of range instead of overflowing
+    for s in &["+99999999999999999999h", "+9223372036854775807d", "-3000000000:00", "+36601d", "+1d9223372036854775807s"] {
+        assert!(parse_duration(s).is_err(), "{}", s);
+    }
+    assert_eq!(parse_duration("-36600d").unwrap().num_days(), -36600);
+
+    assert_eq!(parse_positive_duration("5m").unwrap().num_seconds(), 300);
+    assert_eq!(parse_positive_duration("+00:05").unwrap().num_seconds(), 300);
+    assert!(parse_positive_duration("-5m").is_err());
+}
+
+#[test]
+fn test_shift_values() {
+    let by = Duration::hours(-2);
+    assert_eq!(shift_exif("2017:05:12 01:30:00", by).unwrap(), "2017:05:11 23:30:00");
+    assert_eq!(shift_exif("    :  :     :  :  ", by), None);
+    assert_eq!(shift_iso("2017-05-12T01:30:00.50+02:00", by).unwrap(), "2017-05-11T23:30:00.50+02:00");
+    assert_eq!(shift_iso("2017-05-12T01:30+02:00", by).unwrap(), "2017-05-11T23:30+02:00");
+    assert_eq!(shift_iso("2017-05-12", by), None);
+    assert_eq!(shift_exif("2017:05:12 01:30:00", Duration::max_value()), None);
+    assert_eq!(shift_iso("2017-05-12T01:30:00", Duration::min_value()), None);
+}
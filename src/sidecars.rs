@@ -0,0 +1,263 @@
+// Copyright 2017 Thorben Kroeger.
+// Dual-licensed MIT and Apache 2.0 (see LICENSE files for details).
+
+// Sidecars of other raw developers, which belong to a photo by their name
+// and place instead of by `DerivedFrom`:
+//
+//   RawTherapee   IMG_1.CR2.pp3
+//   DxO           IMG_1.CR2.dop
+//   Capture One   CaptureOne/Settings*/IMG_1.CR2.cos
+//   Lightroom     IMG_1.xmp or IMG_1.CR2.xmp, without `DerivedFrom`
+//
+// Capture One's are looked for next to the photos even if subfolders are
+// not scanned otherwise.
+//
+// They are carried along with their photo. References to the photo's file
+// name inside them (e.g. Lightroom's `crs:RawFileName`) are updated when
+// it is renamed.
+
+use std::collections::{BTreeSet, HashMap};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use errors::*;
+use filetypes::{FileKind, FileTypes};
+use metadata::{Metadata, TagValue, write_tags};
+use paths::replace_word;
+
+static XMP_CRS_RAWFILENAME: &str = "Xmp.crs.RawFileName";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SidecarFormat {
+    RawTherapee,
+    DxO,
+    CaptureOne,
+    Lightroom,
+}
+
+fn has_extension(path: &Path, extension: &str) -> bool {
+    path.extension().is_some_and(|e| e.eq_ignore_ascii_case(extension))
+}
+
+fn file_name_lowercase(path: &Path) -> Option<String> {
+    Some(path.file_name()?.to_str()?.to_lowercase())
+}
+
+impl SidecarFormat {
+    /// The format of `path`, by its extension and, for Capture One, its
+    /// folder. Any XMP file is taken for Lightroom's, since darktable's
+    /// are recognized by their `DerivedFrom` before.
+    pub fn of(path: &Path) -> Option<SidecarFormat> {
+        if has_extension(path, "pp3") {
+            Some(SidecarFormat::RawTherapee)
+        } else if has_extension(path, "dop") {
+            Some(SidecarFormat::DxO)
+        } else if has_extension(path, "xmp") {
+            Some(SidecarFormat::Lightroom)
+        } else if has_extension(path, "cos") {
+            let settings = path.parent()?;
+            let capture_one = settings.parent()?;
+            let is_settings = file_name_lowercase(settings)?.starts_with("settings");
+            let is_capture_one = file_name_lowercase(capture_one)? == "captureone";
+            if is_settings && is_capture_one {
+                Some(SidecarFormat::CaptureOne)
+            } else {
+                None
+            }
+        } else {
+            None
+        }
+    }
+
+    // the folder of the photo a sidecar `path` of this format belongs to
+    fn photo_dir(self, path: &Path) -> Option<&Path> {
+        match self {
+            SidecarFormat::CaptureOne => path.parent()?.parent()?.parent(),
+            _ => path.parent(),
+        }
+    }
+}
+
+// the subfolders of `dir` whose lowercase name satisfies `matches`
+fn subdirs<F: Fn(&str) -> bool>(dir: &Path, matches: F) -> Vec<PathBuf> {
+    let listed = if dir.as_os_str().is_empty() { Path::new(".") } else { dir };
+    let mut dirs: Vec<_> = fs::read_dir(listed).into_iter()
+        .flatten()
+        .filter_map(|e| e.ok())
+        .map(|e| dir.join(e.file_name()))
+        .filter(|d| d.is_dir() && file_name_lowercase(d).is_some_and(|n| matches(&n)))
+        .collect();
+    dirs.sort();
+    dirs
+}
+
+/// The Capture One sidecars in the `CaptureOne/Settings*` subfolders of
+/// the folders of `files`, which are not among `files` yet.
+pub fn find_capture_one_sidecars(files: &[PathBuf]) -> Vec<PathBuf> {
+    let dirs: BTreeSet<_> = files.iter().map(|f| f.parent().unwrap_or_else(|| Path::new(""))).collect();
+    let mut found = vec![];
+    for dir in dirs {
+        for capture_one in subdirs(dir, |n| n == "captureone") {
+            for settings in subdirs(&capture_one, |n| n.starts_with("settings")) {
+                let mut sidecars: Vec<_> = fs::read_dir(&settings).into_iter()
+                    .flatten()
+                    .filter_map(|e| e.ok())
+                    .map(|e| e.path())
+                    .filter(|p| p.is_file() && SidecarFormat::of(p) == Some(SidecarFormat::CaptureOne))
+                    .filter(|p| !files.contains(p))
+                    .collect();
+                sidecars.sort();
+                found.extend(sidecars);
+            }
+        }
+    }
+    found
+}
+
+// folder and lowercase name
+type Key = (PathBuf, String);
+
+/// The photos sidecars may belong to, by name.
+pub struct Owners<'a> {
+    by_name: HashMap<Key, Vec<&'a Path>>,
+    // name without the (last) extension
+    by_stem: HashMap<Key, Vec<&'a Path>>,
+}
+
+impl<'a> Owners<'a> {
+    pub fn new<I: IntoIterator<Item = &'a Path>>(photos: I) -> Owners<'a> {
+        let mut owners = Owners { by_name: HashMap::new(), by_stem: HashMap::new() };
+        for photo in photos {
+            let dir = photo.parent().unwrap_or_else(|| Path::new("")).to_path_buf();
+            if let Some(name) = file_name_lowercase(photo) {
+                owners.by_name.entry((dir.clone(), name)).or_default().push(photo);
+            }
+            if let Some(stem) = photo.file_stem().and_then(|s| s.to_str()) {
+                owners.by_stem.entry((dir, stem.to_lowercase())).or_default().push(photo);
+            }
+        }
+        owners
+    }
+
+    /// The photo the sidecar `path` belongs to, if it is one of the formats
+    /// above; names are compared ignoring case. Of several photos with
+    /// the stem of a Lightroom sidecar (`IMG_1.xmp`), the raw one.
+    pub fn find(&self, path: &Path, types: &FileTypes) -> Option<&'a Path> {
+        let format = SidecarFormat::of(path)?;
+        let dir = format.photo_dir(path)?.to_path_buf();
+        let name = path.file_stem()?.to_str()?.to_lowercase();
+        let others = |photos: Option<&Vec<&'a Path>>| -> Vec<&'a Path> {
+            photos.into_iter().flatten().cloned().filter(|&p| p != path).collect()
+        };
+
+        let mut found = others(self.by_name.get(&(dir.clone(), name.clone())));
+        if found.is_empty() && format == SidecarFormat::Lightroom {
+            found = others(self.by_stem.get(&(dir, name)));
+            if found.len() > 1 {
+                found.retain(|p| types.get(p).is_some_and(|t| t.kind == FileKind::Raw));
+            }
+        }
+        match found[..] {
+            [photo] => Some(photo),
+            _ => None,
+        }
+    }
+}
+
+/// Replaces the references to the file name `old` in the sidecar `file` by
+/// `new`. Returns whether there were any.
+pub fn replace_reference(file: &Path, old: &str, new: &str) -> Result<bool> {
+    if SidecarFormat::of(file) == Some(SidecarFormat::Lightroom) {
+        let meta = Metadata::new(file)?;
+        return match meta.tag_string(XMP_CRS_RAWFILENAME) {
+            Some(ref name) if name == old => {
+                write_tags(file, &[(XMP_CRS_RAWFILENAME, Some(&TagValue::Text(new.to_string())))])?;
+                Ok(true)
+            }
+            _ => Ok(false),
+        };
+    }
+
+    // the others are text files (INI, Lua tables, XML)
+    let text = match String::from_utf8(fs::read(file)?) {
+        Ok(text) => text,
+        Err(_) => return Ok(false),
+    };
+    match replace_word(&text, old, new) {
+        Some(replaced) => {
+            fs::write(file, replaced)?;
+            Ok(true)
+        }
+        None => Ok(false),
+    }
+}
+
+#[test]
+fn test_sidecar_format() {
+    let formats: Vec<_> = ["/a/IMG_1.CR2.pp3", "/a/IMG_1.CR2.DOP", "/a/CaptureOne/Settings130/IMG_1.CR2.cos",
+                           "/a/IMG_1.xmp", "/a/Settings/IMG_1.CR2.cos", "/a/IMG_1.CR2"]
+        .iter()
+        .map(|p| SidecarFormat::of(Path::new(p)))
+        .collect();
+    assert_eq!(formats, vec![Some(SidecarFormat::RawTherapee), Some(SidecarFormat::DxO),
+                             Some(SidecarFormat::CaptureOne), Some(SidecarFormat::Lightroom), None, None]);
+}
+
+#[test]
+fn test_find_owner() {
+    let photos: Vec<_> = ["/a/IMG_1.CR2", "/a/IMG_1.JPG", "/a/IMG_2.jpg", "/a/IMG_3.nef", "/a/IMG_3.dng"]
+        .iter()
+        .map(Path::new)
+        .collect();
+    let owners = Owners::new(photos.iter().cloned());
+    let types = FileTypes::default();
+    let owner = |s: &str| owners.find(Path::new(s), &types).map(|p| p.to_str().unwrap());
+
+    assert_eq!(owner("/a/IMG_1.cr2.pp3"), Some("/a/IMG_1.CR2"));
+    assert_eq!(owner("/a/IMG_1.JPG.dop"), Some("/a/IMG_1.JPG"));
+    assert_eq!(owner("/a/CaptureOne/Settings/IMG_1.CR2.cos"), Some("/a/IMG_1.CR2"));
+    // the raw rather than the JPG
+    assert_eq!(owner("/a/IMG_1.xmp"), Some("/a/IMG_1.CR2"));
+    assert_eq!(owner("/a/IMG_2.xmp"), Some("/a/IMG_2.jpg"));
+    // two raws
+    assert_eq!(owner("/a/IMG_3.xmp"), None);
+    // only Lightroom's sidecars are named by stem
+    assert_eq!(owner("/a/IMG_2.pp3"), None);
+    assert_eq!(owner("/b/IMG_1.CR2.pp3"), None);
+}
+
+#[test]
+fn test_find_capture_one_sidecars() {
+    use fileops::make_test_dir;
+    use scan::{collect_files, ScanOptions};
+
+    let dir = make_test_dir("capture-one-sidecars");
+    for f in &["IMG_1.CR2", "captureone/Settings130/IMG_1.CR2.cos", "CaptureOne/Cache/IMG_1.CR2.cos",
+               "raw/IMG_2.CR2", "raw/CaptureOne/Settings/IMG_2.CR2.cos"] {
+        let path = dir.join(f);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(&path, "").unwrap();
+    }
+
+    let files = collect_files(&dir, &ScanOptions::new(false, &[], &[]).unwrap()).unwrap();
+    assert_eq!(files, vec![dir.join("IMG_1.CR2")]);
+    assert_eq!(find_capture_one_sidecars(&files), vec![dir.join("captureone/Settings130/IMG_1.CR2.cos")]);
+
+    // already found by a recursive scan
+    let files = collect_files(&dir, &ScanOptions::new(true, &[], &[]).unwrap()).unwrap();
+    assert!(files.contains(&dir.join("raw/CaptureOne/Settings/IMG_2.CR2.cos")));
+    assert_eq!(find_capture_one_sidecars(&files), Vec::<PathBuf>::new());
+}
+
+#[test]
+fn test_replace_reference() {
+    use fileops::make_test_dir;
+
+    let dir = make_test_dir("replace-reference");
+    let pp3 = dir.join("x.cr2.pp3");
+    fs::write(&pp3, "[Version]\nAppVersion=5.8\n\n[Source]\nFile=IMG_1.CR2\nOther=IMG_1.CR23\n").unwrap();
+    assert!(replace_reference(&pp3, "IMG_1.CR2", "x.cr2").unwrap());
+    assert_eq!(fs::read_to_string(&pp3).unwrap(),
+               "[Version]\nAppVersion=5.8\n\n[Source]\nFile=x.cr2\nOther=IMG_1.CR23\n");
+    assert!(!replace_reference(&pp3, "IMG_1.CR2", "x.cr2").unwrap());
+}
@@ -0,0 +1,440 @@
+// Copyright 2017 Thorben Kroeger.
+// Dual-licensed MIT and Apache 2.0 (see LICENSE files for details).
+
+// Naming templates for grouped folders and files, e.g.
+//   folder: "{year}/{date} {title}"
+//   file:   "{seq:4}_{date}"
+// Placeholders are written as `{name}` or `{name:argument}`,
+// literal braces as `{{` and `}}`.
+
+use std::fmt::{self, Write};
+use std::path::PathBuf;
+
+use chrono::NaiveDateTime;
+use chrono::format::{Fixed, Item, StrftimeItems};
+
+use errors::*;
+
+pub static DEFAULT_FOLDER_TEMPLATE: &str = "{date}";
+pub static DEFAULT_FILE_TEMPLATE: &str = "{seq:4}_{date}";
+
+// rendered for date placeholders of photos without a date
+static NO_DATE: &str = "no-date";
+static UNKNOWN: &str = "unknown";
+
+#[derive(Debug, Clone, PartialEq)]
+enum Placeholder {
+    // `{date}` or `{date:<strftime format>}`, default `%Y-%m-%d`
+    Date(String),
+    Year,
+    Month,
+    Day,
+    Hour,
+    Minute,
+    Second,
+    // `{seq}` or `{seq:<width>}`, default width 4
+    Seq(usize),
+    Camera,
+    // original file stem
+    Stem,
+    Rating,
+    // per-group title, see `--title`
+    Title,
+    // place of capture, see `--gazetteer`
+    Country,
+    Region,
+    City,
+}
+
+impl Placeholder {
+    fn is_seq(&self) -> bool {
+        matches!(*self, Placeholder::Seq(_))
+    }
+
+    // whether the placeholder distinguishes photos within the same folder
+    fn is_distinctive(&self) -> bool {
+        self.is_seq() || *self == Placeholder::Stem
+    }
+
+    fn is_place(&self) -> bool {
+        matches!(*self, Placeholder::Country | Placeholder::Region | Placeholder::City)
+    }
+
+    fn parse(name: &str, arg: Option<&str>) -> Option<Placeholder> {
+        let p = match (name, arg) {
+            ("date", None) => Placeholder::Date("%Y-%m-%d".into()),
+            ("date", Some(format)) => Placeholder::Date(format.into()),
+            ("seq", None) => Placeholder::Seq(4),
+            ("seq", Some(width)) => Placeholder::Seq(width.parse().ok()?),
+            (_, Some(_)) => return None,
+            ("year", None) => Placeholder::Year,
+            ("month", None) => Placeholder::Month,
+            ("day", None) => Placeholder::Day,
+            ("hour", None) => Placeholder::Hour,
+            ("minute", None) => Placeholder::Minute,
+            ("second", None) => Placeholder::Second,
+            ("camera", None) => Placeholder::Camera,
+            ("stem", None) => Placeholder::Stem,
+            ("rating", None) => Placeholder::Rating,
+            ("title", None) => Placeholder::Title,
+            ("country", None) => Placeholder::Country,
+            ("region", None) => Placeholder::Region,
+            ("city", None) => Placeholder::City,
+            _ => return None,
+        };
+        Some(p)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Literal(String),
+    Placeholder(Placeholder),
+}
+
+/// Everything a template may refer to for a single photo.
+#[derive(Debug, Default)]
+pub struct NamingContext<'a> {
+    // local time of capture
+    pub datetime: Option<NaiveDateTime>,
+    pub seq: usize,
+    pub camera: Option<&'a str>,
+    pub stem: &'a str,
+    pub rating: Option<i32>,
+    pub title: Option<&'a str>,
+    pub country: Option<&'a str>,
+    pub region: Option<&'a str>,
+    pub city: Option<&'a str>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Template {
+    source: String,
+    tokens: Vec<Token>,
+}
+
+fn invalid(template: &str, reason: &str) -> Error {
+    ErrorKind::InvalidTemplate(template.to_string(), reason.to_string()).into()
+}
+
+// checks the strftime `format` of a `{date:...}` placeholder; capture times
+// are local times without offset, so they cannot be formatted with one
+fn check_date_format(format: &str) -> ::std::result::Result<(), String> {
+    for item in StrftimeItems::new(format) {
+        match item {
+            Item::Error => return Err(format!("invalid date format `{}`", format)),
+            Item::Fixed(Fixed::TimezoneName) |
+            Item::Fixed(Fixed::TimezoneOffsetColon) |
+            Item::Fixed(Fixed::TimezoneOffsetColonZ) |
+            Item::Fixed(Fixed::TimezoneOffset) |
+            Item::Fixed(Fixed::TimezoneOffsetZ) |
+            Item::Fixed(Fixed::RFC2822) |
+            Item::Fixed(Fixed::RFC3339) => {
+                return Err(format!("date format `{}` needs a time zone, which capture times do not have",
+                                   format));
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+impl Template {
+    pub fn parse(template: &str) -> Result<Template> {
+        let mut tokens = vec![];
+        let mut literal = String::new();
+        let mut chars = template.chars().peekable();
+
+        while let Some(c) = chars.next() {
+            match c {
+                '{' if chars.peek() == Some(&'{') => {
+                    chars.next();
+                    literal.push('{');
+                }
+                '}' if chars.peek() == Some(&'}') => {
+                    chars.next();
+                    literal.push('}');
+                }
+                '{' => {
+                    let mut inner = String::new();
+                    loop {
+                        match chars.next() {
+                            Some('}') => break,
+                            Some(c) => inner.push(c),
+                            None => return Err(invalid(template, "unterminated `{`")),
+                        }
+                    }
+                    let mut parts = inner.splitn(2, ':');
+                    let name = parts.next().unwrap_or("").trim();
+                    let arg = parts.next();
+                    let placeholder = Placeholder::parse(name, arg).ok_or_else(|| {
+                        invalid(template, &format!("unknown placeholder `{{{}}}`", inner))
+                    })?;
+                    if let Placeholder::Date(ref format) = placeholder {
+                        check_date_format(format).map_err(|reason| invalid(template, &reason))?;
+                    }
+                    if !literal.is_empty() {
+                        tokens.push(Token::Literal(literal.clone()));
+                        literal.clear();
+                    }
+                    tokens.push(Token::Placeholder(placeholder));
+                }
+                '}' => return Err(invalid(template, "unmatched `}`")),
+                c => literal.push(c),
+            }
+        }
+        if !literal.is_empty() {
+            tokens.push(Token::Literal(literal));
+        }
+
+        Ok(Template {
+            source: template.to_string(),
+            tokens,
+        })
+    }
+
+    fn has(&self, f: fn(&Placeholder) -> bool) -> bool {
+        self.tokens.iter().any(|t| match *t {
+            Token::Placeholder(ref p) => f(p),
+            Token::Literal(_) => false,
+        })
+    }
+
+    // unlike `format!`, reports formats chrono cannot render instead of
+    // panicking (see `check_date_format`)
+    fn format_date(&self, datetime: NaiveDateTime, format: &str) -> Result<String> {
+        let mut s = String::new();
+        write!(s, "{}", datetime.format(format))
+            .map_err(|_| invalid(&self.source, &format!("cannot format date with `{}`", format)))?;
+        Ok(s)
+    }
+
+    fn render_placeholder(&self, p: &Placeholder, ctx: &NamingContext) -> Result<String> {
+        let date_part = |format: &str| match ctx.datetime {
+            Some(d) => format!("{}", d.format(format)),
+            None => UNKNOWN.into(),
+        };
+        let s = match *p {
+            Placeholder::Date(ref format) => match ctx.datetime {
+                Some(d) => self.format_date(d, format)?,
+                None => NO_DATE.into(),
+            },
+            Placeholder::Year => date_part("%Y"),
+            Placeholder::Month => date_part("%m"),
+            Placeholder::Day => date_part("%d"),
+            Placeholder::Hour => date_part("%H"),
+            Placeholder::Minute => date_part("%M"),
+            Placeholder::Second => date_part("%S"),
+            Placeholder::Seq(width) => format!("{:0width$}", ctx.seq, width = width),
+            Placeholder::Camera => ctx.camera.unwrap_or(UNKNOWN).into(),
+            Placeholder::Stem => ctx.stem.into(),
+            Placeholder::Rating => ctx.rating.unwrap_or(0).to_string(),
+            Placeholder::Title => ctx.title.unwrap_or("").into(),
+            Placeholder::Country => ctx.country.unwrap_or(UNKNOWN).into(),
+            Placeholder::Region => ctx.region.filter(|r| !r.is_empty()).unwrap_or(UNKNOWN).into(),
+            Placeholder::City => ctx.city.unwrap_or(UNKNOWN).into(),
+        };
+        Ok(s)
+    }
+
+    /// Renders the template. Values substituted for placeholders never
+    /// contain path separators.
+    pub fn render(&self, ctx: &NamingContext) -> Result<String> {
+        let mut s = String::new();
+        for token in &self.tokens {
+            match *token {
+                Token::Literal(ref l) => s.push_str(l),
+                Token::Placeholder(ref p) => {
+                    s.push_str(&self.render_placeholder(p, ctx)?.replace('/', "-"))
+                }
+            }
+        }
+        Ok(s)
+    }
+
+    /// Renders the template as a relative path. Leading and trailing blanks,
+    /// `_` and `-` are trimmed from every component (e.g. because of an
+    /// empty `{title}`), empty components are dropped. `.` and `..` are
+    /// rejected, so that the path stays within the output folder.
+    pub fn render_path(&self, ctx: &NamingContext) -> Result<PathBuf> {
+        let rendered = self.render(ctx)?;
+        let components: Vec<_> = rendered
+            .split('/')
+            .map(|c| c.trim_matches(|c: char| c.is_whitespace() || c == '_' || c == '-'))
+            .filter(|c| !c.is_empty())
+            .collect();
+        if components.iter().any(|&c| c == "." || c == "..") {
+            return Err(invalid(&self.source, &format!("'{}' is not a folder within the output folder", rendered)));
+        }
+        Ok(components.into_iter().collect())
+    }
+
+    /// Extracts the sequence number from a name rendered by this template.
+    pub fn parse_seq(&self, name: &str) -> Option<usize> {
+        if !self.has(Placeholder::is_seq) {
+            return None;
+        }
+        match_tokens(&self.tokens, name)
+    }
+}
+
+impl fmt::Display for Template {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.source)
+    }
+}
+
+// Matches `name` against `tokens`, where `{seq}` matches one or more digits
+// and all other placeholders match arbitrary text. Returns the sequence
+// number if `name` matches (or 0 if `tokens` contain no `{seq}`).
+fn match_tokens(tokens: &[Token], name: &str) -> Option<usize> {
+    match tokens.split_first() {
+        None => {
+            if name.is_empty() { Some(0) } else { None }
+        }
+        Some((Token::Literal(l), rest)) => {
+            name.strip_prefix(l.as_str()).and_then(|n| match_tokens(rest, n))
+        }
+        Some((Token::Placeholder(Placeholder::Seq(_)), rest)) => {
+            let digits = name.chars().take_while(|c| c.is_ascii_digit()).count();
+            // longest match first
+            (1..digits + 1).rev().filter_map(|n| {
+                match_tokens(rest, &name[n..])
+                    .and_then(|_| name[..n].parse().ok())
+            }).next()
+        }
+        Some((Token::Placeholder(_), rest)) => {
+            name.char_indices().map(|(i, _)| i)
+                .chain(Some(name.len()))
+                .filter_map(|i| match_tokens(rest, &name[i..]))
+                .next()
+        }
+    }
+}
+
+/// Templates for the folder and file names of grouped photos.
+#[derive(Debug, Clone)]
+pub struct NamingScheme {
+    pub folder: Template,
+    pub file: Template,
+}
+
+impl NamingScheme {
+    pub fn new(folder: &str, file: &str) -> Result<NamingScheme> {
+        let folder = Template::parse(folder)?;
+        let file = Template::parse(file)?;
+
+        if folder.has(Placeholder::is_distinctive) {
+            return Err(invalid(&folder.source, "`{seq}` and `{stem}` are not allowed in folder names"));
+        }
+        if !file.has(Placeholder::is_distinctive) {
+            return Err(invalid(&file.source, "file names need `{seq}` or `{stem}` to be unique"));
+        }
+        if file.source.contains('/') {
+            return Err(invalid(&file.source, "file names must not contain `/`"));
+        }
+
+        Ok(NamingScheme { folder, file })
+    }
+
+    /// Whether the templates refer to the place of capture.
+    pub fn uses_places(&self) -> bool {
+        self.folder.has(Placeholder::is_place) || self.file.has(Placeholder::is_place)
+    }
+}
+
+impl Default for NamingScheme {
+    fn default() -> NamingScheme {
+        NamingScheme::new(DEFAULT_FOLDER_TEMPLATE, DEFAULT_FILE_TEMPLATE).unwrap()
+    }
+}
+
+#[cfg(test)]
+fn test_context<'a>() -> NamingContext<'a> {
+    use chrono::NaiveDate;
+    NamingContext {
+        datetime: Some(NaiveDate::from_ymd(2017, 5, 12).and_hms(9, 8, 7)),
+        seq: 42,
+        camera: Some("Canon EOS 5D Mark III"),
+        stem: "IMG_7506",
+        rating: Some(3),
+        title: Some("Wedding"),
+        country: Some("Germany"),
+        region: Some("Bavaria"),
+        city: Some("Munich"),
+    }
+}
+
+#[test]
+fn test_render() {
+    let ctx = test_context();
+    let inputs = vec![
+        "{seq:4}_{date}", "{year}{month}{day}-{hour}{minute}{second}", "{date:%y%m%d}_{seq:2}",
+        "{camera} {stem} {rating}*", "{title}", "{{{seq}}}", "{country}/{region}/{city}",
+    ];
+    let a: Vec<_> = inputs.iter()
+        .map(|t| Template::parse(t).unwrap().render(&ctx).unwrap())
+        .collect();
+    let e = vec![
+        "0042_2017-05-12", "20170512-090807", "170512_42",
+        "Canon EOS 5D Mark III IMG_7506 3*", "Wedding", "{0042}", "Germany/Bavaria/Munich",
+    ];
+    assert_eq!(a, e);
+}
+
+#[test]
+fn test_render_missing_values() {
+    let ctx = NamingContext { stem: "x", ..Default::default() };
+    let t = Template::parse("{date}/{year}/{camera}/{rating}/{title}").unwrap();
+    assert_eq!(t.render(&ctx).unwrap(), "no-date/unknown/unknown/0/");
+    let t = Template::parse("{year}/{date} {title}").unwrap();
+    assert_eq!(t.render_path(&ctx).unwrap(), PathBuf::from("unknown/no-date"));
+    assert_eq!(t.render_path(&test_context()).unwrap(), PathBuf::from("2017/2017-05-12 Wedding"));
+    let t = Template::parse("{country}/{city}").unwrap();
+    assert_eq!(t.render_path(&ctx).unwrap(), PathBuf::from("unknown/unknown"));
+}
+
+#[test]
+fn test_render_path_escaping() {
+    let t = Template::parse("{year}/{title}").unwrap();
+    for title in &["..", ".", " .. ", "_.._"] {
+        let ctx = NamingContext { title: Some(title), ..test_context() };
+        assert!(t.render_path(&ctx).is_err(), "{}", title);
+    }
+    // a `/` in a value does not start a new component
+    let ctx = NamingContext { title: Some("../.."), ..test_context() };
+    assert_eq!(t.render_path(&ctx).unwrap(), PathBuf::from("2017/..-.."));
+    let ctx = NamingContext { title: Some("...Wedding"), ..test_context() };
+    assert_eq!(t.render_path(&ctx).unwrap(), PathBuf::from("2017/...Wedding"));
+    assert!(Template::parse("../{year}").unwrap().render_path(&test_context()).is_err());
+}
+
+#[test]
+fn test_parse_invalid() {
+    let inputs = ["{date", "date}", "{foo}", "{seq:x}", "{year:4}", "{date:%Q}", "{date:%Y-%m-%d %z}",
+                  "{date:%Z}", "{date:%+}"];
+    for t in inputs {
+        assert!(Template::parse(t).is_err(), "{}", t);
+    }
+    assert!(NamingScheme::new("{date}", "{date}").is_err());
+    assert!(NamingScheme::new("{seq}", "{seq}").is_err());
+    assert!(NamingScheme::new("{date}", "{date}/{seq}").is_err());
+    assert!(NamingScheme::new("{year}/{date}", "{stem}").is_ok());
+    assert!(NamingScheme::new("{country}/{city}", "{stem}").unwrap().uses_places());
+    assert!(!NamingScheme::default().uses_places());
+}
+
+#[test]
+fn test_parse_seq() {
+    let t = Template::parse("{seq:4}_{date}").unwrap();
+    assert_eq!(t.parse_seq("0042_2017-05-12"), Some(42));
+    assert_eq!(t.parse_seq("0042_no-date"), Some(42));
+    assert_eq!(t.parse_seq("IMG_0042"), None);
+
+    let t = Template::parse("{date}-{camera}-{seq}").unwrap();
+    assert_eq!(t.parse_seq("2017-05-12-EOS 5D-0007"), Some(7));
+    assert_eq!(t.parse_seq("2017-05-12-EOS 5D"), None);
+
+    let t = Template::parse("{stem}").unwrap();
+    assert_eq!(t.parse_seq("IMG_0042"), None);
+}
@@ -0,0 +1,422 @@
+// Copyright 2017 Thorben Kroeger.
+// Dual-licensed MIT and Apache 2.0 (see LICENSE files for details).
+
+// Checks a plan (list of `Cmd`s) for operations which would overwrite
+// files or leave broken references, before anything is executed.
+
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+#[cfg(test)]
+use std::fs;
+#[cfg(test)]
+use fileops::make_test_dir;
+
+use errors::*;
+use paths::make_new_filename;
+use plan::{Cmd, Plan};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ConflictPolicy {
+    // refuse to execute the plan
+    Abort,
+    // drop all operations of the affected photos
+    Skip,
+    // append `_1`, `_2`, ... to the new names of the affected photos
+    Suffix,
+}
+
+impl FromStr for ConflictPolicy {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<ConflictPolicy> {
+        match s {
+            "abort" => Ok(ConflictPolicy::Abort),
+            "skip" => Ok(ConflictPolicy::Skip),
+            "suffix" => Ok(ConflictPolicy::Suffix),
+            _ => bail!("unknown conflict policy '{}' (expected abort, skip or suffix)", s),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ConflictKind {
+    // another file of the plan is renamed to the same destination
+    DuplicateDestination(PathBuf),
+    // the destination exists already
+    DestinationExists(PathBuf),
+    // a reference to a file which neither exists nor is created by the plan
+    DanglingRef(PathBuf, PathBuf),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Conflict {
+    // index of the offending `Cmd`
+    pub index: usize,
+    // original path of the photo (i.e. its source file) the `Cmd` belongs to
+    pub photo: PathBuf,
+    pub kind: ConflictKind,
+}
+
+impl fmt::Display for Conflict {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: ", self.photo.display())?;
+        match self.kind {
+            ConflictKind::DuplicateDestination(ref dest) => {
+                write!(f, "'{}' is the destination of more than one file", dest.display())
+            }
+            ConflictKind::DestinationExists(ref dest) => {
+                write!(f, "'{}' exists already", dest.display())
+            }
+            ConflictKind::DanglingRef(ref file, ref target) => {
+                write!(f, "'{}' would reference '{}', which does not exist",
+                       file.display(), target.display())
+            }
+        }
+    }
+}
+
+// Assigns each `Cmd` to the photo it belongs to, identified by the index of
+// the `Rename` of the photo's source file, for plans which do not say (see
+// `Plan::from_cmds`). A photo's derived files are recognized by the
+// `AdjustRef`s pointing (directly or indirectly) to the new name of its
+// source file, its attached sidecars by the `ReplaceRef`s.
+// `CreateDirectory`s belong to no photo.
+pub fn photo_units(cmds: &[Cmd]) -> Vec<Option<usize>> {
+    let adjusted: HashSet<&Path> = cmds.iter().filter_map(|c| match *c {
+        Cmd::AdjustRef(ref file, _) | Cmd::ReplaceRef(ref file, _, _) => Some(file.as_path()),
+        _ => None,
+    }).collect();
+
+    let mut units = vec![None; cmds.len()];
+    // unit of the most recent `Rename` to a destination
+    let mut unit_of_dest = HashMap::<&Path, usize>::new();
+    // units of derived files, which are only known once their `AdjustRef` is seen
+    let mut pending = HashMap::<&Path, usize>::new();
+
+    for (i, cmd) in cmds.iter().enumerate() {
+        match *cmd {
+            Cmd::CreateDirectory(_) => {}
+            Cmd::Rename(_, ref dest) => {
+                if adjusted.contains(dest.as_path()) {
+                    pending.insert(dest, i);
+                } else {
+                    units[i] = Some(i);
+                    unit_of_dest.insert(dest, i);
+                }
+            }
+            Cmd::AdjustRef(ref file, ref target) | Cmd::ReplaceRef(ref file, _, ref target) => {
+                let rename = pending.remove(file.as_path());
+                // without a known target, the derived file is a photo of its own
+                let unit = unit_of_dest.get(target.as_path()).cloned().or(rename);
+                units[i] = unit;
+                if let Some(r) = rename {
+                    units[r] = unit;
+                }
+                if let Some(u) = unit {
+                    unit_of_dest.insert(file, u);
+                }
+            }
+        }
+    }
+    for (_, r) in pending {
+        units[r] = Some(r);
+    }
+    units
+}
+
+// the source of the photo of `plan.cmds[index]`: the first file of the
+// photo which is renamed
+fn unit_photo(plan: &Plan, index: usize) -> PathBuf {
+    let first = (0..plan.cmds.len())
+        .find(|&i| plan.photos[i] == plan.photos[index] && matches!(plan.cmds[i], Cmd::Rename(..)))
+        .unwrap_or(index);
+    match plan.cmds[first] {
+        Cmd::Rename(ref src, _) => src.clone(),
+        Cmd::AdjustRef(ref file, _) | Cmd::ReplaceRef(ref file, _, _) => file.clone(),
+        Cmd::CreateDirectory(ref dir) => dir.clone(),
+    }
+}
+
+pub fn find_conflicts(plan: &Plan) -> Vec<Conflict> {
+    let cmds = &plan.cmds;
+    let mut conflicts = vec![];
+    let mut created = HashSet::<&Path>::new();
+
+    for (i, cmd) in cmds.iter().enumerate() {
+        let kind = match *cmd {
+            Cmd::CreateDirectory(_) => None,
+            Cmd::Rename(_, ref dest) => {
+                if !created.insert(dest) {
+                    Some(ConflictKind::DuplicateDestination(dest.clone()))
+                } else if dest.exists() {
+                    Some(ConflictKind::DestinationExists(dest.clone()))
+                } else {
+                    None
+                }
+            }
+            Cmd::AdjustRef(ref file, ref target) | Cmd::ReplaceRef(ref file, _, ref target) => {
+                if !created.contains(target.as_path()) && !target.exists() {
+                    Some(ConflictKind::DanglingRef(file.clone(), target.clone()))
+                } else {
+                    None
+                }
+            }
+        };
+        if let Some(kind) = kind {
+            conflicts.push(Conflict {
+                index: i,
+                photo: unit_photo(plan, i),
+                kind,
+            });
+        }
+    }
+    conflicts
+}
+
+// the name of a file of the photo whose source is renamed to `<stem>.ext`,
+// when that is renamed to `<stem>_<n>.ext` instead: `dir/<stem>_01.cr2.xmp`
+// -> `dir/<stem>_<n>_01.cr2.xmp` (see `make_new_filename`)
+fn with_suffix(path: &Path, stem: &str, n: usize) -> PathBuf {
+    let name = path.file_name().and_then(|n| n.to_str()).unwrap_or("");
+    path.with_file_name(make_new_filename(name, stem, &format!("{}_{}", stem, n)))
+}
+
+// drops `CreateDirectory`s for directories no file is renamed into anymore
+fn prune_directories(plan: Plan) -> Plan {
+    let used: Vec<PathBuf> = plan.cmds.iter().filter_map(|c| match *c {
+        Cmd::Rename(_, ref dest) => Some(dest.clone()),
+        _ => None,
+    }).collect();
+    let (cmds, photos) = plan.cmds.into_iter().zip(plan.photos).filter(|(c, _)| match *c {
+        Cmd::CreateDirectory(ref dir) => used.iter().any(|d| d.starts_with(dir)),
+        _ => true,
+    }).unzip();
+    Plan { cmds, photos }
+}
+
+/// Finds the conflicts in `plan` and resolves them according to `policy`.
+/// Returns the resolved plan and all conflicts found. With
+/// `ConflictPolicy::Abort`, any conflict is an error.
+pub fn resolve_conflicts(plan: &Plan, policy: ConflictPolicy) -> Result<(Plan, Vec<Conflict>)> {
+    let conflicts = find_conflicts(plan);
+    if conflicts.is_empty() {
+        return Ok((plan.clone(), conflicts));
+    }
+    if policy == ConflictPolicy::Abort {
+        bail!(ErrorKind::PlanConflicts(conflicts.len()));
+    }
+
+    let cmds = &plan.cmds;
+    let unit_of = |i: usize| plan.photos[i].unwrap_or(i);
+    let mut affected = HashSet::new();
+    let mut unfixable = HashSet::new();
+    for c in &conflicts {
+        affected.insert(unit_of(c.index));
+        if let ConflictKind::DanglingRef(..) = c.kind {
+            unfixable.insert(unit_of(c.index));
+        }
+    }
+
+    // destinations claimed by photos without conflicts
+    let mut taken: HashSet<PathBuf> = cmds.iter().enumerate().filter_map(|(i, c)| match *c {
+        Cmd::Rename(_, ref dest) if !affected.contains(&unit_of(i)) => Some(dest.clone()),
+        _ => None,
+    }).collect();
+
+    // per affected unit, the new stem of its source (the first file it
+    // renames) and the suffix to use
+    let mut stems = HashMap::<usize, &str>::new();
+    for (i, c) in cmds.iter().enumerate() {
+        if let Cmd::Rename(_, ref dest) = *c {
+            if let Some(stem) = dest.file_stem().and_then(|s| s.to_str()) {
+                stems.entry(unit_of(i)).or_insert(stem);
+            }
+        }
+    }
+    let mut suffixes = HashMap::<usize, usize>::new();
+    if policy == ConflictPolicy::Suffix {
+        let mut unit_dests = HashMap::<usize, Vec<&PathBuf>>::new();
+        let mut order = vec![];
+        for (i, c) in cmds.iter().enumerate() {
+            if let Cmd::Rename(_, ref dest) = *c {
+                let u = unit_of(i);
+                if affected.contains(&u) && !unfixable.contains(&u) {
+                    if !unit_dests.contains_key(&u) {
+                        order.push(u);
+                    }
+                    unit_dests.entry(u).or_default().push(dest);
+                }
+            }
+        }
+        for u in order {
+            let (dests, stem) = (&unit_dests[&u], stems[&u]);
+            let n = (1..).find(|&n| dests.iter().all(|d| {
+                let d = with_suffix(d, stem, n);
+                !taken.contains(&d) && !d.exists()
+            })).unwrap();
+            for d in dests {
+                taken.insert(with_suffix(d, stem, n));
+            }
+            suffixes.insert(u, n);
+        }
+    }
+
+    let mut resolved = Plan::default();
+    for (i, cmd) in cmds.iter().enumerate() {
+        let u = unit_of(i);
+        if !affected.contains(&u) || matches!(*cmd, Cmd::CreateDirectory(_)) {
+            resolved.cmds.push(cmd.clone());
+            resolved.photos.push(plan.photos[i]);
+            continue;
+        }
+        if let Some(&n) = suffixes.get(&u) {
+            let suffixed = |path: &Path| with_suffix(path, stems[&u], n);
+            resolved.photos.push(plan.photos[i]);
+            resolved.cmds.push(match *cmd {
+                Cmd::Rename(ref src, ref dest) => Cmd::Rename(src.clone(), suffixed(dest)),
+                Cmd::AdjustRef(ref file, ref target) => Cmd::AdjustRef(suffixed(file), suffixed(target)),
+                Cmd::ReplaceRef(ref file, ref old, ref target) => {
+                    Cmd::ReplaceRef(suffixed(file), old.clone(), suffixed(target))
+                }
+                Cmd::CreateDirectory(_) => unreachable!(),
+            });
+        }
+    }
+
+    Ok((prune_directories(resolved), conflicts))
+}
+
+#[cfg(test)]
+fn p(s: &str) -> PathBuf {
+    PathBuf::from(s)
+}
+
+#[cfg(test)]
+fn conflicting_plan() -> Vec<Cmd> {
+    vec![
+        Cmd::CreateDirectory(p("/nonexistent/out/a")),
+        Cmd::Rename(p("/nonexistent/in/1.cr2"), p("/nonexistent/out/a/x.cr2")),
+        Cmd::Rename(p("/nonexistent/in/1.cr2.xmp"), p("/nonexistent/out/a/x.cr2.xmp")),
+        Cmd::AdjustRef(p("/nonexistent/out/a/x.cr2.xmp"), p("/nonexistent/out/a/x.cr2")),
+        Cmd::Rename(p("/nonexistent/in/2.cr2"), p("/nonexistent/out/a/x.cr2")),
+        Cmd::Rename(p("/nonexistent/in/2.jpg"), p("/nonexistent/out/a/x.jpg")),
+        Cmd::AdjustRef(p("/nonexistent/out/a/x.jpg"), p("/nonexistent/out/a/x.cr2")),
+        Cmd::CreateDirectory(p("/nonexistent/out/b")),
+        Cmd::Rename(p("/nonexistent/in/3.jpg"), p("/nonexistent/out/b/y.jpg")),
+        Cmd::AdjustRef(p("/nonexistent/out/b/y.jpg"), p("/nonexistent/out/b/y.cr2")),
+    ]
+}
+
+#[test]
+fn test_photo_units() {
+    let a = photo_units(&conflicting_plan());
+    let e = vec![None, Some(1), Some(1), Some(1), Some(4), Some(4), Some(4), None, Some(8), Some(8)];
+    assert_eq!(a, e);
+}
+
+#[test]
+fn test_find_conflicts() {
+    let a = find_conflicts(&Plan::from_cmds(conflicting_plan()));
+    let e = vec![
+        Conflict {
+            index: 4,
+            photo: p("/nonexistent/in/2.cr2"),
+            kind: ConflictKind::DuplicateDestination(p("/nonexistent/out/a/x.cr2")),
+        },
+        Conflict {
+            index: 9,
+            photo: p("/nonexistent/in/3.jpg"),
+            kind: ConflictKind::DanglingRef(p("/nonexistent/out/b/y.jpg"), p("/nonexistent/out/b/y.cr2")),
+        },
+    ];
+    assert_eq!(a, e);
+}
+
+#[test]
+fn test_find_conflicts_existing_destination() {
+    let dir = make_test_dir("validate-existing");
+    fs::write(dir.join("x.cr2"), b"").unwrap();
+    let cmds = vec![Cmd::Rename(p("/nonexistent/in/1.cr2"), dir.join("x.cr2"))];
+
+    let a = find_conflicts(&Plan::from_cmds(cmds));
+    assert_eq!(a.len(), 1);
+    assert_eq!(a[0].kind, ConflictKind::DestinationExists(dir.join("x.cr2")));
+}
+
+#[test]
+fn test_resolve_conflicts() {
+    let cmds = conflicting_plan();
+    let plan = Plan::from_cmds(cmds.clone());
+    assert!(resolve_conflicts(&plan, ConflictPolicy::Abort).is_err());
+
+    let (skipped, conflicts) = resolve_conflicts(&plan, ConflictPolicy::Skip).unwrap();
+    assert_eq!(conflicts.len(), 2);
+    assert_eq!(skipped.cmds, cmds[..4].to_vec());
+    assert_eq!(skipped.photos, plan.photos[..4].to_vec());
+
+    let (suffixed, _) = resolve_conflicts(&plan, ConflictPolicy::Suffix).unwrap();
+    let mut e = cmds[..4].to_vec();
+    e.extend(vec![
+        Cmd::Rename(p("/nonexistent/in/2.cr2"), p("/nonexistent/out/a/x_1.cr2")),
+        Cmd::Rename(p("/nonexistent/in/2.jpg"), p("/nonexistent/out/a/x_1.jpg")),
+        Cmd::AdjustRef(p("/nonexistent/out/a/x_1.jpg"), p("/nonexistent/out/a/x_1.cr2")),
+    ]);
+    assert_eq!(suffixed.cmds, e);
+}
+
+#[test]
+fn test_resolve_conflicts_duplicates() {
+    let mut plan = Plan::default();
+    plan.push_photo(vec![Cmd::Rename(p("/nonexistent/in/1.cr2"), p("/nonexistent/out/x.cr2"))]);
+    // a darktable duplicate, and a name which does not start with the stem
+    plan.push_photo(vec![
+        Cmd::Rename(p("/nonexistent/in/2.cr2"), p("/nonexistent/out/x.cr2")),
+        Cmd::Rename(p("/nonexistent/in/2_01.cr2.xmp"), p("/nonexistent/out/x_01.cr2.xmp")),
+        Cmd::AdjustRef(p("/nonexistent/out/x_01.cr2.xmp"), p("/nonexistent/out/x.cr2")),
+        Cmd::Rename(p("/nonexistent/in/edit of 2.jpg"), p("/nonexistent/out/edit of x.jpg")),
+    ]);
+
+    let (suffixed, _) = resolve_conflicts(&plan, ConflictPolicy::Suffix).unwrap();
+    let e = vec![
+        Cmd::Rename(p("/nonexistent/in/1.cr2"), p("/nonexistent/out/x.cr2")),
+        Cmd::Rename(p("/nonexistent/in/2.cr2"), p("/nonexistent/out/x_1.cr2")),
+        Cmd::Rename(p("/nonexistent/in/2_01.cr2.xmp"), p("/nonexistent/out/x_1_01.cr2.xmp")),
+        Cmd::AdjustRef(p("/nonexistent/out/x_1_01.cr2.xmp"), p("/nonexistent/out/x_1.cr2")),
+        Cmd::Rename(p("/nonexistent/in/edit of 2.jpg"), p("/nonexistent/out/edit of x_1.jpg")),
+    ];
+    assert_eq!(suffixed.cmds, e);
+}
+
+#[test]
+fn test_resolve_conflicts_companions() {
+    let mut plan = Plan::default();
+    plan.push_photo(vec![Cmd::Rename(p("/nonexistent/in/1.cr2"), p("/nonexistent/out/x.cr2"))]);
+    // named alike by chance, e.g. by a `{title}` template
+    plan.push_photo(vec![Cmd::Rename(p("/nonexistent/in/2.jpg"), p("/nonexistent/out/x.jpg"))]);
+    plan.push_photo(vec![
+        Cmd::Rename(p("/nonexistent/in/3.cr2"), p("/nonexistent/out/x.cr2")),
+        // shot together with 3.cr2
+        Cmd::Rename(p("/nonexistent/in/3.jpg"), p("/nonexistent/out/x.jpg")),
+        Cmd::Rename(p("/nonexistent/in/3.jpg.xmp"), p("/nonexistent/out/x.jpg.xmp")),
+        Cmd::AdjustRef(p("/nonexistent/out/x.jpg.xmp"), p("/nonexistent/out/x.jpg")),
+    ]);
+
+    let (skipped, conflicts) = resolve_conflicts(&plan, ConflictPolicy::Skip).unwrap();
+    assert_eq!(conflicts.iter().map(|c| c.photo.clone()).collect::<Vec<_>>(),
+               vec![p("/nonexistent/in/3.cr2"), p("/nonexistent/in/3.cr2")]);
+    assert_eq!(skipped.cmds, plan.cmds[..2].to_vec());
+
+    let (suffixed, _) = resolve_conflicts(&plan, ConflictPolicy::Suffix).unwrap();
+    let e = vec![
+        Cmd::Rename(p("/nonexistent/in/1.cr2"), p("/nonexistent/out/x.cr2")),
+        Cmd::Rename(p("/nonexistent/in/2.jpg"), p("/nonexistent/out/x.jpg")),
+        Cmd::Rename(p("/nonexistent/in/3.cr2"), p("/nonexistent/out/x_1.cr2")),
+        Cmd::Rename(p("/nonexistent/in/3.jpg"), p("/nonexistent/out/x_1.jpg")),
+        Cmd::Rename(p("/nonexistent/in/3.jpg.xmp"), p("/nonexistent/out/x_1.jpg.xmp")),
+        Cmd::AdjustRef(p("/nonexistent/out/x_1.jpg.xmp"), p("/nonexistent/out/x_1.jpg")),
+    ];
+    assert_eq!(suffixed.cmds, e);
+}